@@ -1,53 +1,540 @@
 #![allow(missing_docs)]
+// The parsers below are written with nom's `chain!` macro, which nom 2
+// deprecates in favour of `do_parse!`.
+#![allow(deprecated)]
 #[macro_use]
 extern crate nom;
-#[macro_use]
+#[cfg_attr(feature = "fs", macro_use)]
 extern crate log;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
-use nom::{digit, line_ending, not_line_ending, space, IResult};
-use std::fs::File;
-use std::io::Read;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+#[cfg(feature = "fs")]
+use std::collections::HashSet;
+#[cfg(feature = "fs")]
+use std::path::PathBuf;
+#[cfg(feature = "fs")]
+use std::rc::Rc;
 
+use nom::{line_ending, not_line_ending, space, IResult, Needed};
+
+mod uml_arrow;
+mod uml_builder;
+mod uml_error;
+#[cfg(feature = "fs")]
+mod uml_fs;
+mod uml_lifecycle;
+mod uml_mermaid;
+mod uml_preprocess;
 mod uml_print;
+mod uml_render;
+mod uml_span;
+mod uml_summary;
+mod uml_validate;
+mod uml_walk;
+
+pub use uml_arrow::{parse_arrow, Arrow, ArrowDirection, ArrowHead, ArrowStyle};
+use uml_arrow::find_arrow;
+pub use uml_builder::UMLBuilder;
+use uml_error::block_len;
+pub use uml_error::UMLError;
+pub use uml_lifecycle::LifecycleEvent;
+pub use uml_mermaid::from_mermaid;
+use uml_preprocess::{expand_compact_loops, preprocess};
+use uml_walk::walk_token;
+pub use uml_walk::FlatIter;
+pub use uml_print::{IncludeMode, LineEnding, PrintOptions};
+#[cfg(feature = "fs")]
+pub use uml_fs::{parse_uml_dir, parse_uml_file};
+#[cfg(feature = "fs")]
+use uml_fs::load_include;
+pub use uml_render::{Event, EventKind, Group, Lane, RenderModel};
+pub use uml_validate::{UMLWarning, ValidationConfig};
 
 /// Tokens that represent each of the elements of UML that are supported.
+///
+/// With the `serde` feature enabled, each token serializes as an object whose
+/// `type` field names the variant.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type"))]
 pub enum UMLToken {
-    StartUML,
+    StartUML { name: Option<String> },
     EndUML,
-    Note { position: String, text: String },
-    Parallel { sequences: Vec<UMLTokens> },
+    Note {
+        position: NotePosition,
+        text: String,
+    },
+    SplitNote { notes: Vec<UMLToken> },
+    Parallel {
+        sequences: Vec<UMLTokens>,
+        /// The label of each branch, e.g. the text after `par` or `else`.
+        labels: Vec<Option<String>>,
+    },
     Message {
         from: String,
         to: String,
         text: Option<String>,
         colour: Option<String>,
+        arrow: Arrow,
+        /// Whether the receiver is created by the message, written with a
+        /// `**` after its name.
+        creates_target: bool,
     },
     Participant {
+        kind: ParticipantKind,
         long_name: Option<String>,
         short_name: String,
+        stereotype: Option<Stereotype>,
+        colour: Option<String>,
+        order: Option<i32>,
+    },
+    Activate {
+        name: String,
+        colour: Option<String>,
+    },
+    Deactivate {
+        name: String,
+        colour: Option<String>,
+    },
+    Loop {
+        sequence: UMLTokens,
+        count: LoopCount,
+    },
+    Include {
+        file: String,
+        sequence: UMLTokens,
+        once: bool,
+    },
+    Box {
+        name: String,
+        colour: Option<String>,
+        sequence: UMLTokens,
     },
-    Activate { name: String },
-    Deactivate { name: String },
-    Loop { sequence: UMLTokens, count: u8 },
-    Include { file: String, sequence: UMLTokens },
-    Box { name: String, sequence: UMLTokens },
+    Create { name: String },
     Destroy { name: String },
-    Delay { text: String },
-    Alt { sequences: Vec<UMLTokens> },
+    /// A `delay`.  `amount` is the number the text starts with, if any, as in
+    /// `delay 50` or `delay 5 seconds`.
+    Delay { text: String, amount: Option<u32> },
+    Divider { text: String },
+    Title { text: String },
+    /// A `newpage` page break, with the title of the next page if given.
+    NewPage { title: Option<String> },
+    /// A `'` comment line, or a `/' ... '/` block comment if `block` is set.
+    /// `text` is everything after the `'`, or between the delimiters, as
+    /// written.
+    Comment { text: String, block: bool },
+    /// An empty line, only kept if `ParserConfig::preserve_blank_lines` is
+    /// set.
+    Blank,
+    Alt {
+        sequences: Vec<UMLTokens>,
+        /// The guard of each branch, e.g. the text after `alt` or `else`.
+        labels: Vec<Option<String>>,
+    },
+    Opt {
+        label: Option<String>,
+        sequence: UMLTokens,
+    },
+    Critical {
+        label: Option<String>,
+        sequence: UMLTokens,
+    },
+    Break {
+        label: Option<String>,
+        sequence: UMLTokens,
+    },
+    Group { label: String, sequence: UMLTokens },
+    Autonumber {
+        start: Option<u32>,
+        stop: bool,
+        inc: Option<String>,
+        /// The quoted format for the numbers, e.g. `<b>[000]`, kept verbatim.
+        format: Option<String>,
+    },
+    Ref {
+        participants: Vec<String>,
+        text: String,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UMLTokens {
     pub tokens: Vec<UMLToken>,
 }
 
+/// The keyword a participant is declared with, which sets the shape it is
+/// drawn as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ParticipantKind {
+    #[default]
+    Participant,
+    Actor,
+    Boundary,
+    Control,
+    Entity,
+    Database,
+    Collections,
+    Queue,
+}
+
+impl ParticipantKind {
+    /// The keyword declaring a participant of this kind.
+    pub fn keyword(&self) -> &'static str {
+        match *self {
+            ParticipantKind::Participant => "participant",
+            ParticipantKind::Actor => "actor",
+            ParticipantKind::Boundary => "boundary",
+            ParticipantKind::Control => "control",
+            ParticipantKind::Entity => "entity",
+            ParticipantKind::Database => "database",
+            ParticipantKind::Collections => "collections",
+            ParticipantKind::Queue => "queue",
+        }
+    }
+
+    fn parse(keyword: &str) -> Result<ParticipantKind, ()> {
+        match &*keyword.to_ascii_lowercase() {
+            "participant" => Ok(ParticipantKind::Participant),
+            "actor" => Ok(ParticipantKind::Actor),
+            "boundary" => Ok(ParticipantKind::Boundary),
+            "control" => Ok(ParticipantKind::Control),
+            "entity" => Ok(ParticipantKind::Entity),
+            "database" => Ok(ParticipantKind::Database),
+            "collections" => Ok(ParticipantKind::Collections),
+            "queue" => Ok(ParticipantKind::Queue),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A participant's `<<stereotype>>`, optionally with a spot: a character in a
+/// coloured circle, written `<<(C,#ADD1B2) text>>`.  The spot colour is stored
+/// without its `#`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stereotype {
+    pub spot: Option<(char, String)>,
+    pub text: String,
+}
+
+impl Stereotype {
+    /// Parses the text between `<<` and `>>`.  A spot that isn't of the
+    /// form `(C,#colour)` is kept as part of the text.
+    fn parse(inner: &str) -> Stereotype {
+        let inner = inner.trim();
+
+        let spot = inner.strip_prefix('(').and_then(|rest| {
+            let close = rest.find(')')?;
+            let mut parts = rest[..close].splitn(2, ',');
+            let mut character = parts.next()?.trim().chars();
+            let colour = parts.next()?.trim().strip_prefix('#')?;
+
+            match (character.next(), character.next()) {
+                (Some(character), None) if !colour.is_empty() => {
+                    Some(((character, colour.to_string()), rest[close + 1..].trim()))
+                }
+                _ => None,
+            }
+        });
+
+        match spot {
+            Some((spot, text)) => {
+                Stereotype {
+                    spot: Some(spot),
+                    text: text.to_string(),
+                }
+            }
+            None => {
+                Stereotype {
+                    spot: None,
+                    text: inner.to_string(),
+                }
+            }
+        }
+    }
+}
+
 impl UMLTokens {
     pub fn new(tokens: Vec<UMLToken>) -> UMLTokens {
-        UMLTokens { tokens: tokens }
+        UMLTokens { tokens }
+    }
+
+    /// Returns the tokens between the first `start` token and the next `end`
+    /// token, excluding both markers.
+    ///
+    /// Only this sequence's own tokens are compared with the markers, so a
+    /// block is always kept or dropped as a whole.  If there is no `start`
+    /// the result is empty, and if there is no `end` it runs to the end of
+    /// the diagram.
+    pub fn slice_between(&self, start: &UMLToken, end: &UMLToken) -> UMLTokens {
+        let tokens = match self.tokens.iter().position(|token| token == start) {
+            Some(index) => &self.tokens[index + 1..],
+            None => &[],
+        };
+        let tokens = match tokens.iter().position(|token| token == end) {
+            Some(index) => &tokens[..index],
+            None => tokens,
+        };
+
+        UMLTokens::new(tokens.to_vec())
+    }
+
+    /// Compares two diagrams ignoring insignificant whitespace: every string in
+    /// them is trimmed, with runs of spaces and tabs collapsed to one space,
+    /// before comparing with `==`.
+    pub fn structurally_eq(&self, other: &UMLTokens) -> bool {
+        self.whitespace_normalised() == other.whitespace_normalised()
+    }
+
+    fn whitespace_normalised(&self) -> UMLTokens {
+        let mut uml_tokens = self.clone();
+        uml_tokens.walk_mut(&mut normalise_token_whitespace);
+
+        uml_tokens
+    }
+
+    /// The top level statements, without descending into blocks.  Use `walk`
+    /// to visit nested tokens too.
+    pub fn statements(&self) -> &[UMLToken] {
+        &self.tokens
+    }
+
+    /// The top level statements, mutably.  Use `walk_mut` to visit nested
+    /// tokens too.
+    pub fn statements_mut(&mut self) -> &mut [UMLToken] {
+        &mut self.tokens
+    }
+
+    /// The diagram name given after `@startuml`, such as an output filename.
+    pub fn diagram_name(&self) -> Option<&str> {
+        self.tokens.iter().find_map(|token| match *token {
+            UMLToken::StartUML { ref name } => name.as_deref(),
+            _ => None,
+        })
+    }
+}
+
+/// What was noticed while parsing a diagram, besides its tokens and errors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParserContext {
+    /// Problems that didn't stop statements from being parsed, in document
+    /// order.
+    pub warnings: Vec<UMLWarning>,
+}
+
+/// Where a note is drawn.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NotePosition {
+    /// `left`, or `left of X`.  A bare `left` note straight after a message
+    /// is drawn next to its arrow.
+    Left(Option<String>),
+    /// `right`, or `right of X`.
+    Right(Option<String>),
+    /// `over X, Y, ...`
+    Over(Vec<String>),
+    /// Any other position, kept as written.  This is only accepted when not in
+    /// strict mode.
+    Other(String),
+}
+
+impl NotePosition {
+    /// Parses the text after `note`.
+    fn parse(text: &str) -> NotePosition {
+        let text = text.trim();
+        let mut words = text.split_whitespace();
+        let side = words.next().unwrap_or("");
+        let rest = text[side.len()..].trim_start();
+
+        let target = match words.next() {
+            Some(of) if of.eq_ignore_ascii_case("of") && words.next().is_some() => {
+                Some(rest[of.len()..].trim())
+            }
+            _ => None,
+        };
+
+        match (&*side.to_ascii_lowercase(), rest, target) {
+            ("left", "", _) => NotePosition::Left(None),
+            ("right", "", _) => NotePosition::Right(None),
+            ("left", _, Some(target)) => NotePosition::Left(Some(target.to_string())),
+            ("right", _, Some(target)) => NotePosition::Right(Some(target.to_string())),
+            ("over", _, _) if !rest.is_empty() => {
+                let names: Vec<String> = rest.split(',').map(|name| name.trim().to_string()).collect();
+                if names.iter().any(|name| name.is_empty()) {
+                    NotePosition::Other(text.to_string())
+                } else {
+                    NotePosition::Over(names)
+                }
+            }
+            _ => NotePosition::Other(text.to_string()),
+        }
+    }
+
+    /// The participants the note is attached to, if any.
+    pub fn participants(&self) -> Vec<&str> {
+        match *self {
+            NotePosition::Left(Some(ref name)) |
+            NotePosition::Right(Some(ref name)) => vec![name.as_str()],
+            NotePosition::Over(ref names) => names.iter().map(|name| name.as_str()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// What a `loop` is labelled with.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LoopCount {
+    /// `loop 1000`
+    Times(u32),
+    /// `loop until done`
+    Label(String),
+}
+
+impl LoopCount {
+    /// Parses the text after `loop`.  A number must fit in a `u32`.
+    fn parse(text: &str) -> Option<LoopCount> {
+        let text = text.trim();
+
+        if text.is_empty() {
+            None
+        } else if text.bytes().all(|c| c.is_ascii_digit()) {
+            text.parse().ok().map(LoopCount::Times)
+        } else {
+            Some(LoopCount::Label(text.to_string()))
+        }
+    }
+}
+
+/// Options controlling how diagrams are parsed.
+#[derive(Debug, Clone, Default)]
+pub struct ParserConfig {
+    /// Descend into subdirectories when parsing a directory.
+    pub recursive: bool,
+    /// When parsing several files, skip files that fail to parse rather than
+    /// aborting.
+    pub continue_on_error: bool,
+    /// Reject statements that are accepted leniently by default, such as a
+    /// note whose position isn't one of `left`, `right`, `left of X`,
+    /// `right of X` or `over ...`.
+    pub strict: bool,
+    /// Accept the single line loop shorthand `loop 5: a->b : text`, which
+    /// isn't PlantUML syntax.  It is read as a loop containing one statement.
+    pub compact_loops: bool,
+    /// Make `UMLTokens::validate` warn about participants activated within a
+    /// fragment but not deactivated within it.
+    pub warn_activation_leaks: bool,
+    /// Ignore any lines before the first `@startuml`, such as a shebang or
+    /// YAML front matter.  Use `split_preamble` to get at the ignored text.
+    pub skip_preamble_until_startuml: bool,
+    /// Keep empty lines as `UMLToken::Blank`, so that writing the diagram
+    /// out again reproduces its layout.
+    pub preserve_blank_lines: bool,
+}
+
+/// The result of `try_parse`, without depending on nom's types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseOutcome<'a> {
+    /// The tokens parsed, and the input left over after them.
+    Done(&'a [u8], UMLTokens),
+    /// The input ends part way through a statement.  Holds the total number
+    /// of bytes needed, if known.
+    Incomplete(Option<usize>),
+    /// The input doesn't start with a statement that can be parsed.
+    Error,
+}
+
+impl UMLToken {
+    /// A short, lower case name for the kind of token, e.g. `"message"`.
+    pub fn kind_name(&self) -> &'static str {
+        match *self {
+            UMLToken::StartUML { .. } => "startuml",
+            UMLToken::EndUML => "enduml",
+            UMLToken::Blank => "blank",
+            UMLToken::Note { .. } => "note",
+            UMLToken::SplitNote { .. } => "split_note",
+            UMLToken::Parallel { .. } => "par",
+            UMLToken::Alt { .. } => "alt",
+            UMLToken::Opt { .. } => "opt",
+            UMLToken::Critical { .. } => "critical",
+            UMLToken::Break { .. } => "break",
+            UMLToken::Group { .. } => "group",
+            UMLToken::Message { .. } => "message",
+            UMLToken::Participant { .. } => "participant",
+            UMLToken::Activate { .. } => "activate",
+            UMLToken::Deactivate { .. } => "deactivate",
+            UMLToken::Loop { .. } => "loop",
+            UMLToken::Include { .. } => "include",
+            UMLToken::Box { .. } => "box",
+            UMLToken::Create { .. } => "create",
+            UMLToken::Destroy { .. } => "destroy",
+            UMLToken::Delay { .. } => "delay",
+            UMLToken::Divider { .. } => "divider",
+            UMLToken::Title { .. } => "title",
+            UMLToken::NewPage { .. } => "newpage",
+            UMLToken::Comment { .. } => "comment",
+            UMLToken::Autonumber { .. } => "autonumber",
+            UMLToken::Ref { .. } => "ref",
+        }
+    }
+
+    /// Compares two tokens ignoring how messages are drawn: messages are equal
+    /// if they have the same `from`, `to` and `text`, whatever their arrows
+    /// and colours.  Other tokens are compared with `==`.
+    pub fn semantically_eq(&self, other: &UMLToken) -> bool {
+        match (self, other) {
+            (UMLToken::Message { from, to, text, .. },
+             UMLToken::Message {
+                 from: other_from,
+                 to: other_to,
+                 text: other_text,
+                 ..
+             }) => from == other_from && to == other_to && text == other_text,
+            _ => self == other,
+        }
+    }
+
+    /// The display names of the participants a `Ref` is drawn over, looked up
+    /// in `aliases` (see `UMLTokens::participant_aliases`).
+    ///
+    /// Fails with the name of the first participant that was never declared.
+    /// Tokens other than `Ref` have no participants.
+    pub fn ref_display_names(&self,
+                             aliases: &HashMap<String, String>)
+                             -> Result<Vec<String>, String> {
+        match *self {
+            UMLToken::Ref { ref participants, .. } => {
+                participants.iter()
+                    .map(|name| aliases.get(name).cloned().ok_or_else(|| name.clone()))
+                    .collect()
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// The `amount` of a `Delay`, or `None` for other tokens.
+    pub fn duration(&self) -> Option<u32> {
+        match *self {
+            UMLToken::Delay { amount, .. } => amount,
+            _ => None,
+        }
     }
 }
 
+/// Takes the input up to `tag` or, if `tag` isn't found first, up to the end
+/// of the line.
+///
+/// The tags searched for are ASCII, and every byte of a multi-byte UTF-8
+/// character is non-ASCII, so the input is never split within a character.
 fn take_until_or_line_ending<'a>(input: &'a [u8],
                                  tag: &'static str)
                                  -> IResult<&'a [u8], &'a [u8]> {
@@ -68,238 +555,1072 @@ fn take_until_or_line_ending<'a>(input: &'a [u8],
     }
 }
 
-/// Parse a UML file and return the `UMLTokens` that were parsed.
-pub fn parse_uml_file(file: &str, path: Option<&std::path::Path>) -> UMLTokens {
+/// Trims each line of `text`, and `text` as a whole, and collapses runs of
+/// whitespace within lines to single spaces.
+fn normalise_whitespace(text: &mut String) {
+    let lines: Vec<String> = text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    *text = lines.join("\n").trim().to_string();
+}
 
-    let old_path = std::env::current_dir().expect("Can't access current directory");
+/// Applies `normalise_whitespace` to every string in `token` itself, but not
+/// in the tokens nested within it.
+fn normalise_token_whitespace(token: &mut UMLToken) {
+    let mut strings: Vec<&mut String> = Vec::new();
 
-    if let Some(path) = path {
-        info!("Setting current directory to {:?}", path.to_str().unwrap());
-        std::env::set_current_dir(&path).unwrap();
+    match *token {
+        UMLToken::StartUML { ref mut name } => strings.extend(name),
+        UMLToken::Note {
+            ref mut position,
+            ref mut text,
+        } => {
+            match *position {
+                NotePosition::Left(ref mut name) |
+                NotePosition::Right(ref mut name) => strings.extend(name),
+                NotePosition::Over(ref mut names) => strings.extend(names),
+                NotePosition::Other(ref mut text) => strings.push(text),
+            }
+            strings.push(text);
+        }
+        UMLToken::Parallel { ref mut labels, .. } |
+        UMLToken::Alt { ref mut labels, .. } => strings.extend(labels.iter_mut().flatten()),
+        UMLToken::Opt { ref mut label, .. } |
+        UMLToken::Critical { ref mut label, .. } |
+        UMLToken::Break { ref mut label, .. } => strings.extend(label),
+        UMLToken::Group { ref mut label, .. } => strings.push(label),
+        UMLToken::Message {
+            ref mut from,
+            ref mut to,
+            ref mut text,
+            ref mut colour,
+            ..
+        } => {
+            strings.push(from);
+            strings.push(to);
+            strings.extend(text);
+            strings.extend(colour);
+        }
+        UMLToken::Participant {
+            ref mut long_name,
+            ref mut short_name,
+            ref mut stereotype,
+            ref mut colour,
+            ..
+        } => {
+            strings.extend(long_name);
+            strings.push(short_name);
+            if let Some(ref mut stereotype) = *stereotype {
+                strings.push(&mut stereotype.text);
+                strings.extend(stereotype.spot.as_mut().map(|spot| &mut spot.1));
+            }
+            strings.extend(colour);
+        }
+        UMLToken::Activate {
+            ref mut name,
+            ref mut colour,
+        } |
+        UMLToken::Deactivate {
+            ref mut name,
+            ref mut colour,
+        } |
+        UMLToken::Box {
+            ref mut name,
+            ref mut colour,
+            ..
+        } => {
+            strings.push(name);
+            strings.extend(colour);
+        }
+        UMLToken::Loop { ref mut count, .. } => {
+            if let LoopCount::Label(ref mut label) = *count {
+                strings.push(label);
+            }
+        }
+        UMLToken::Include { ref mut file, .. } => strings.push(file),
+        UMLToken::Create { ref mut name } |
+        UMLToken::Destroy { ref mut name } => strings.push(name),
+        UMLToken::Delay { ref mut text, .. } |
+        UMLToken::Divider { ref mut text } |
+        UMLToken::Title { ref mut text } |
+        UMLToken::Comment { ref mut text, .. } => strings.push(text),
+        UMLToken::NewPage { ref mut title } => strings.extend(title),
+        UMLToken::Autonumber {
+            ref mut inc,
+            ref mut format,
+            ..
+        } => {
+            strings.extend(inc);
+            strings.extend(format);
+        }
+        UMLToken::Ref {
+            ref mut participants,
+            ref mut text,
+        } => {
+            strings.extend(participants);
+            strings.push(text);
+        }
+        UMLToken::EndUML |
+        UMLToken::SplitNote { .. } |
+        UMLToken::Blank => {}
     }
 
-    let cur_path = std::env::current_dir().unwrap();
-    let file_path = if file.starts_with('/') {
-        file.to_string()
-    } else {
-        format!("{}/{}", cur_path.to_str().unwrap(), file)
-    };
-
-    let mut f = File::open(file_path).unwrap();
-    let mut uml = String::new();
-    f.read_to_string(&mut uml).unwrap();
+    for text in strings {
+        normalise_whitespace(text);
+    }
+}
 
-    // Strip out any \r characters from the file to cope with DOS line endings.
-    uml = uml.replace("\r", "");
+/// Parse as many statements as possible from the start of `input`, e.g. a
+/// partially received buffer, reporting whether more input is needed.
+///
+/// Unlike `parse_uml_str`, no preprocessing is done.
+pub fn try_parse(input: &[u8]) -> ParseOutcome<'_> {
+    match uml_parser(input) {
+        IResult::Done(rest, uml_tokens) => ParseOutcome::Done(rest, uml_tokens),
+        IResult::Incomplete(Needed::Size(size)) => ParseOutcome::Incomplete(Some(size)),
+        IResult::Incomplete(Needed::Unknown) => ParseOutcome::Incomplete(None),
+        IResult::Error(_) => ParseOutcome::Error,
+    }
+}
 
-    info!("Parsing {}", file);
-    let result = uml_parser(uml.as_bytes());
+/// Parse UML held in memory, e.g. received over the network or embedded in
+/// another document, failing at the first statement that can't be parsed.
+///
+/// As with `parse_uml_file`, any `\r` characters are stripped first.  There is
+/// no file to resolve `!include`s against, so they are resolved relative to
+/// the current directory, which is left untouched.  An included file that
+/// can't be read or parsed in full is an error too.
+pub fn parse_uml_str(input: &str) -> Result<UMLTokens, UMLError> {
+    let uml = preprocess(&input.replace("\r", ""));
+    parse_complete(&uml, &ParseState::default())
+}
 
-    let uml_tokens = match result {
-        IResult::Done(_, tokens) => tokens,
-        _ => panic!("{:?}", result),
+/// Parse all of `uml`, which has already been preprocessed, failing at the
+/// first statement that can't be parsed.  If that is because a file it
+/// includes can't be loaded, the error from loading the file is returned.
+pub(crate) fn parse_complete(uml: &str, state: &ParseState) -> Result<UMLTokens, UMLError> {
+    let failed_at = |rest: &[u8]| {
+        state.take_include_error().unwrap_or_else(|| {
+            UMLError::parse_at(uml, uml.len() - skip_blank_lines(rest).len())
+        })
     };
-    info!("Done parsing {}", file);
 
-    std::env::set_current_dir(&old_path).unwrap();
+    match uml_sequence(uml.as_bytes(), state) {
+        IResult::Done(rest, tokens) => {
+            if !skip_blank_lines(rest).is_empty() {
+                return Err(failed_at(rest));
+            }
+            Ok(tokens)
+        }
+        _ => Err(failed_at(uml.as_bytes())),
+    }
+}
+
+/// Parse UML from a string, recovering from statements that fail to parse.
+///
+/// Each statement that can't be parsed is recorded as a `UMLError` and
+/// parsing resumes at the following line, so the result contains every
+/// parseable token alongside every error in the input.  Within a block, the
+/// bad statements are left out of the block rather than failing it, and a
+/// block whose header can't be parsed is skipped up to its `end`.
+pub fn parse_uml_collect_errors(input: &str) -> (UMLTokens, Vec<UMLError>) {
+    let (uml_tokens, errors, _) = parse_uml_with_config(input, &ParserConfig::default());
 
-    uml_tokens
+    (uml_tokens, errors)
 }
 
-/// `take_until_first_tag!(tag, ...) => &[T] -> IResult<&[T], &[T]>`
+/// As `parse_uml_collect_errors`, but with the checks selected by `cfg`, and
+/// also returning the warnings gathered in a `ParserContext`.
 ///
-/// Reads up to the first provided tag (preferring earlier tags if there's
-/// conflict).
-macro_rules! take_until_first_tag (
-    ($i:expr, $($substr:expr),+) => ({
-        use $crate::nom::InputLength;
-        use $crate::nom::FindSubstring;
-        use $crate::nom::Slice;
-
-// This enum tracks the current "best answer" over all provided tags.
-//
-// It has a cryptically short name, since we can't `use P::*` due to a language
-// limitation.
-        enum P {
-            Nothing,
-            Found(usize),
-            NeedMore(usize),
-        }
+/// In strict mode a statement that contains a note with an unrecognized
+/// position is dropped and reported as a `UMLError::Parse` on the note's line.
+/// Otherwise the statement is kept, with a `UMLWarning::UnknownNotePosition`.
+pub fn parse_uml_with_config(input: &str,
+                             cfg: &ParserConfig)
+                             -> (UMLTokens, Vec<UMLError>, ParserContext) {
+    parse_uml_with_state(input, cfg, &ParseState::new(cfg))
+}
 
-        let res = P::Nothing;
+/// As `parse_uml_with_config`, resolving `!include`s as set out in `state`.
+///
+/// A statement that fails to parse because a file it includes can't be loaded
+/// is reported with the error from loading that file.
+pub(crate) fn parse_uml_with_state(input: &str,
+                                   cfg: &ParserConfig,
+                                   state: &ParseState)
+                                   -> (UMLTokens, Vec<UMLError>, ParserContext) {
+    let input = if cfg.skip_preamble_until_startuml { split_preamble(input).1 } else { input };
+    let mut uml = preprocess(&input.replace("\r", ""));
+    if cfg.compact_loops {
+        uml = expand_compact_loops(&uml);
+    }
+    let mut remaining = uml.as_bytes();
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut context = ParserContext::default();
 
-        $(let res = if $substr.input_len() > $i.input_len() {
-            match res {
-                P::Nothing => P::NeedMore($substr.input_len()),
-                P::NeedMore(old) if old > $substr.input_len() => P::NeedMore($substr.input_len()),
-                _ => res,
+    loop {
+        let rest = skip_blank_lines(remaining);
+        if rest.is_empty() {
+            // Blank lines at the very end are too short for uml_statement to
+            // tell that they don't start an `end`.
+            if cfg.preserve_blank_lines {
+                tokens.extend(remaining.iter().filter(|&&c| c == b'\n').map(|_| UMLToken::Blank));
             }
-        } else if let Some(index) = $i.find_substring($substr) {
-            match res {
-                P::Nothing | P::NeedMore(_) => P::Found(index),
-                P::Found(old) if old > index => P::Found(index),
-                _ => res
+            break;
+        }
+        if !cfg.preserve_blank_lines {
+            remaining = rest;
+        }
+
+        let start = uml.len() - remaining.len();
+        let parsed = match uml_statement(remaining, state) {
+            IResult::Done(rest, token) => {
+                state.take_include_error();
+                Some((token, rest))
             }
-        } else {
-            res
-        };)*
+            _ => {
+                match recover_block(&uml, start, state) {
+                    Some(recovery) => {
+                        errors.extend(recovery.bad.into_iter().map(|(_, error)| error));
+                        let rest = &remaining[recovery.len..];
+                        match recovery.token {
+                            Some(token) => Some((token, rest)),
+                            None => {
+                                remaining = rest;
+                                None
+                            }
+                        }
+                    }
+                    None => {
+                        errors.push(state.take_include_error()
+                            .unwrap_or_else(|| UMLError::parse_at(&uml, start)));
+                        remaining = &remaining[line_len(remaining)..];
+                        None
+                    }
+                }
+            }
+        };
 
-        match res {
-            P::Nothing => $crate::nom::IResult::Error($crate::nom::ErrorKind::TakeUntil),
-            P::Found(index) => $crate::nom::IResult::Done($i.slice(index..), $i.slice(0..index)),
-            P::NeedMore(size) => $crate::nom::IResult::Incomplete($crate::nom::Needed::Size(size)),
+        if let Some((token, rest)) = parsed {
+            let invalid = invalid_note_position(&token).map(|position| {
+                let statement = &uml[start..uml.len() - rest.len()];
+                (position.to_string(), start + note_line_offset(statement, position))
+            });
+
+            match invalid {
+                Some((_, offset)) if cfg.strict => {
+                    errors.push(UMLError::parse_at(&uml, offset));
+                }
+                Some((position, offset)) => {
+                    context.warnings.push(UMLWarning::UnknownNotePosition {
+                        line: uml[..offset].matches('\n').count() + 1,
+                        position,
+                    });
+                    tokens.push(token);
+                }
+                None => tokens.push(token),
+            }
+            remaining = rest;
         }
-    })
-);
+    }
 
-named!(startuml<&[u8], UMLToken>,
-    chain!(
-        space?                            ~
-        tag!("@startuml")                 ~
-        space?                            ~
-        line_ending
-        ,
-        || UMLToken::StartUML
-    )
-);
+    (UMLTokens::new(tokens), errors, context)
+}
 
-named!(enduml<&[u8], UMLToken>,
-    chain!(
-        space?                            ~
-        tag!("@enduml")                   ~
-        space?                            ~
-        line_ending
-        ,
-        || UMLToken::EndUML
-    )
-);
+/// The length of the first line of `input`, including its line ending.
+fn line_len(input: &[u8]) -> usize {
+    input.iter().position(|&c| c == b'\n').map_or(input.len(), |index| index + 1)
+}
 
-named!(include_parser<&[u8], UMLToken>,
-    chain!(
-        space?                            ~
-        tag!("!include")                  ~
-        space                             ~
-        file: map_res!(
-            not_line_ending,
-            std::str::from_utf8
-        )                                 ~
-        line_ending
-        ,
-        || {
-            let file = file.trim().trim_matches('\"').to_string();
+/// How a block that failed to parse was recovered from.
+struct BlockRecovery {
+    /// The block, parsed again without its bad lines, if that worked.
+    token: Option<UMLToken>,
+    /// The range of each bad line within the block, including its line ending,
+    /// with its error.  If the
+    /// block couldn't be parsed even without them and no lines within it are
+    /// at fault, its header is reported instead.
+    bad: Vec<(Range<usize>, UMLError)>,
+    /// The length of the block, up to the end of the line that closes it.
+    len: usize,
+}
 
-            UMLToken::Include {
-                file: file.clone(),
-                sequence: parse_uml_file(&file, None),
-            }
+/// Recovers from a statement starting `start` bytes into `uml` that failed to
+/// parse, if it opens a block that is closed later on.
+///
+/// The lines within the block are checked one statement at a time and the
+/// block is parsed again without the bad ones, so that a mistake
+/// inside a block is reported on its own line, and neither loses the rest of
+/// the block nor leaves the block's `end` to be reported as well.
+fn recover_block(uml: &str, start: usize, state: &ParseState) -> Option<BlockRecovery> {
+    let len = block_len(&uml[start..])?;
+    let end = start + len;
+    let body_start = start + line_len(&uml.as_bytes()[start..end]);
+    let body_end = start + uml[start..end - 1].rfind('\n').map_or(0, |index| index + 1);
+
+    // Whatever stopped the block from parsing is found again below.
+    state.take_include_error();
+    let bad = bad_lines(uml, body_start, body_end.max(body_start), state);
+
+    let mut patched = Vec::new();
+    let mut copied = start;
+    for (range, _) in &bad {
+        if range.start >= copied {
+            patched.extend_from_slice(&uml.as_bytes()[copied..range.start]);
         }
-    )
-);
+        copied = copied.max(range.end);
+    }
+    patched.extend_from_slice(&uml.as_bytes()[copied..end]);
 
-named!(participant_parser<&[u8], UMLToken>,
-    chain!(
-        space?                            ~
-        alt!(
-            tag!("participant") |
-            tag!("actor")
-        )                                 ~
-        space                             ~
-        name: map_res!(
-            apply!(
-                take_until_or_line_ending, " as "
-            ),
-            std::str::from_utf8
-        )                                 ~
-        short_name: opt!(
-            chain!(
-                tag!(" as ")              ~
-                space?                    ~
-                text: map_res!(
-                    not_line_ending,
-                    std::str::from_utf8
-                )
-                ,
-                || {
-                    text.trim().to_string()
-                }
-            )
-        )                                 ~
-        line_ending
-        ,
-        || {
-            UMLToken::Participant {
-                long_name: if short_name.is_some() {
-                    Some(name.trim().to_string())
-                } else {
-                    None
-                },
-                short_name: if short_name.is_some() {
-                    short_name.unwrap().trim().to_string()
-                } else {
-                    name.trim().to_string()
-                }
+    let token = match uml_statement(&patched, state) {
+        IResult::Done(&[], token) => Some(token),
+        _ => None,
+    };
+    state.take_include_error();
+
+    let bad = if token.is_none() && bad.is_empty() {
+        vec![(start..end, UMLError::parse_at(uml, start))]
+    } else {
+        bad
+    };
+
+    Some(BlockRecovery { token, bad, len })
+}
+
+/// Finds the statements between `from` and `to` in `uml` that fail to parse,
+/// along with the error for each.  A block that fails to parse is recovered
+/// from as by `recover_block`, and the `else` lines between branches are
+/// skipped.
+fn bad_lines(uml: &str,
+             from: usize,
+             to: usize,
+             state: &ParseState)
+             -> Vec<(Range<usize>, UMLError)> {
+    let input = &uml.as_bytes()[..to];
+    let mut bad = Vec::new();
+    let mut position = from;
+
+    loop {
+        position = to - skip_blank_lines(&input[position..]).len();
+        if position == to {
+            return bad;
+        }
+
+        if let IResult::Done(rest, _) = uml_statement(&input[position..], state) {
+            state.take_include_error();
+            position = to - rest.len();
+            continue;
+        }
+
+        let line = &uml[position..position + line_len(&input[position..])];
+        if line.split_whitespace().next().is_some_and(|word| word.eq_ignore_ascii_case("else")) {
+            position += line.len();
+        } else if let Some(recovery) = recover_block(uml, position, state) {
+            // A block that can't be recovered is left out as a whole.
+            let block = position..position + recovery.len;
+            let recovered = recovery.token.is_some();
+            bad.extend(recovery.bad.into_iter().map(|(range, error)| {
+                (if recovered { range } else { block.clone() }, error)
+            }));
+            position += recovery.len;
+        } else {
+            let error = state.take_include_error()
+                .unwrap_or_else(|| UMLError::parse_at(uml, position));
+            bad.push((position..position + line.len(), error));
+            position += line.len();
+        }
+    }
+}
+
+/// Returns the position of the first note within `token` whose position isn't
+/// one of the forms PlantUML accepts, if any.
+fn invalid_note_position(token: &UMLToken) -> Option<&str> {
+    let mut invalid = None;
+
+    walk_token(token, &mut |token| {
+        if let UMLToken::Note { position: NotePosition::Other(ref position), .. } = *token {
+            if invalid.is_none() {
+                invalid = Some(position.as_str());
             }
         }
-    )
-);
+    });
 
-named!(note_parser<&[u8], UMLToken>,
-    chain!(
-        space?                            ~
-        tag!("note")                      ~
-        position: map_res!(
-            not_line_ending,
-            std::str::from_utf8
-        )                                 ~
-        line_ending                       ~
-        text: map_res!(
-            take_until!("end note"),
-            std::str::from_utf8
-        )                                 ~
-        tag!("end note")                  ~
-        space?                            ~
-        line_ending?
-        ,
-        || {
-            UMLToken::Note {
-                position: position.trim().to_string(),
-                text: text.trim().to_string()
+    invalid
+}
+
+/// Returns the offset within `statement` of the line of the note with the
+/// given position.
+fn note_line_offset(statement: &str, position: &str) -> usize {
+    let mut offset = 0;
+
+    for line in statement.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("note") {
+            let rest = rest.split(':').next().unwrap_or("");
+            if strip_comment(rest).trim() == position {
+                return offset + line.len() - trimmed.len();
             }
         }
-    )
-);
+        offset += line.len();
+    }
 
-named!(loop_parser<&[u8], UMLToken>,
+    0
+}
+
+/// Find the `@startuml` ... `@enduml` blocks embedded in a larger document,
+/// such as a markdown file, ignoring all of the surrounding text.
+///
+/// Each slice runs from `@startuml` to the end of the matching `@enduml` line,
+/// so it can be parsed on its own.  A `@startuml` without a matching
+/// `@enduml` is ignored.
+pub fn extract_uml_blocks(text: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with("@startuml") {
+            start = Some(offset + line.len() - trimmed.len());
+        } else if trimmed.starts_with("@enduml") {
+            if let Some(start) = start.take() {
+                blocks.push(&text[start..offset + line.len()]);
+            }
+        }
+
+        offset += line.len();
+    }
+
+    blocks
+}
+
+/// Splits `text` at the start of the line containing the first `@startuml`,
+/// returning the preamble before it and the rest of the text.  If there is no
+/// `@startuml` the preamble is empty.
+pub fn split_preamble(text: &str) -> (&str, &str) {
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("@startuml") {
+            return text.split_at(offset);
+        }
+        offset += line.len();
+    }
+
+    ("", text)
+}
+
+/// What the statement parsers need to know about the diagram they are
+/// parsing and where it came from.
+#[derive(Debug, Default)]
+pub(crate) struct ParseState {
+    /// The directory that relative `!include` paths are resolved against, or
+    /// `None` for the current directory.
+    #[cfg(feature = "fs")]
+    base: Option<PathBuf>,
+    /// Every file read so far for the diagram, by resolved path.  This is
+    /// shared with the states of the files it includes.
+    #[cfg(feature = "fs")]
+    included: Rc<RefCell<HashSet<PathBuf>>>,
+    /// The resolved paths of the file being parsed and the files that include
+    /// it, outermost first.
+    #[cfg(feature = "fs")]
+    ancestors: Vec<PathBuf>,
+    /// Whether empty lines are parsed as `UMLToken::Blank`, rather than
+    /// skipped.
+    preserve_blank_lines: bool,
+    /// Whether `!include` directives are parsed without reading the file, as
+    /// when only the extent of a statement in the source is wanted.
+    skip_includes: bool,
+    /// Why the last `!include` that couldn't be loaded failed, to be reported
+    /// in place of the parse error it causes.
+    include_error: RefCell<Option<UMLError>>,
+}
+
+impl ParseState {
+    /// The state for UML that isn't from a file, parsed as set out in `cfg`.
+    pub(crate) fn new(cfg: &ParserConfig) -> ParseState {
+        ParseState { preserve_blank_lines: cfg.preserve_blank_lines, ..ParseState::default() }
+    }
+
+    /// Takes the error recorded for the last `!include` that couldn't be
+    /// loaded, if any.
+    pub(crate) fn take_include_error(&self) -> Option<UMLError> {
+        self.include_error.borrow_mut().take()
+    }
+}
+
+/// Parses an empty line as `UMLToken::Blank`, if blank lines are being
+/// preserved.
+fn blank_line<'a>(input: &'a [u8], state: &ParseState) -> IResult<&'a [u8], UMLToken> {
+    if !state.preserve_blank_lines {
+        return IResult::Error(error_position!(nom::ErrorKind::Tag, input));
+    }
+
+    chain!(input,
+        space?                             ~
+        line_ending
+        ,
+        || UMLToken::Blank
+    )
+}
+
+/// Parses the line ending of an empty line that is to be skipped, i.e. unless
+/// blank lines are being preserved.
+fn skipped_line_ending<'a>(input: &'a [u8], state: &ParseState) -> IResult<&'a [u8], &'a [u8]> {
+    if state.preserve_blank_lines {
+        return IResult::Error(error_position!(nom::ErrorKind::Tag, input));
+    }
+
+    line_ending(input)
+}
+
+/// Skips any lines that contain only whitespace.
+fn skip_blank_lines(mut input: &[u8]) -> &[u8] {
+    loop {
+        let line_len = input.iter().position(|&c| c == b'\n');
+        match line_len {
+            Some(len) if input[..len].iter().all(|&c| c == b' ' || c == b'\t') => {
+                input = &input[len + 1..];
+            }
+            None if input.iter().all(|&c| c == b' ' || c == b'\t') => return &[],
+            _ => return input,
+        }
+    }
+}
+
+/// Strips a trailing `' comment` from a statement line.  The quote must follow
+/// whitespace and be outside a quoted string, so apostrophes within words are
+/// kept.  Single-quoted text such as `'hello'` is kept too, as long as its
+/// closing quote ends a word and more of the statement follows it.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut skip_to = 0;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        if index >= skip_to {
+            if c == '"' {
+                quoted = !quoted;
+            } else if c == '\'' && !quoted && previous.is_whitespace() {
+                match closing_quote(&line[index + 1..]) {
+                    Some(close) => skip_to = index + close + 2,
+                    None => return line[..index].trim_end(),
+                }
+            }
+        }
+        previous = c;
+    }
+    line
+}
+
+/// The offset of the `'` that closes single-quoted text at the start of
+/// `rest`: the first one that ends a word, provided it isn't the last word.
+fn closing_quote(rest: &str) -> Option<usize> {
+    let (close, _) = rest.match_indices('\'').find(|&(close, _)| {
+        rest[close + 1..].chars().next().is_none_or(char::is_whitespace)
+    })?;
+
+    if rest[close + 1..].trim().is_empty() {
+        None
+    } else {
+        Some(close)
+    }
+}
+
+/// Reads up to the end of the line, following continuation lines: a line
+/// ending in a backslash continues onto the next one.  Each line is trimmed and
+/// the lines are joined with newlines.
+fn continued_line(input: &[u8]) -> IResult<&[u8], String> {
+    let mut lines = Vec::new();
+    let mut remaining = input;
+
+    loop {
+        let (rest, line) = match not_line_ending(remaining) {
+            IResult::Done(rest, line) => (rest, line),
+            IResult::Error(error) => return IResult::Error(error),
+            IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+        };
+
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => return IResult::Error(error_position!(nom::ErrorKind::MapRes, remaining)),
+        };
+
+        if let Some(line) = line.strip_suffix('\\') {
+            if let IResult::Done(next, _) = line_ending(rest) {
+                lines.push(line.trim());
+                remaining = next;
+                continue;
+            }
+        }
+
+        lines.push(strip_comment(line).trim());
+        return IResult::Done(rest, lines.join("\n"));
+    }
+}
+
+/// Without the `fs` feature there is no way to read included files, so
+/// `!include` directives are kept with an empty sequence.
+#[cfg(not(feature = "fs"))]
+fn load_include(_file: &str, _once: bool, _state: &ParseState) -> Result<UMLTokens, UMLError> {
+    Ok(UMLTokens::new(Vec::new()))
+}
+
+/// Matches the keyword `$kw` in any case, as PlantUML does, provided it isn't
+/// just the start of a longer word such as a participant's name.
+macro_rules! kw (
+    ($i:expr, $kw:expr) => (
+        terminated!($i, tag_no_case!($kw), keyword_end)
+    );
+);
+
+/// Succeeds, consuming nothing, unless `input` continues the word before it.
+fn keyword_end(input: &[u8]) -> IResult<&[u8], ()> {
+    match input.first() {
+        Some(&c) if c.is_ascii_alphabetic() || c == b'_' => {
+            IResult::Error(error_position!(nom::ErrorKind::Tag, input))
+        }
+        _ => IResult::Done(input, ()),
+    }
+}
+
+/// Like `take_until!`, but finds `tag` in any case.
+fn take_until_no_case<'a>(input: &'a [u8], tag: &'static str) -> IResult<&'a [u8], &'a [u8]> {
+    if tag.len() > input.len() {
+        return IResult::Incomplete(Needed::Size(tag.len()));
+    }
+
+    match input.windows(tag.len()).position(|window| window.eq_ignore_ascii_case(tag.as_bytes())) {
+        Some(index) => IResult::Done(&input[index..], &input[..index]),
+        None => IResult::Error(error_position!(nom::ErrorKind::TakeUntil, input)),
+    }
+}
+
+named!(startuml<&[u8], UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("@startuml")                  ~
+        name: opt!(
+            complete!(
+                chain!(
+                    space                 ~
+                    name: map_res!(
+                        not_line_ending,
+                        std::str::from_utf8
+                    )
+                    ,
+                    || name.trim()
+                )
+            )
+        )                                 ~
+        line_ending
+        ,
+        || {
+            UMLToken::StartUML {
+                name: name.filter(|name| !name.is_empty()).map(|name| name.to_string()),
+            }
+        }
+    )
+);
+
+named!(enduml<&[u8], UMLToken>,
     chain!(
         space?                            ~
-        tag!("loop")                      ~
+        kw!("@enduml")                    ~
         space?                            ~
-        count: map_res!(
-            digit,
+        line_ending
+        ,
+        || UMLToken::EndUML
+    )
+);
+
+// Parses the target of an `!include` directive.
+//
+// A quoted target runs up to the closing quote, so it may contain spaces,
+// while an unquoted target stops at the first whitespace.
+named!(include_target<&[u8], &str>,
+    map_res!(
+        alt!(
+            chain!(
+                tag!("\"")                   ~
+                file: apply!(
+                    take_until_or_line_ending, "\""
+                )                             ~
+                tag!("\"")
+                ,
+                || file
+            ) |
+            is_not!(" \t\r\n")
+        ),
+        std::str::from_utf8
+    )
+);
+
+named!(include_directive<&[u8], (&str, bool)>,
+    chain!(
+        space?                            ~
+        tag!("!include")                  ~
+        once: opt!(
+            complete!(
+                tag!("_once")
+            )
+        )                                 ~
+        space                             ~
+        file: include_target              ~
+        space?                            ~
+        line_ending
+        ,
+        || (file, once.is_some())
+    )
+);
+
+/// Parses an `!include` directive and loads the file it names.  If the file
+/// can't be loaded the directive fails to parse, and why is kept in `state`.
+fn include_parser<'a>(input: &'a [u8], state: &ParseState) -> IResult<&'a [u8], UMLToken> {
+    let (rest, (file, once)) = match include_directive(input) {
+        IResult::Done(rest, directive) => (rest, directive),
+        IResult::Error(error) => return IResult::Error(error),
+        IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+    };
+
+    let loaded = if state.skip_includes {
+        Ok(UMLTokens::new(Vec::new()))
+    } else {
+        load_include(file, once, state)
+    };
+
+    match loaded {
+        Ok(sequence) => {
+            IResult::Done(rest,
+                          UMLToken::Include {
+                              file: file.to_string(),
+                              sequence,
+                              once,
+                          })
+        }
+        Err(error) => {
+            *state.include_error.borrow_mut() = Some(error);
+            IResult::Error(error_position!(nom::ErrorKind::MapRes, input))
+        }
+    }
+}
+
+/// Splits a line into whitespace separated words, keeping quoted strings and
+/// `<<...>>` stereotypes together, and returns the byte range of each word.
+/// Quoted strings are words of their own even without whitespace around them,
+/// so `"Foo"as Bar` is three words.
+fn split_words(line: &str) -> Vec<(usize, usize)> {
+    let bytes = line.as_bytes();
+    let mut words = Vec::new();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b' ' || bytes[index] == b'\t' {
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        if bytes[index] == b'"' {
+            index = match line[index + 1..].find('"') {
+                Some(end) => index + end + 2,
+                None => bytes.len(),
+            };
+        } else if line[index..].starts_with("<<") {
+            index = match line[index..].find(">>") {
+                Some(end) => index + end + 2,
+                None => bytes.len(),
+            };
+        } else {
+            while index < bytes.len() && !b" \t\"".contains(&bytes[index]) {
+                index += 1;
+            }
+        }
+
+        words.push((start, index));
+    }
+
+    words
+}
+
+/// Splits a trailing `#colour` off some text, returning the trimmed text before
+/// it and the colour without its `#`.  Quoted strings are never split.
+fn split_colour(text: &str) -> (&str, Option<String>) {
+    let text = text.trim();
+
+    match split_words(text).last() {
+        Some(&(start, end)) if text[start..].starts_with('#') && end - start > 1 => {
+            (text[..start].trim_end(), Some(text[start + 1..end].to_string()))
+        }
+        _ => (text, None),
+    }
+}
+
+/// Parses the remainder of a participant declaration after the keyword, which
+/// gives `kind`.
+///
+/// The name comes first and may be followed by an `as` alias, a
+/// `<<stereotype>>`, a `#colour` and an `order` in any order.
+fn participant_details(kind: ParticipantKind, line: &str) -> Result<UMLToken, ()> {
+    let line = strip_comment(line);
+    let ranges = split_words(line);
+    let words: Vec<&str> = ranges.iter().map(|&(start, end)| &line[start..end]).collect();
+
+    if words.is_empty() {
+        return Err(());
+    }
+
+    // Unquoted names may span several words, so the name runs up to the
+    // first attribute.
+    let name_len = words[1..]
+        .iter()
+        .position(|word| {
+            word.eq_ignore_ascii_case("as") || word.eq_ignore_ascii_case("order") ||
+            word.starts_with("<<") || word.starts_with('#')
+        })
+        .map_or(words.len(), |position| position + 1);
+    let name = line[ranges[0].0..ranges[name_len - 1].1].to_string();
+
+    let mut alias = None;
+    let mut stereotype = None;
+    let mut colour = None;
+    let mut order = None;
+
+    let mut attributes = words[name_len..].iter();
+    while let Some(word) = attributes.next() {
+        if word.eq_ignore_ascii_case("as") && alias.is_none() {
+            alias = Some(attributes.next().ok_or(())?.to_string());
+        } else if word.eq_ignore_ascii_case("order") && order.is_none() {
+            order = Some(attributes.next().ok_or(())?.parse::<i32>().map_err(|_| ())?);
+        } else if word.starts_with("<<") && word.ends_with(">>") && stereotype.is_none() {
+            stereotype = Some(Stereotype::parse(&word[2..word.len() - 2]));
+        } else if word.starts_with('#') && word.len() > 1 && colour.is_none() {
+            colour = Some(word[1..].to_string());
+        } else {
+            return Err(());
+        }
+    }
+
+    let (long_name, short_name) = match alias {
+        Some(alias) => (Some(name), alias),
+        None => (None, name),
+    };
+
+    Ok(UMLToken::Participant {
+        kind,
+        long_name,
+        short_name,
+        stereotype,
+        colour,
+        order,
+    })
+}
+
+named!(participant_parser<&[u8], UMLToken>,
+    chain!(
+        space?                            ~
+        kind: map_res!(
+            map_res!(
+                alt!(
+                    kw!("participant") |
+                    kw!("actor") |
+                    kw!("boundary") |
+                    kw!("control") |
+                    kw!("entity") |
+                    kw!("database") |
+                    kw!("collections") |
+                    kw!("queue")
+                ),
+                std::str::from_utf8
+            ),
+            ParticipantKind::parse
+        )                                 ~
+        space                             ~
+        participant: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            |line| participant_details(kind, line)
+        )                                 ~
+        line_ending
+        ,
+        || participant
+    )
+);
+
+// Parses a note, either inline as `note left of A : text` or as a block ending
+// with `end note`.
+named!(note_parser<&[u8], UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("note")                       ~
+        position: map_res!(
+            apply!(
+                take_until_or_line_ending, ":"
+            ),
             std::str::from_utf8
         )                                 ~
+        text: alt!(
+            complete!(
+                chain!(
+                    tag!(":")             ~
+                    text: continued_line  ~
+                    line_ending?
+                    ,
+                    || text
+                )
+            ) |
+            complete!(
+                chain!(
+                    line_ending           ~
+                    text: map_res!(
+                        apply!(take_until_no_case, "end note"),
+                        std::str::from_utf8
+                    )                     ~
+                    kw!("end note")       ~
+                    space?                ~
+                    line_ending?
+                    ,
+                    || text.trim().to_string()
+                )
+            )
+        )
+        ,
+        || {
+            UMLToken::Note {
+                position: NotePosition::parse(strip_comment(position)),
+                text
+            }
+        }
+    )
+);
+
+/// Splits the comma separated participant list of a `ref over`.
+fn ref_participants(list: &str) -> Result<Vec<String>, ()> {
+    let participants: Vec<String> = list.split(',').map(|name| name.trim().to_string()).collect();
+
+    if participants.iter().any(|name| name.is_empty()) {
+        return Err(());
+    }
+
+    Ok(participants)
+}
+
+// Parses a reference to another diagram, either inline as
+// `ref over A, B : text` or as a block ending with `end ref`.
+named!(ref_parser<&[u8], UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("ref")                        ~
+        space                             ~
+        kw!("over")                       ~
+        space                             ~
+        participants: map_res!(
+            map_res!(
+                apply!(
+                    take_until_or_line_ending, ":"
+                ),
+                std::str::from_utf8
+            ),
+            ref_participants
+        )                                 ~
+        text: alt!(
+            complete!(
+                chain!(
+                    tag!(":")             ~
+                    text: continued_line  ~
+                    line_ending
+                    ,
+                    || text
+                )
+            ) |
+            complete!(
+                chain!(
+                    line_ending           ~
+                    text: map_res!(
+                        apply!(take_until_no_case, "end ref"),
+                        std::str::from_utf8
+                    )                     ~
+                    kw!("end ref")        ~
+                    space?                ~
+                    line_ending?
+                    ,
+                    || text.trim().to_string()
+                )
+            )
+        )
+        ,
+        || {
+            UMLToken::Ref {
+                participants,
+                text,
+            }
+        }
+    )
+);
+
+// Parses notes stacked side by side with the `/ note` continuation syntax.
+named!(split_note_parser<&[u8], UMLToken>,
+    chain!(
+        first: note_parser                ~
+        rest: many1!(
+            complete!(
+                chain!(
+                    space?                ~
+                    tag!("/")             ~
+                    note: note_parser
+                    ,
+                    || note
+                )
+            )
+        )
+        ,
+        || {
+            let mut notes = vec![first];
+            notes.extend(rest);
+
+            UMLToken::SplitNote {
+                notes
+            }
+        }
+    )
+);
+
+named_args!(loop_parser<'a>(state: &ParseState)<UMLToken>,
+    chain!(
         space?                            ~
+        kw!("loop")                       ~
+        count: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            loop_count
+        )                                 ~
         line_ending                       ~
-        sequence: uml_parser              ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
         space?                            ~
         line_ending?                      ~
-        tag!("end")                       ~
-        not_line_ending                   ~
-        line_ending
+        apply!(block_end, "loop")
         ,
         || {
             UMLToken::Loop {
-                sequence: sequence,
-                count: count.parse::<u8>().unwrap()
+                sequence,
+                count
             }
         }
     )
 );
 
-named!(box_parser<&[u8], UMLToken>,
+named_args!(box_parser<'a>(state: &ParseState)<UMLToken>,
     chain!(
         space?                            ~
-        tag!("box")                       ~
+        kw!("box")                        ~
         space?                            ~
         name: map_res!(
             not_line_ending,
@@ -307,141 +1628,378 @@ named!(box_parser<&[u8], UMLToken>,
         )                                 ~
         space?                            ~
         line_ending                       ~
-        sequence: uml_parser              ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
         space?                            ~
         line_ending?                      ~
-        tag!("end box")                   ~
-        not_line_ending                   ~
-        line_ending
+        apply!(block_end, "box")
         ,
         || {
+            let (name, colour) = split_colour(strip_comment(name));
+
             UMLToken::Box {
-                name: name.trim().to_string(),
-                sequence: sequence,
+                name: name.to_string(),
+                colour,
+                sequence,
             }
         }
     )
 );
 
+/// Parses the participants and arrow of a message, e.g. `A -[#red]> B`,
+/// returning a message without any text.
+fn message_details(line: &str) -> Result<UMLToken, ()> {
+    let (left, arrow, colour, right) = find_arrow(line).ok_or(())?;
+    let (from, to) = match arrow.direction {
+        ArrowDirection::ToLeft => (right.trim(), left.trim()),
+        _ => (left.trim(), right.trim()),
+    };
+
+    // A `**` after the receiver creates it.
+    let (to, creates_target) = match to.strip_suffix("**") {
+        Some(to) => (to.trim_end(), true),
+        None => (to, false),
+    };
+
+    Ok(UMLToken::Message {
+        from: from.to_string(),
+        to: to.to_string(),
+        text: None,
+        colour,
+        arrow,
+        creates_target,
+    })
+}
+
 named!(message_parser<&[u8], UMLToken>,
     chain!(
         space?                           ~
-        participant_1: map_res!(
-            take_until_first_tag!("->", "<-"),
-            std::str::from_utf8
-        )                                ~
-        direction: map_res!(
-            alt!(
-                tag!("->") |
-                tag!("<-")
-            ),
-            std::str::from_utf8
-        )                                ~
-        participant_2: map_res!(
-            apply!(
-                take_until_or_line_ending, ":"
+        details: map_res!(
+            map_res!(
+                apply!(
+                    take_until_or_line_ending, ":"
+                ),
+                std::str::from_utf8
             ),
-            std::str::from_utf8
+            message_details
         )                                ~
         text: opt!(
             chain!(
                 tag!(":")                ~
-                text: map_res!(
-                    not_line_ending,
-                    std::str::from_utf8
-                )
+                text: continued_line
                 ,
                 || {
-                    text.trim().to_string()
+                    text
                 }
             )
         )                                ~
         line_ending
         ,
         || {
-            let (from, to) = match direction {
-                "->" => (participant_1, participant_2),
-                "<-" => (participant_2, participant_1),
-                _ => panic!("Unhandled direction: {}", direction)
-            };
-
-            UMLToken::Message {
-                from: from.trim().to_string(),
-                to: to.trim().to_string(),
-                text: text,
-                colour: None
+            let mut message = details;
+            if let UMLToken::Message { text: ref mut message_text, .. } = message {
+                *message_text = text;
             }
+
+            message
         }
 
     )
 );
 
-named!(par_parser<&[u8], UMLToken>,
-  chain!(
-    space?                                ~
-    tag!("par")                           ~
-    not_line_ending                       ~
+/// Returns the label after a `par`, `alt` or `else` keyword, if any.
+fn branch_label(text: &str) -> Option<String> {
+    let label = strip_comment(text).trim();
+
+    if label.is_empty() {
+        None
+    } else {
+        Some(label.to_string())
+    }
+}
+
+// Parses an `end` line, giving the rest of the line.
+named!(end_line<&[u8], &str>,
+    chain!(
+        kw!("end")                        ~
+        line: map_res!(
+            not_line_ending,
+            std::str::from_utf8
+        )                                 ~
+        line_ending
+        ,
+        || line
+    )
+);
+
+/// Parses the line closing a block opened by `keyword`: `end`, or `end`
+/// followed by the keyword, optionally with a trailing comment.
+fn block_end<'a>(input: &'a [u8], keyword: &'static str) -> IResult<&'a [u8], ()> {
+    let (rest, line) = match end_line(input) {
+        IResult::Done(rest, line) => (rest, line),
+        IResult::Error(error) => return IResult::Error(error),
+        IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+    };
+
+    match strip_comment(line).trim() {
+        "" => IResult::Done(rest, ()),
+        name if name.eq_ignore_ascii_case(keyword) && line.starts_with(char::is_whitespace) => {
+            IResult::Done(rest, ())
+        }
+        _ => IResult::Error(error_position!(nom::ErrorKind::Tag, input)),
+    }
+}
+
+/// Builds the branches of a `par` or `alt` from its parsed parts, pairing
+/// each branch with the label on the line that starts it.
+fn branches(label: &str,
+            empty_branch: Option<Option<String>>,
+            uml_array: Vec<(UMLTokens, Option<Option<String>>)>)
+            -> (Vec<UMLTokens>, Vec<Option<String>>) {
+    let mut sequences = Vec::new();
+    let mut labels = vec![branch_label(label)];
+
+    if let Some(else_label) = empty_branch {
+        sequences.push(UMLTokens::new(vec![]));
+        labels.push(else_label);
+    }
+    for (tokens, else_label) in uml_array {
+        sequences.push(tokens);
+        labels.extend(else_label);
+    }
+    labels.resize(sequences.len(), None);
+
+    (sequences, labels)
+}
+
+// Parses an `else` line between branches, giving its label.
+named!(else_line<&[u8], Option<String> >,
+    chain!(
+        space?                            ~
+        kw!("else")                       ~
+        label: map_res!(
+            not_line_ending,
+            std::str::from_utf8
+        )                                 ~
+        line_ending
+        ,
+        || branch_label(label)
+    )
+);
+
+named_args!(par_parser<'a>(state: &ParseState)<UMLToken>,
+  chain!(
+    space?                                ~
+    kw!("par")                            ~
+    label: map_res!(
+        not_line_ending,
+        std::str::from_utf8
+    )                                     ~
     line_ending                           ~
+    empty_branch: opt!(
+        complete!(else_line)
+    )                                     ~
     uml_array: many1!(
         chain!(
-            tokens: uml_parser            ~
+            tokens: apply!(
+                uml_sequence, state
+            )                             ~
             space?                        ~
             line_ending?                  ~
-            tag!("else")?                 ~
-            line_ending?
+            else_label: opt!(
+                complete!(else_line)
+            )
             ,
             || {
-                tokens
+                (tokens, else_label)
             }
         )
     )                                     ~
-    tag!("end")                           ~
-    not_line_ending                       ~
-    line_ending
+    apply!(block_end, "par")
     ,
     || {
+        let (sequences, labels) = branches(label, empty_branch, uml_array);
         UMLToken::Parallel {
-            sequences: uml_array
+            sequences,
+            labels,
         }
     }
   )
 );
 
-named!(alt_parser<&[u8], UMLToken>,
+named_args!(alt_parser<'a>(state: &ParseState)<UMLToken>,
   chain!(
     space?                                ~
-    tag!("alt")                           ~
-    not_line_ending                       ~
+    kw!("alt")                            ~
+    label: map_res!(
+        not_line_ending,
+        std::str::from_utf8
+    )                                     ~
     line_ending                           ~
+    empty_branch: opt!(
+        complete!(else_line)
+    )                                     ~
     uml_array: many1!(
         chain!(
-            tokens: uml_parser            ~
+            tokens: apply!(
+                uml_sequence, state
+            )                             ~
             space?                        ~
             line_ending?                  ~
-            tag!("else")?                 ~
-            line_ending?
+            else_label: opt!(
+                complete!(else_line)
+            )
             ,
             || {
-                tokens
+                (tokens, else_label)
             }
         )
     )                                     ~
-    tag!("end")                           ~
-    not_line_ending                       ~
-    line_ending
+    apply!(block_end, "alt")
     ,
     || {
+        let (sequences, labels) = branches(label, empty_branch, uml_array);
         UMLToken::Alt {
-            sequences: uml_array
+            sequences,
+            labels,
         }
     }
   )
 );
 
+/// Returns the label after a block keyword such as `opt`, failing if the
+/// keyword is just the start of a longer word.
+fn keyword_label(text: &str) -> Result<Option<String>, ()> {
+    if text.is_empty() || text.starts_with(char::is_whitespace) {
+        Ok(branch_label(text))
+    } else {
+        Err(())
+    }
+}
+
+named_args!(opt_parser<'a>(state: &ParseState)<UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("opt")                        ~
+        label: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            keyword_label
+        )                                 ~
+        line_ending                       ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
+        space?                            ~
+        line_ending?                      ~
+        apply!(block_end, "opt")
+        ,
+        || {
+            UMLToken::Opt {
+                label,
+                sequence
+            }
+        }
+    )
+);
+
+named_args!(critical_parser<'a>(state: &ParseState)<UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("critical")                   ~
+        label: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            keyword_label
+        )                                 ~
+        line_ending                       ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
+        space?                            ~
+        line_ending?                      ~
+        apply!(block_end, "critical")
+        ,
+        || {
+            UMLToken::Critical {
+                label,
+                sequence
+            }
+        }
+    )
+);
+
+named_args!(break_parser<'a>(state: &ParseState)<UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("break")                      ~
+        label: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            keyword_label
+        )                                 ~
+        line_ending                       ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
+        space?                            ~
+        line_ending?                      ~
+        apply!(block_end, "break")
+        ,
+        || {
+            UMLToken::Break {
+                label,
+                sequence
+            }
+        }
+    )
+);
+
+/// Returns the label of a `group`, which can't be empty.
+fn group_label(text: &str) -> Result<String, ()> {
+    branch_label(text).ok_or(())
+}
+
+named_args!(group_parser<'a>(state: &ParseState)<UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("group")                      ~
+        space                             ~
+        label: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            group_label
+        )                                 ~
+        line_ending                       ~
+        sequence: apply!(
+            uml_sequence, state
+        )                                 ~
+        space?                            ~
+        line_ending?                      ~
+        apply!(block_end, "group")
+        ,
+        || {
+            UMLToken::Group {
+                label,
+                sequence
+            }
+        }
+    )
+);
+
 named!(delay_parser<&[u8], UMLToken>,
     chain!(
         space?                           ~
-        tag!("delay")                    ~
+        kw!("delay")                     ~
         text: map_res!(
             not_line_ending,
             std::str::from_utf8
@@ -449,8 +2007,144 @@ named!(delay_parser<&[u8], UMLToken>,
         line_ending
         ,
         || {
+            let text = strip_comment(text).trim();
+
             UMLToken::Delay {
-                text: text.trim().to_string()
+                text: text.to_string(),
+                amount: delay_amount(text),
+            }
+        }
+    )
+);
+
+/// The number a delay's text starts with, if any.
+fn delay_amount(text: &str) -> Option<u32> {
+    text.split_whitespace().next()?.parse().ok()
+}
+
+/// Reads the text of a `== text ==` divider, without the leading `==`.  Any
+/// further `=` delimiters are trimmed too.
+fn divider_text(line: &str) -> Result<String, ()> {
+    match strip_comment(line).trim().strip_suffix("==") {
+        Some(text) if !text.trim_matches('=').trim().is_empty() => {
+            Ok(text.trim_matches('=').trim().to_string())
+        }
+        _ => Err(()),
+    }
+}
+
+named!(title_parser<&[u8], UMLToken>,
+    chain!(
+        space?                           ~
+        kw!("title")                     ~
+        space                            ~
+        text: map_res!(
+            not_line_ending,
+            std::str::from_utf8
+        )                                ~
+        line_ending
+        ,
+        || {
+            UMLToken::Title {
+                text: strip_comment(text).trim().to_string()
+            }
+        }
+    )
+);
+
+named!(newpage_parser<&[u8], UMLToken>,
+    chain!(
+        space?                            ~
+        kw!("newpage")                    ~
+        title: opt!(
+            complete!(
+                chain!(
+                    space                 ~
+                    title: map_res!(
+                        not_line_ending,
+                        std::str::from_utf8
+                    )
+                    ,
+                    || strip_comment(title).trim()
+                )
+            )
+        )                                 ~
+        line_ending
+        ,
+        || {
+            UMLToken::NewPage {
+                title: title.filter(|title| !title.is_empty()).map(|title| title.to_string()),
+            }
+        }
+    )
+);
+
+// Parses a comment line, `' text`.
+named!(line_comment<&[u8], UMLToken>,
+    chain!(
+        space?                           ~
+        tag!("'")                        ~
+        text: map_res!(
+            not_line_ending,
+            std::str::from_utf8
+        )                                ~
+        line_ending
+        ,
+        || {
+            UMLToken::Comment {
+                text: text.to_string(),
+                block: false,
+            }
+        }
+    )
+);
+
+// Parses a block comment, `/' text '/`, which may span several lines.
+named!(block_comment<&[u8], UMLToken>,
+    chain!(
+        space?                           ~
+        tag!("/'")                       ~
+        text: map_res!(
+            take_until!("'/"),
+            std::str::from_utf8
+        )                                ~
+        tag!("'/")                       ~
+        space?                           ~
+        line_ending
+        ,
+        || {
+            UMLToken::Comment {
+                text: text.to_string(),
+                block: true,
+            }
+        }
+    )
+);
+
+named!(comment_parser<&[u8], UMLToken>,
+    alt!(
+        line_comment |
+        block_comment
+    )
+);
+
+// Parses a section divider, `== text ==`.
+named!(divider_parser<&[u8], UMLToken>,
+    chain!(
+        space?                           ~
+        tag!("==")                       ~
+        text: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            divider_text
+        )                                ~
+        line_ending
+        ,
+        || {
+            UMLToken::Divider {
+                text
             }
         }
     )
@@ -459,7 +2153,7 @@ named!(delay_parser<&[u8], UMLToken>,
 named!(activate_parser<&[u8], UMLToken>,
     chain!(
         space?                           ~
-        tag!("activate")                 ~
+        kw!("activate")                  ~
         name: map_res!(
             not_line_ending,
             std::str::from_utf8
@@ -467,8 +2161,11 @@ named!(activate_parser<&[u8], UMLToken>,
         line_ending
         ,
         || {
+            let (name, colour) = split_colour(strip_comment(name));
+
             UMLToken::Activate {
-                name: name.trim().to_string()
+                name: name.to_string(),
+                colour,
             }
         }
     )
@@ -477,7 +2174,7 @@ named!(activate_parser<&[u8], UMLToken>,
 named!(deactivate_parser<&[u8], UMLToken>,
     chain!(
         space?                           ~
-        tag!("deactivate")               ~
+        kw!("deactivate")                ~
         name: map_res!(
             not_line_ending,
             std::str::from_utf8
@@ -485,8 +2182,41 @@ named!(deactivate_parser<&[u8], UMLToken>,
         line_ending
         ,
         || {
+            let (name, colour) = split_colour(strip_comment(name));
+
             UMLToken::Deactivate {
-                name: name.trim().to_string()
+                name: name.to_string(),
+                colour,
+            }
+        }
+    )
+);
+
+/// The name in a `create`, dropping any participant keyword before it as in
+/// `create participant A`.
+fn create_name(text: &str) -> String {
+    let text = text.trim();
+
+    match text.split_once(char::is_whitespace) {
+        Some((keyword, name)) if ParticipantKind::parse(keyword).is_ok() => name.trim().to_string(),
+        _ => text.to_string(),
+    }
+}
+
+named!(create_parser<&[u8], UMLToken>,
+    chain!(
+        space?                          ~
+        kw!("create")                   ~
+        space                           ~
+        name: map_res!(
+            not_line_ending,
+            std::str::from_utf8
+        )                                ~
+        line_ending
+        ,
+        || {
+            UMLToken::Create {
+                name: create_name(strip_comment(name))
             }
         }
     )
@@ -495,7 +2225,7 @@ named!(deactivate_parser<&[u8], UMLToken>,
 named!(destroy_parser<&[u8], UMLToken>,
     chain!(
         space?                          ~
-        tag!("destroy")                 ~
+        kw!("destroy")                  ~
         name: map_res!(
             not_line_ending,
             std::str::from_utf8
@@ -504,49 +2234,137 @@ named!(destroy_parser<&[u8], UMLToken>,
         ,
         || {
             UMLToken::Destroy {
-                name: name.trim().to_string()
+                name: strip_comment(name).trim().to_string()
             }
         }
     )
 );
 
-named!(pub uml_parser<&[u8], UMLTokens >,
+named_args!(uml_statement<'a>(state: &ParseState)<UMLToken>,
     chain!(
-        tokens: many1!(
-            chain!(
-                not!(
-                    peek!(
-                        alt!(
-                            tag!("else") |
-                            tag!("end")
-                        )
-                    )
-                )                              ~
-                space?                         ~
-                line_ending?                   ~
-                token: alt!(
-                    startuml |
-                    enduml |
-                    include_parser |
-                    note_parser |
-                    participant_parser |
-                    par_parser |
-                    alt_parser |
-                    delay_parser |
-                    activate_parser |
-                    deactivate_parser |
-                    destroy_parser |
-                    box_parser |
-                    loop_parser |
-                    message_parser
+        not!(
+            peek!(
+                alt!(
+                    kw!("else") |
+                    kw!("end")
                 )
-                ,
-                || {
-                    token
-                }
             )
+        )                                  ~
+        space?                             ~
+        apply!(
+            skipped_line_ending, state
+        )?                                 ~
+        token: alt!(
+            apply!(blank_line, state) |
+            comment_parser |
+            startuml |
+            enduml |
+            title_parser |
+            newpage_parser |
+            apply!(include_parser, state) |
+            split_note_parser |
+            note_parser |
+            ref_parser |
+            participant_parser |
+            apply!(par_parser, state) |
+            apply!(alt_parser, state) |
+            apply!(opt_parser, state) |
+            apply!(critical_parser, state) |
+            apply!(break_parser, state) |
+            apply!(group_parser, state) |
+            delay_parser |
+            divider_parser |
+            activate_parser |
+            deactivate_parser |
+            create_parser |
+            destroy_parser |
+            autonumber_parser |
+            apply!(box_parser, state) |
+            apply!(loop_parser, state) |
+            message_parser
         )
         ,
+        || {
+            token
+        }
+    )
+);
+
+/// Parses the arguments of a `loop`: a count, which may follow the keyword
+/// directly, or a label.
+fn loop_count(args: &str) -> Result<LoopCount, ()> {
+    if !args.starts_with(|c: char| c.is_whitespace() || c.is_ascii_digit()) {
+        return Err(());
+    }
+
+    LoopCount::parse(strip_comment(args)).ok_or(())
+}
+
+/// Parses the arguments of an `autonumber` directive: nothing, a start
+/// number, `stop`, or `inc <level>`.  A start number, or nothing, may be
+/// followed by a quoted format, which is kept exactly as written.
+fn autonumber_details(args: &str) -> Result<UMLToken, ()> {
+    let args = strip_comment(args);
+    if !args.is_empty() && !args.starts_with(' ') && !args.starts_with('\t') {
+        return Err(());
+    }
+
+    let (args, format) = match args.find('"') {
+        Some(open) => {
+            let quoted = args[open..].trim_end();
+            if quoted.len() < 2 || !quoted.ends_with('"') {
+                return Err(());
+            }
+            (&args[..open], Some(quoted[1..quoted.len() - 1].to_string()))
+        }
+        None => (args, None),
+    };
+
+    // As with the keyword itself, `stop` and `inc` may be in any case.
+    let words: Vec<&str> = args.split_whitespace().collect();
+    let (start, stop, inc) = match words[..] {
+        [] => (None, false, None),
+        [stop] if stop.eq_ignore_ascii_case("stop") && format.is_none() => (None, true, None),
+        [inc, level] if inc.eq_ignore_ascii_case("inc") && format.is_none() => {
+            (None, false, Some(level.to_string()))
+        }
+        [start] => (Some(start.parse::<u32>().map_err(|_| ())?), false, None),
+        _ => return Err(()),
+    };
+
+    Ok(UMLToken::Autonumber {
+        start,
+        stop,
+        inc,
+        format,
+    })
+}
+
+named!(autonumber_parser<&[u8], UMLToken>,
+    chain!(
+        space?                           ~
+        kw!("autonumber")                ~
+        autonumber: map_res!(
+            map_res!(
+                not_line_ending,
+                std::str::from_utf8
+            ),
+            autonumber_details
+        )                                ~
+        line_ending
+        ,
+        || autonumber
+    )
+);
+
+named!(pub uml_parser<&[u8], UMLTokens >,
+    apply!(uml_sequence, &ParseState::default())
+);
+
+named_args!(uml_sequence<'a>(state: &ParseState)<UMLTokens>,
+    chain!(
+        tokens: many1!(apply!(uml_statement, state))
+        ,
         || {
             UMLTokens::new(tokens)
         }
@@ -570,6 +2388,8 @@ mod tests {
                             to: "PERSON_B".to_string(),
                             text: None,
                             colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
                         }));
     }
 
@@ -585,6 +2405,8 @@ mod tests {
                             to: "PERSON_B".to_string(),
                             text: Some("Test".to_string()),
                             colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
                         }));
     }
 
@@ -600,6 +2422,8 @@ mod tests {
                             to: "PERSON_B".to_string(),
                             text: Some("Test".to_string()),
                             colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
                         }));
     }
 
@@ -611,8 +2435,12 @@ mod tests {
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Participant {
+                            kind: ParticipantKind::Participant,
                             short_name: "test".to_string(),
                             long_name: None,
+                            stereotype: None,
+                            colour: None,
+                            order: None,
                         }));
     }
 
@@ -624,8 +2452,12 @@ mod tests {
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Participant {
+                            kind: ParticipantKind::Participant,
                             short_name: "hello".to_string(),
                             long_name: Some("\"test name\"".to_string()),
+                            stereotype: None,
+                            colour: None,
+                            order: None,
                         }));
     }
 
@@ -641,12 +2473,20 @@ participant "test name" as hello
                    Done(&[][..],
                         UMLTokens {
                             tokens: vec![UMLToken::Participant {
+                                             kind: ParticipantKind::Participant,
                                              short_name: "\"test name\"".to_string(),
                                              long_name: None,
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          },
                                          UMLToken::Participant {
+                                             kind: ParticipantKind::Participant,
                                              short_name: "hello".to_string(),
                                              long_name: Some("\"test name\"".to_string()),
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          }],
                         }));
     }
@@ -667,12 +2507,16 @@ TESTB->TESTA: Hello
                                              to: "TESTB".to_string(),
                                              text: None,
                                              colour: None,
+                                             arrow: Arrow::default(),
+                                             creates_target: false,
                                          },
                                          UMLToken::Message {
                                              from: "TESTB".to_string(),
                                              to: "TESTA".to_string(),
                                              text: Some("Hello".to_string()),
                                              colour: None,
+                                             arrow: Arrow::default(),
+                                             creates_target: false,
                                          }],
                         }));
     }
@@ -685,8 +2529,12 @@ TESTB->TESTA: Hello
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Participant {
+                            kind: ParticipantKind::Actor,
                             short_name: "test".to_string(),
                             long_name: None,
+                            stereotype: None,
+                            colour: None,
+                            order: None,
                         }));
     }
 
@@ -698,7 +2546,7 @@ TESTB->TESTA: Hello
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Note {
-                            position: "position".to_string(),
+                            position: NotePosition::Other("position".to_string()),
                             text: "quick test".to_string(),
                         }));
     }
@@ -709,7 +2557,10 @@ TESTB->TESTA: Hello
         let result = ::activate_parser(test_uml.as_bytes());
 
         assert_eq!(result,
-                   Done(&[][..], UMLToken::Activate { name: "test".to_string() }));
+                   Done(&[][..], UMLToken::Activate {
+                            name: "test".to_string(),
+                            colour: None,
+                        }));
     }
 
     #[test]
@@ -718,7 +2569,19 @@ TESTB->TESTA: Hello
         let result = ::deactivate_parser(test_uml.as_bytes());
 
         assert_eq!(result,
-                   Done(&[][..], UMLToken::Deactivate { name: "test".to_string() }));
+                   Done(&[][..], UMLToken::Deactivate {
+                            name: "test".to_string(),
+                            colour: None,
+                        }));
+    }
+
+    #[test]
+    fn test_create_parser() {
+        let test_uml = "create test\n";
+        let result = ::create_parser(test_uml.as_bytes());
+
+        assert_eq!(result,
+                   Done(&[][..], UMLToken::Create { name: "test".to_string() }));
     }
 
     #[test]
@@ -741,7 +2604,7 @@ TESTB->TESTA: Hello
                           end par
 "#;
 
-        let result = ::par_parser(test_uml.as_bytes());
+        let result = ::par_parser(test_uml.as_bytes(), &ParseState::default());
 
         assert_eq!(result,
                    Done(&[][..],
@@ -752,14 +2615,17 @@ TESTB->TESTA: Hello
                                                                  to: "PERSON_B".to_string(),
                                                                  text: Some("Test".to_string()),
                                                                  colour: None,
+                                                                 arrow: Arrow::default(),
+                                                                 creates_target: false,
                                                              }],
                                             },
                                             UMLTokens {
                                                 tokens: vec![UMLToken::Note {
-                                                                 position: "position".to_string(),
+                                                                 position: NotePosition::Other("position".to_string()),
                                                                  text: "quick test".to_string(),
                                                              }],
                                             }],
+                            labels: vec![None, None],
                         }))
     }
 
@@ -785,21 +2651,21 @@ TESTB->TESTA: Hello
                           end par
 "#;
 
-        let result = ::par_parser(test_uml.as_bytes());
+        let result = ::par_parser(test_uml.as_bytes(), &ParseState::default());
 
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Parallel {
                             sequences: vec![UMLTokens {
                                                 tokens: vec![UMLToken::Note {
-                                                                 position: "position".to_string(),
+                                                                 position: NotePosition::Other("position".to_string()),
                                                                  text: "outer par".to_string(),
                                                              },
                                                              UMLToken::Parallel {
                                                                  sequences: vec![UMLTokens {
                                                                                      tokens: vec![
                                             UMLToken::Note {
-                                                position: "position".to_string(),
+                                                position: NotePosition::Other("position".to_string()),
                                                 text: "inner par".to_string()
                                             },
                                         ],
@@ -807,19 +2673,21 @@ TESTB->TESTA: Hello
                                                                                  UMLTokens {
                                                                                      tokens: vec![
                                             UMLToken::Note {
-                                                position: "position".to_string(),
+                                                position: NotePosition::Other("position".to_string()),
                                                 text: "inner".to_string()
                                             },
                                         ],
                                                                                  }],
+                                                                 labels: vec![None, None],
                                                              }],
                                             },
                                             UMLTokens {
                                                 tokens: vec![UMLToken::Note {
-                                                                 position: "position".to_string(),
+                                                                 position: NotePosition::Other("position".to_string()),
                                                                  text: "outer else".to_string(),
                                                              }],
                                             }],
+                            labels: vec![None, None],
                         }))
     }
 
@@ -841,9 +2709,11 @@ TESTB->TESTA: Hello
                                              to: "PERSON_B".to_string(),
                                              text: Some("Test".to_string()),
                                              colour: None,
+                                             arrow: Arrow::default(),
+                                             creates_target: false,
                                          },
                                          UMLToken::Note {
-                                             position: "position".to_string(),
+                                             position: NotePosition::Other("position".to_string()),
                                              text: "quick test".to_string(),
                                          }],
                         }));
@@ -858,15 +2728,15 @@ TESTB->TESTA: Hello
                           end loop
 "#;
 
-        let result = ::loop_parser(test_uml.as_bytes());
+        let result = ::loop_parser(test_uml.as_bytes(), &ParseState::default());
 
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Loop {
-                            count: 10,
+                            count: LoopCount::Times(10),
                             sequence: UMLTokens {
                                 tokens: vec![UMLToken::Note {
-                                                 position: "position".to_string(),
+                                                 position: NotePosition::Other("position".to_string()),
                                                  text: "quick test".to_string(),
                                              }],
                             },
@@ -881,15 +2751,16 @@ TESTB->TESTA: Hello
                             end note
                           end box
 "#;
-        let result = ::box_parser(test_uml.as_bytes());
+        let result = ::box_parser(test_uml.as_bytes(), &ParseState::default());
 
         assert_eq!(result,
                    Done(&[][..],
                         UMLToken::Box {
                             name: "test".to_string(),
+                            colour: None,
                             sequence: UMLTokens {
                                 tokens: vec![UMLToken::Note {
-                                                 position: "position".to_string(),
+                                                 position: NotePosition::Other("position".to_string()),
                                                  text: "quick test".to_string(),
                                              }],
                             },
@@ -907,10 +2778,14 @@ TESTB->TESTA: Hello
         assert_eq!(result,
                    Done(&[][..],
                         UMLTokens {
-                            tokens: vec![UMLToken::StartUML,
+                            tokens: vec![UMLToken::StartUML { name: None },
                                          UMLToken::Participant {
+                                             kind: ParticipantKind::Participant,
                                              short_name: "test1".to_string(),
                                              long_name: None,
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          },
                                          UMLToken::EndUML],
                         }));
@@ -928,10 +2803,14 @@ TESTB->TESTA: Hello
         assert_eq!(result,
                    Done(&[][..],
                         UMLTokens {
-                            tokens: vec![UMLToken::StartUML,
+                            tokens: vec![UMLToken::StartUML { name: None },
                                          UMLToken::Participant {
+                                             kind: ParticipantKind::Participant,
                                              short_name: "test1".to_string(),
                                              long_name: None,
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          },
                                          UMLToken::EndUML],
                         }));
@@ -967,27 +2846,35 @@ deactivate test deactivate
         assert_eq!(result,
                    Done(&[][..],
                         UMLTokens {
-                            tokens: vec![UMLToken::StartUML,
+                            tokens: vec![UMLToken::StartUML { name: None },
                                          UMLToken::Participant {
+                                             kind: ParticipantKind::Participant,
                                              short_name: "test1".to_string(),
                                              long_name: None,
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          },
                                          UMLToken::Note {
-                                             position: "position".to_string(),
+                                             position: NotePosition::Other("position".to_string()),
                                              text: "quick test".to_string(),
                                          },
                                          UMLToken::Participant {
+                                             kind: ParticipantKind::Actor,
                                              short_name: "test".to_string(),
                                              long_name: None,
+                                             stereotype: None,
+                                             colour: None,
+                                             order: None,
                                          },
                                          UMLToken::Loop {
-                                             count: 5,
+                                             count: LoopCount::Times(5),
                                              sequence: UMLTokens {
                                                  tokens: vec![UMLToken::Parallel {
                                                                   sequences: vec![UMLTokens {
                                                                                       tokens: vec![
                                             UMLToken::Note {
-                                                position: "position".to_string(),
+                                                position: NotePosition::Other("position".to_string()),
                                                 text: "inside par".to_string()
                                             }
                                         ],
@@ -995,17 +2882,22 @@ deactivate test deactivate
                                                                                   UMLTokens {
                                                                                       tokens: vec![
                                             UMLToken::Note {
-                                                position: "position".to_string(),
+                                                position: NotePosition::Other("position".to_string()),
                                                 text: "else clause".to_string()
                                             }
                                         ],
                                                                                   }],
+                                                                  labels: vec![Some("test".to_string()), None],
                                                               }],
                                              },
                                          },
-                                         UMLToken::Activate { name: "test activate".to_string() },
+                                         UMLToken::Activate {
+                                             name: "test activate".to_string(),
+                                             colour: None,
+                                         },
                                          UMLToken::Deactivate {
                                              name: "test deactivate".to_string(),
+                                             colour: None,
                                          },
                                          UMLToken::EndUML],
                         }));
@@ -1025,8 +2917,11 @@ delay 50
         assert_eq!(result,
                    Done(&[][..],
                         UMLTokens {
-                            tokens: vec![UMLToken::StartUML,
-                                         UMLToken::Delay { text: "50".to_string() },
+                            tokens: vec![UMLToken::StartUML { name: None },
+                                         UMLToken::Delay {
+                                             text: "50".to_string(),
+                                             amount: Some(50),
+                                         },
                                          UMLToken::EndUML],
                         }));
     }
@@ -1050,7 +2945,7 @@ end par
         assert_eq!(result,
                    Done(&[][..],
                         UMLTokens {
-                            tokens: vec![UMLToken::StartUML,
+                            tokens: vec![UMLToken::StartUML { name: None },
                                          UMLToken::Parallel {
                                              sequences: vec![UMLTokens {
                                                                  tokens: vec![
@@ -1058,7 +2953,9 @@ end par
                                     from: "PERSON_A".to_string(),
                                     to: "PERSON_B".to_string(),
                                     text: Some("Hello 1".to_string()),
-                                    colour: None
+                                    colour: None,
+                                    arrow: Arrow::default(),
+                                    creates_target: false
                                 }
                             ],
                                                              },
@@ -1068,7 +2965,9 @@ end par
                                     from: "PERSON_A".to_string(),
                                     to: "PERSON_B".to_string(),
                                     text: Some("Hello 2".to_string()),
-                                    colour: None
+                                    colour: None,
+                                    arrow: Arrow::default(),
+                                    creates_target: false
                                 }
                             ],
                                                              },
@@ -1078,10 +2977,13 @@ end par
                                     from: "PERSON_A".to_string(),
                                     to: "PERSON_B".to_string(),
                                     text: Some("Hello 3".to_string()),
-                                    colour: None
+                                    colour: None,
+                                    arrow: Arrow::default(),
+                                    creates_target: false
                                 }
                             ],
                                                              }],
+                                             labels: vec![None, None, None],
                                          },
                                          UMLToken::EndUML],
                         }));
@@ -1139,7 +3041,7 @@ end box
                           end alt
 "#;
 
-        let result = ::alt_parser(test_uml.as_bytes());
+        let result = ::alt_parser(test_uml.as_bytes(), &ParseState::default());
 
         assert_eq!(result,
                    Done(&[][..],
@@ -1150,14 +3052,3517 @@ end box
                                                                  to: "PERSON_B".to_string(),
                                                                  text: Some("Test".to_string()),
                                                                  colour: None,
+                                                                 arrow: Arrow::default(),
+                                                                 creates_target: false,
                                                              }],
                                             },
                                             UMLTokens {
                                                 tokens: vec![UMLToken::Note {
-                                                                 position: "position".to_string(),
+                                                                 position: NotePosition::Other("position".to_string()),
                                                                  text: "quick test".to_string(),
                                                              }],
                                             }],
+                            labels: vec![None, None],
                         }))
     }
+
+    #[test]
+    fn test_include_target_quoted_with_spaces() {
+        let result = ::include_target("\"my dir/sub file.uml\"\n".as_bytes());
+
+        assert_eq!(result, Done(&b"\n"[..], "my dir/sub file.uml"));
+    }
+
+    #[test]
+    fn test_include_target_unquoted() {
+        let result = ::include_target("sub.uml trailing\n".as_bytes());
+
+        assert_eq!(result, Done(&b" trailing\n"[..], "sub.uml"));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_include_parser_quoted_path_with_spaces() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_include_{}", std::process::id()));
+        let sub_dir = dir.join("my dir");
+        std::fs::create_dir_all(&sub_dir).unwrap();
+        let included = sub_dir.join("sub file.uml");
+        std::fs::write(&included, "participant inner\n").unwrap();
+
+        let test_uml = format!("!include \"{}\"\n", included.to_str().unwrap());
+        let result = ::include_parser(test_uml.as_bytes(), &ParseState::default());
+
+        assert_eq!(result,
+                   Done(&[][..],
+                        UMLToken::Include {
+                            file: included.to_str().unwrap().to_string(),
+                            sequence: UMLTokens {
+                                tokens: vec![UMLToken::Participant {
+                                                 kind: ParticipantKind::Participant,
+                                                 short_name: "inner".to_string(),
+                                                 long_name: None,
+                                                 stereotype: None,
+                                                 colour: None,
+                                                 order: None,
+                                             }],
+                            },
+                            once: false,
+                        }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_render_model() {
+        let test_uml = r#"participant "Alice Long" as A
+B->A: hello
+loop 2
+A->C: ping
+note over A, C
+both
+end note
+end loop
+"#;
+        let (_, uml_tokens) = ::uml_parser(test_uml.as_bytes()).unwrap();
+        let model = uml_tokens.to_render_model();
+
+        assert_eq!(model.lanes,
+                   vec![Lane {
+                            index: 0,
+                            name: "A".to_string(),
+                            label: Some("\"Alice Long\"".to_string()),
+                            group: None,
+                        },
+                        Lane {
+                            index: 1,
+                            name: "B".to_string(),
+                            label: None,
+                            group: None,
+                        },
+                        Lane {
+                            index: 2,
+                            name: "C".to_string(),
+                            label: None,
+                            group: None,
+                        }]);
+        assert_eq!(model.groups,
+                   vec![Group {
+                            id: 0,
+                            kind: "loop".to_string(),
+                            label: Some("2".to_string()),
+                            parent: None,
+                        }]);
+        assert_eq!(model.events,
+                   vec![Event {
+                            group: None,
+                            branch: 0,
+                            kind: EventKind::Message {
+                                from: 1,
+                                to: 0,
+                                text: Some("hello".to_string()),
+                            },
+                        },
+                        Event {
+                            group: Some(0),
+                            branch: 0,
+                            kind: EventKind::Message {
+                                from: 0,
+                                to: 2,
+                                text: Some("ping".to_string()),
+                            },
+                        },
+                        Event {
+                            group: Some(0),
+                            branch: 0,
+                            kind: EventKind::Note {
+                                lanes: vec![0, 2],
+                                text: "both".to_string(),
+                            },
+                        }]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_render_model_json() {
+        let test_uml = "A->B: hello\n";
+        let (_, uml_tokens) = ::uml_parser(test_uml.as_bytes()).unwrap();
+        let json = ::serde_json::to_string(&uml_tokens.to_render_model()).unwrap();
+
+        assert_eq!(json,
+                   r#"{"lanes":[{"index":0,"name":"A","label":null,"group":null},{"index":1,"name":"B","label":null,"group":null}],"groups":[],"events":[{"group":null,"branch":0,"type":"message","from":0,"to":1,"text":"hello"}]}"#);
+    }
+
+    #[test]
+    fn test_split_note_parser() {
+        let test_uml = r#"note over A
+first
+end note
+/ note over B
+second
+end note
+"#;
+        let result = ::uml_parser(test_uml.as_bytes());
+
+        assert_eq!(result,
+                   Done(&[][..],
+                        UMLTokens {
+                            tokens: vec![UMLToken::SplitNote {
+                                             notes: vec![UMLToken::Note {
+                                                             position: NotePosition::Over(vec!["A".to_string()]),
+                                                             text: "first".to_string(),
+                                                         },
+                                                         UMLToken::Note {
+                                                             position: NotePosition::Over(vec!["B".to_string()]),
+                                                             text: "second".to_string(),
+                                                         }],
+                                         }],
+                        }));
+
+        let (_, uml_tokens) = result.unwrap();
+        assert_eq!(format!("{}", uml_tokens), test_uml);
+    }
+
+    #[test]
+    fn test_participant_parser_attributes() {
+        let expected = UMLToken::Participant {
+            kind: ParticipantKind::Participant,
+            long_name: Some("\"Long\"".to_string()),
+            short_name: "A".to_string(),
+            stereotype: Some(Stereotype {
+                spot: None,
+                text: "svc".to_string(),
+            }),
+            colour: Some("red".to_string()),
+            order: Some(5),
+        };
+
+        for test_uml in &["participant \"Long\" as A <<svc>> #red order 5\n",
+                          "participant \"Long\" #red order 5 <<svc>> as A\n",
+                          "participant \"Long\" order 5 as A #red <<svc>>\n"] {
+            let result = ::participant_parser(test_uml.as_bytes());
+
+            assert_eq!(result, Done(&[][..], expected.clone()));
+        }
+
+        let rendered = format!("{}", expected);
+        assert_eq!(rendered, "participant \"Long\" as A <<svc>> #red order 5\n");
+        assert_eq!(::participant_parser(rendered.as_bytes()),
+                   Done(&[][..], expected));
+    }
+
+    #[test]
+    fn test_parse_uml_collect_errors() {
+        let test_uml = r#"@startuml
+A->B: ok
+loop 4294967296
+participant X
+end note
+@enduml
+"#;
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(test_uml);
+
+        assert_eq!(uml_tokens,
+                   UMLTokens {
+                       tokens: vec![UMLToken::StartUML { name: None },
+                                    UMLToken::Message {
+                                        from: "A".to_string(),
+                                        to: "B".to_string(),
+                                        text: Some("ok".to_string()),
+                                        colour: None,
+                                        arrow: Arrow::default(),
+                                        creates_target: false,
+                                    },
+                                    UMLToken::Participant {
+                                        kind: ParticipantKind::Participant,
+                                        short_name: "X".to_string(),
+                                        long_name: None,
+                                        stereotype: None,
+                                        colour: None,
+                                        order: None,
+                                    },
+                                    UMLToken::EndUML],
+                   });
+        assert_eq!(errors,
+                   vec![UMLError::Parse {
+                            line: 3,
+                            column: 1,
+                            snippet: "loop 4294967296".to_string(),
+                        },
+                        UMLError::Parse {
+                            line: 5,
+                            column: 1,
+                            snippet: "end note".to_string(),
+                        }]);
+    }
+
+    #[test]
+    fn test_autonumber_inc_parser() {
+        let test_uml = "autonumber inc A\n";
+        let result = ::autonumber_parser(test_uml.as_bytes());
+
+        assert_eq!(result,
+                   Done(&[][..],
+                        UMLToken::Autonumber {
+                            start: None,
+                            stop: false,
+                            inc: Some("A".to_string()),
+                            format: None,
+                        }));
+
+        let (_, token) = result.unwrap();
+        assert_eq!(format!("{}", token), test_uml);
+    }
+
+    #[cfg(not(feature = "fs"))]
+    #[test]
+    fn test_include_without_fs() {
+        let test_uml = "@startuml\n!include other.uml\n@enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(test_uml);
+
+        assert_eq!(errors, vec![]);
+        assert_eq!(uml_tokens,
+                   UMLTokens {
+                       tokens: vec![UMLToken::StartUML { name: None },
+                                    UMLToken::Include {
+                                        file: "other.uml".to_string(),
+                                        sequence: UMLTokens { tokens: vec![] },
+                                        once: false,
+                                    },
+                                    UMLToken::EndUML],
+                   });
+    }
+
+    #[test]
+    fn test_parse_message_with_continuation() {
+        let test_uml = "PERSON_A->PERSON_B: first part \\\n    second part\n";
+        let result = ::message_parser(test_uml.as_bytes());
+
+        assert_eq!(result,
+                   Done(&[][..],
+                        UMLToken::Message {
+                            from: "PERSON_A".to_string(),
+                            to: "PERSON_B".to_string(),
+                            text: Some("first part\nsecond part".to_string()),
+                            colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
+                        }));
+
+        let (_, token) = result.unwrap();
+        let rendered = format!("{}", token);
+        assert_eq!(rendered, "PERSON_A->PERSON_B:first part\\\nsecond part\n");
+        assert_eq!(::message_parser(rendered.as_bytes()), Done(&[][..], token));
+    }
+
+    #[test]
+    fn test_map_messages() {
+        let test_uml = r#"a->b:Hello
+alt
+a->b:inside alt
+else
+b->a
+end alt
+"#;
+        let (_, mut uml_tokens) = ::uml_parser(test_uml.as_bytes()).unwrap();
+        uml_tokens.map_messages(|text| text.to_uppercase());
+
+        assert_eq!(format!("{}", uml_tokens),
+                   r#"a->b:HELLO
+alt
+a->b:INSIDE ALT
+else
+b->a
+end alt
+"#);
+    }
+
+    #[test]
+    fn test_extract_uml_blocks() {
+        let text = r#"# Design
+
+Some prose about the first flow.
+
+  @startuml
+  a->b:Hello
+  @enduml
+
+More prose, then the second flow:
+
+@startuml
+b->a
+@enduml
+Trailing prose.
+"#;
+        let blocks = ::extract_uml_blocks(text);
+
+        assert_eq!(blocks,
+                   vec!["@startuml\n  a->b:Hello\n  @enduml\n", "@startuml\nb->a\n@enduml\n"]);
+
+        for block in blocks {
+            let (remaining, _) = ::uml_parser(block.as_bytes()).unwrap();
+            assert!(remaining.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_delay_duration() {
+        let (_, numeric) = ::delay_parser("delay 50\n".as_bytes()).unwrap();
+        let (_, textual) = ::delay_parser("delay a while\n".as_bytes()).unwrap();
+        let ellipsis = UMLToken::Delay {
+            text: "...".to_string(),
+            amount: None,
+        };
+
+        assert_eq!(numeric.duration(), Some(50));
+        assert_eq!(textual.duration(), None);
+        assert_eq!(ellipsis.duration(), None);
+        assert_eq!(format!("{}", numeric), "delay 50\n");
+    }
+
+    #[test]
+    fn test_box_parser_colour() {
+        let cases = [("box test one\n", "test one", None),
+                     ("box test one #red\n", "test one", Some("red".to_string())),
+                     ("box \"quoted name\" #red\n", "\"quoted name\"", Some("red".to_string())),
+                     ("box \"quoted #name\"\n", "\"quoted #name\"", None)];
+
+        for &(header, name, ref colour) in &cases {
+            let test_uml = format!("{}participant a\nend box\n", header);
+            let result = ::box_parser(test_uml.as_bytes(), &ParseState::default());
+
+            assert_eq!(result,
+                       Done(&[][..],
+                            UMLToken::Box {
+                                name: name.to_string(),
+                                colour: colour.clone(),
+                                sequence: UMLTokens {
+                                    tokens: vec![UMLToken::Participant {
+                                                     kind: ParticipantKind::Participant,
+                                                     short_name: "a".to_string(),
+                                                     long_name: None,
+                                                     stereotype: None,
+                                                     colour: None,
+                                                     order: None,
+                                                 }],
+                                },
+                            }));
+
+            let (_, token) = result.unwrap();
+            assert_eq!(format!("{}", token), test_uml);
+        }
+    }
+
+    #[test]
+    fn test_mermaid_round_trip() {
+        let mermaid = r#"sequenceDiagram
+    participant A as Alice
+    participant B
+    A->>B: hello
+    activate B
+    loop 3
+        B-->>A: ping
+        A-xB: lost
+    end
+    alt
+        A->>B: yes
+    else
+        Note over A,B: no
+    end
+    deactivate B
+"#;
+        let uml_tokens = ::from_mermaid(mermaid).unwrap();
+
+        assert_eq!(format!("{}", uml_tokens),
+                   r#"participant "Alice" as A
+participant B
+A->B:hello
+activate B
+loop 3
+B-->A:ping
+A->x B:lost
+end loop
+alt
+A->B:yes
+else
+note over A, B
+no
+end note
+end alt
+deactivate B
+"#);
+        assert_eq!(uml_tokens.to_mermaid(), mermaid);
+    }
+
+    #[test]
+    fn test_from_mermaid_unsupported() {
+        let mermaid = "sequenceDiagram\n    A-->>B: reply\n    rect rgb(0, 0, 255)\n    end\n";
+
+        assert_eq!(::from_mermaid(mermaid),
+                   Err(UMLError::Parse {
+                       line: 3,
+                       column: 5,
+                       snippet: "rect rgb(0, 0, 255)".to_string(),
+                   }));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_dir() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_dir_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.uml"), "@startuml\na->b\n@enduml\n").unwrap();
+        std::fs::write(dir.join("b.uml"), "@startuml\nb->a\n@enduml\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a diagram\n").unwrap();
+        std::fs::write(dir.join("sub").join("c.uml"), "@startuml\n@enduml\n").unwrap();
+
+        let mut cfg = ParserConfig::default();
+        let diagrams = ::parse_uml_dir(&dir, &cfg).unwrap();
+        let mut paths: Vec<_> = diagrams.keys().cloned().collect();
+        paths.sort();
+        assert_eq!(paths, vec![dir.join("a.uml"), dir.join("b.uml")]);
+        assert_eq!(format!("{}", diagrams[&dir.join("b.uml")]),
+                   "@startuml\nb->a\n@enduml\n");
+
+        cfg.recursive = true;
+        let diagrams = ::parse_uml_dir(&dir, &cfg).unwrap();
+        assert_eq!(diagrams.len(), 3);
+        assert!(diagrams.contains_key(&dir.join("sub").join("c.uml")));
+
+        std::fs::write(dir.join("bad.uml"), "@startuml\nloop 4294967296\n@enduml\n").unwrap();
+        assert_eq!(::parse_uml_dir(&dir, &cfg),
+                   Err(UMLError::File {
+                       path: dir.join("bad.uml"),
+                       error: Box::new(UMLError::Parse {
+                           line: 2,
+                           column: 1,
+                           snippet: "loop 4294967296".to_string(),
+                       }),
+                   }));
+
+        cfg.continue_on_error = true;
+        assert_eq!(::parse_uml_dir(&dir, &cfg).unwrap().len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_participant_first_use() {
+        let uml = "@startuml\n\
+                   a->b\n\
+                   loop 2\n\
+                   b->c\n\
+                   end loop\n\
+                   participant d\n\
+                   participant a\n\
+                   note over e, b\n\
+                   hi\n\
+                   end note\n\
+                   @enduml\n";
+        let (tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(tokens.participant_first_use(),
+                   vec![("a".to_string(), 1),
+                        ("b".to_string(), 1),
+                        ("c".to_string(), 3),
+                        ("d".to_string(), 4),
+                        ("e".to_string(), 6)]);
+    }
+
+    #[test]
+    fn test_trailing_comments() {
+        assert_eq!(::message_parser(&b"a->b:it's done ' reply later\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Message {
+                            from: "a".to_string(),
+                            to: "b".to_string(),
+                            text: Some("it's done".to_string()),
+                            colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
+                        }));
+        assert_eq!(::participant_parser(&b"participant \"A ' B\" as ab #red ' the client\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Participant {
+                            kind: ParticipantKind::Participant,
+                            long_name: Some("\"A ' B\"".to_string()),
+                            short_name: "ab".to_string(),
+                            stereotype: None,
+                            colour: Some("red".to_string()),
+                            order: None,
+                        }));
+        assert_eq!(::message_parser(&b"a->b : hi ' don't\n"[..]),
+                   ::message_parser(&b"a->b : hi\n"[..]));
+        assert_eq!(::participant_parser(&b"participant a ' it's b\n"[..]),
+                   ::participant_parser(&b"participant a\n"[..]));
+    }
+
+    #[test]
+    fn test_trailing_comments_on_statements() {
+        let cases = [("activate a ' start\n", "activate a\n"),
+                     ("deactivate a #red ' stop\n", "deactivate a #red\n"),
+                     ("create participant a ' new\n", "create participant a\n"),
+                     ("destroy a ' gone\n", "destroy a\n"),
+                     ("autonumber 10 ' c\n", "autonumber 10\n"),
+                     ("autonumber \"<b>[0]\" ' c\n", "autonumber \"<b>[0]\"\n"),
+                     ("== d == ' c\n", "== d ==\n"),
+                     ("delay ... ' c\n", "delay ...\n"),
+                     ("opt x ' c\na->b\nend\n", "opt x\na->b\nend\n"),
+                     ("alt x ' c\na->b\nelse y ' c\nb->a\nend\n", "alt x\na->b\nelse y\nb->a\nend\n"),
+                     ("box \"b\" ' c\nparticipant a\nend box\n", "box \"b\"\nparticipant a\nend box\n")];
+
+        for &(commented, plain) in &cases {
+            let expected = ::uml_parser(plain.as_bytes());
+            assert!(matches!(expected, Done(&[], _)), "{:?}", plain);
+            assert_eq!(::uml_parser(commented.as_bytes()), expected, "{:?}", commented);
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let test_uml = r#"
+@startuml
+participant test1
+note position
+    quick test
+end note
+actor test
+
+loop 5
+    par test
+        note position
+            inside par
+        end note
+    else
+        note position
+            else clause
+        end note
+    end
+end
+activate test activate
+deactivate test deactivate
+@enduml
+"#;
+        let tokens = match ::uml_parser(test_uml.as_bytes()) {
+            Done(_, tokens) => tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(tokens.summary(),
+                   "participants: test1, test, test activate, test deactivate\n\
+                    tokens: activate 1, deactivate 1, enduml 1, loop 1, note 3, par 1, \
+                    participant 2, startuml 1\n\
+                    structure: loop(5) > par[2]\n");
+    }
+
+    #[test]
+    fn test_message_arrows() {
+        use ArrowDirection::*;
+        use ArrowHead::*;
+        use ArrowStyle::*;
+
+        // Each spelling of a message from a to b, the parsed arrow and colour,
+        // and the canonical form.
+        let arrows = [("a->b", ToRight, Solid, Filled, None, "a->b"),
+                      ("a -> b", ToRight, Solid, Filled, None, "a->b"),
+                      ("a-->b", ToRight, Dashed, Filled, None, "a-->b"),
+                      ("a->>b", ToRight, Solid, Open, None, "a->>b"),
+                      ("a-->>b", ToRight, Dashed, Open, None, "a-->>b"),
+                      ("a ->x b", ToRight, Solid, Cross, None, "a->x b"),
+                      ("a -->x b", ToRight, Dashed, Cross, None, "a-->x b"),
+                      ("a ->o b", ToRight, Solid, Circle, None, "a->o b"),
+                      ("a -->o b", ToRight, Dashed, Circle, None, "a-->o b"),
+                      ("b<-a", ToLeft, Solid, Filled, None, "b<-a"),
+                      ("b<--a", ToLeft, Dashed, Filled, None, "b<--a"),
+                      ("b<<-a", ToLeft, Solid, Open, None, "b<<-a"),
+                      ("b<<--a", ToLeft, Dashed, Open, None, "b<<--a"),
+                      ("b x<- a", ToLeft, Solid, Cross, None, "b x<-a"),
+                      ("b x<-- a", ToLeft, Dashed, Cross, None, "b x<--a"),
+                      ("b o<- a", ToLeft, Solid, Circle, None, "b o<-a"),
+                      ("b o<-- a", ToLeft, Dashed, Circle, None, "b o<--a"),
+                      ("a<->b", Both, Solid, Filled, None, "a<->b"),
+                      ("a<-->b", Both, Dashed, Filled, None, "a<-->b"),
+                      ("a<<->>b", Both, Solid, Open, None, "a<<->>b"),
+                      ("a<<-->>b", Both, Dashed, Open, None, "a<<-->>b"),
+                      ("a x<->x b", Both, Solid, Cross, None, "a x<->x b"),
+                      ("a o<-->o b", Both, Dashed, Circle, None, "a o<-->o b"),
+                      ("a-[#red]>b", ToRight, Solid, Filled, Some("red"), "a-[#red]>b"),
+                      ("a--[#red]>b", ToRight, Dashed, Filled, Some("red"), "a-[#red]->b"),
+                      ("b<[#0000FF]-a", ToLeft, Solid, Filled, Some("0000FF"), "b<[#0000FF]-a"),
+                      ("a<-[#red]->b", Both, Dashed, Filled, Some("red"), "a<-[#red]->b")];
+
+        for &(spelling, direction, style, head, colour, canonical) in arrows.iter() {
+            let expected = UMLToken::Message {
+                from: "a".to_string(),
+                to: "b".to_string(),
+                text: Some("hi".to_string()),
+                colour: colour.map(|colour| colour.to_string()),
+                arrow: Arrow {
+                    direction,
+                    style,
+                    head,
+                },
+                creates_target: false,
+            };
+
+            let input = format!("{}:hi\n", spelling);
+            assert_eq!(::message_parser(input.as_bytes()),
+                       Done(&[][..], expected.clone()),
+                       "{}",
+                       spelling);
+
+            let output = format!("{}", expected);
+            assert_eq!(output, format!("{}:hi\n", canonical));
+            assert_eq!(::message_parser(output.as_bytes()), Done(&[][..], expected));
+        }
+
+        // An `x` or `o` next to the arrow but within a name is part of the name.
+        assert_eq!(::message_parser(&b"box->ox\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Message {
+                            from: "box".to_string(),
+                            to: "ox".to_string(),
+                            text: None,
+                            colour: None,
+                            arrow: Arrow::default(),
+                            creates_target: false,
+                        }));
+    }
+
+    #[test]
+    fn test_unterminated_blocks() {
+        let openers = [("loop", "loop 5"), ("alt", "alt"), ("par", "par"), ("box", "box \"b\"")];
+
+        for &(keyword, opener) in openers.iter() {
+            let uml = format!("@startuml\na->b\n  {}\nb->a\n@enduml\n", opener);
+            let (_, errors) = ::parse_uml_collect_errors(&uml);
+
+            assert_eq!(errors[0],
+                       UMLError::UnterminatedBlock {
+                           keyword: keyword.to_string(),
+                           start_offset: 17,
+                       });
+        }
+
+        // The inner loop is closed by the outer block's `end`, leaving the
+        // outer block open.
+        let uml = "loop 2\nloop 3\nnote over a\nend\nend note\nend\n";
+        assert_eq!(::parse_uml_collect_errors(uml).1[0],
+                   UMLError::UnterminatedBlock {
+                       keyword: "loop".to_string(),
+                       start_offset: 0,
+                   });
+
+        // A closed block with bad contents is a normal parse error, reported
+        // where the contents go wrong.
+        let uml = "loop 2\nloop 4294967296\nend\nend\n";
+        assert_eq!(::parse_uml_collect_errors(uml).1,
+                   vec![UMLError::Parse {
+                            line: 2,
+                            column: 1,
+                            snippet: "loop 4294967296".to_string(),
+                        }]);
+    }
+
+    #[test]
+    fn test_startuml_name() {
+        assert_eq!(::startuml(&b"@startuml diagram1.png\n"[..]),
+                   Done(&[][..], UMLToken::StartUML { name: Some("diagram1.png".to_string()) }));
+        assert_eq!(::startuml(&b"@startuml \n"[..]),
+                   Done(&[][..], UMLToken::StartUML { name: None }));
+
+        let uml = "@startuml diagram1.png\na->b\n@enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+        assert_eq!(uml_tokens.diagram_name(), Some("diagram1.png"));
+        assert_eq!(format!("{}", uml_tokens), uml);
+
+        assert_eq!(UMLTokens::new(vec![]).diagram_name(), None);
+    }
+
+    #[test]
+    fn test_slice_between() {
+        let uml = "@startuml\na->b\ndelay start\nb->c\nloop 2\nc->d\nend\ndelay end\nd->e\n@enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+        let start = UMLToken::Delay {
+            text: "start".to_string(),
+            amount: None,
+        };
+        let end = UMLToken::Delay {
+            text: "end".to_string(),
+            amount: None,
+        };
+
+        assert_eq!(format!("{}", uml_tokens.slice_between(&start, &end)),
+                   "b->c\nloop 2\nc->d\nend loop\n");
+        assert_eq!(format!("{}", uml_tokens.slice_between(&end, &start)),
+                   "d->e\n@enduml\n");
+        assert_eq!(uml_tokens.slice_between(&UMLToken::EndUML, &start).tokens, vec![]);
+        assert_eq!(uml_tokens.slice_between(&UMLToken::Delay {
+                                                    text: "none".to_string(),
+                                                    amount: None,
+                                                }, &end)
+                       .tokens,
+                   vec![]);
+    }
+
+    #[test]
+    fn test_stacked_coloured_activations() {
+        let uml = "@startuml\n\
+                   a->b:call\n\
+                   activate b #FFBBBB\n\
+                   b->b:nested\n\
+                   activate b #DarkSalmon\n\
+                   deactivate b\n\
+                   deactivate b\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(uml_tokens.tokens[2],
+                   UMLToken::Activate {
+                       name: "b".to_string(),
+                       colour: Some("FFBBBB".to_string()),
+                   });
+        assert_eq!(uml_tokens.tokens[4],
+                   UMLToken::Activate {
+                       name: "b".to_string(),
+                       colour: Some("DarkSalmon".to_string()),
+                   });
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[test]
+    fn test_crlf_round_trip() {
+        let uml = "@startuml\r\na->b:multi\\\r\nline\r\nnote over a\r\ntext\r\nend note\r\n@enduml\r\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(LineEnding::detect(uml), LineEnding::CrLf);
+        assert_eq!(uml_tokens.to_string_with_line_ending(LineEnding::detect(uml)), uml);
+        assert_eq!(uml_tokens.to_string_with_line_ending(LineEnding::Lf),
+                   uml.replace("\r\n", "\n"));
+        assert_eq!(LineEnding::detect("@startuml\n"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn test_ref_over() {
+        let ref_token = UMLToken::Ref {
+            participants: vec!["A".to_string(), "B".to_string()],
+            text: "See the login diagram".to_string(),
+        };
+        assert_eq!(::ref_parser(&b"ref over A, B : See the login diagram\n"[..]),
+                   Done(&[][..], ref_token.clone()));
+        assert_eq!(format!("{}", ref_token), "ref over A, B : See the login diagram\n");
+
+        assert_eq!(::ref_parser(&b"ref over A\n  first\n  second\nend ref\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Ref {
+                            participants: vec!["A".to_string()],
+                            text: "first\n  second".to_string(),
+                        }));
+    }
+
+    #[test]
+    fn test_ref_display_names() {
+        let uml = "@startuml\n\
+                   participant \"Long Name\" as A\n\
+                   participant B\n\
+                   ref over A, B : handshake\n\
+                   ref over A, C : undeclared\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+        let aliases = uml_tokens.participant_aliases();
+
+        assert_eq!(uml_tokens.tokens[3].ref_display_names(&aliases),
+                   Ok(vec!["Long Name".to_string(), "B".to_string()]));
+        assert_eq!(uml_tokens.tokens[4].ref_display_names(&aliases),
+                   Err("C".to_string()));
+    }
+
+    #[test]
+    fn test_add_participant_header() {
+        let uml = "@startuml\n\
+                   c->a\n\
+                   participant b\n\
+                   loop 2\n\
+                   a->b\n\
+                   b->d\n\
+                   end\n\
+                   @enduml\n";
+        let mut uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        uml_tokens.add_participant_header();
+        let expected = "@startuml\n\
+                        participant c\n\
+                        participant a\n\
+                        participant d\n\
+                        c->a\n\
+                        participant b\n\
+                        loop 2\n\
+                        a->b\n\
+                        b->d\n\
+                        end loop\n\
+                        @enduml\n";
+        assert_eq!(format!("{}", uml_tokens), expected);
+
+        uml_tokens.add_participant_header();
+        assert_eq!(format!("{}", uml_tokens), expected);
+    }
+
+    #[test]
+    fn test_spotted_stereotype() {
+        let test_uml = "participant Foo <<(C,#ADD1B2) component>>\n";
+        let expected = UMLToken::Participant {
+            kind: ParticipantKind::Participant,
+            long_name: None,
+            short_name: "Foo".to_string(),
+            stereotype: Some(Stereotype {
+                spot: Some(('C', "ADD1B2".to_string())),
+                text: "component".to_string(),
+            }),
+            colour: None,
+            order: None,
+        };
+
+        assert_eq!(::participant_parser(test_uml.as_bytes()),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+
+        // Anything else in parentheses is just text.
+        assert_eq!(Stereotype::parse(" (not a spot) text "),
+                   Stereotype {
+                       spot: None,
+                       text: "(not a spot) text".to_string(),
+                   });
+    }
+
+    #[test]
+    fn test_undeclared_participants() {
+        let uml = "@startuml\n\
+                   participant \"Alice\" as a\n\
+                   a->bob:hello\n\
+                   note over a, carol\n\
+                   hi\n\
+                   end note\n\
+                   participant bob\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.undeclared_participants(), vec!["carol".to_string()]);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_include_once() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_once_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        // top includes left and right, which both include common.
+        std::fs::write(dir.join("common.uml"), "common->x\n").unwrap();
+        std::fs::write(dir.join("top.uml"),
+                       format!("@startuml\n!include {}\n!include {}\n@enduml\n",
+                               path("left.uml"),
+                               path("right.uml")))
+            .unwrap();
+
+        let modes = [("!include", "left->x\ncommon->x\nright->x\ncommon->x\n"),
+                     ("!include_once", "left->x\ncommon->x\nright->x\n")];
+
+        for &(directive, expected) in modes.iter() {
+            for name in &["left", "right"] {
+                std::fs::write(dir.join(format!("{}.uml", name)),
+                               format!("{}->x\n{} {}\n", name, directive, path("common.uml")))
+                    .unwrap();
+            }
+
+            let uml_tokens = ::parse_uml_file(&path("top.uml"), None).unwrap();
+            assert_eq!(format!("{}", uml_tokens),
+                       format!("@startuml\n{}@enduml\n", expected));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_token_at_offset() {
+        let uml = "@startuml\n\
+                   a->b\n\
+                   loop 2\n\
+                   \x20 alt\n\
+                   \x20   b->a\n\
+                   \x20 else\n\
+                   \x20   note over a\n\
+                   \x20     nested text\n\
+                   \x20   end note\n\
+                   \x20 end\n\
+                   end\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        let offset = uml.find("nested text").unwrap() + 3;
+        assert_eq!(uml_tokens.token_at_offset(uml, offset),
+                   Some((&UMLToken::Note {
+                            position: NotePosition::Over(vec!["a".to_string()]),
+                            text: "nested text".to_string(),
+                        },
+                        vec![2, 0, 1, 0])));
+
+        let offset = uml.find("b->a").unwrap();
+        assert_eq!(uml_tokens.token_at_offset(uml, offset).map(|(_, path)| path),
+                   Some(vec![2, 0, 0, 0]));
+
+        // The `else` line belongs to the alt, and `end` to the loop.
+        let offset = uml.find("else").unwrap();
+        assert_eq!(uml_tokens.token_at_offset(uml, offset).map(|(_, path)| path),
+                   Some(vec![2, 0]));
+        let offset = uml.find("end\n@enduml").unwrap();
+        assert_eq!(uml_tokens.token_at_offset(uml, offset).map(|(_, path)| path),
+                   Some(vec![2]));
+
+        assert_eq!(uml_tokens.token_at_offset(uml, uml.len()), None);
+    }
+
+    #[test]
+    fn test_alt_empty_first_branch() {
+        let test_uml = "alt\nelse\n X->Y\nend\n";
+        let expected = UMLToken::Alt {
+            sequences: vec![UMLTokens::new(vec![]),
+                            UMLTokens::new(vec![UMLToken::Message {
+                                                    from: "X".to_string(),
+                                                    to: "Y".to_string(),
+                                                    text: None,
+                                                    colour: None,
+                                                    arrow: Arrow::default(),
+                                                    creates_target: false,
+                                                }])],
+            labels: vec![None, None],
+        };
+
+        assert_eq!(::alt_parser(test_uml.as_bytes(), &ParseState::default()), Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), "alt\nelse\nX->Y\nend alt\n");
+
+        let printed = format!("{}", expected);
+        assert_eq!(::alt_parser(printed.as_bytes(), &ParseState::default()), Done(&[][..], expected));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_resolve_with_provenance() {
+        let dir = std::env::temp_dir()
+            .join(format!("uml_parser_provenance_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let top = dir.join("top.uml");
+        let common = dir.join("common.uml");
+
+        std::fs::write(&common, "b->c\nloop 2\nc->b\nend\n").unwrap();
+        std::fs::write(&top,
+                       format!("@startuml\na->b\n!include {}\nloop 1\n!include {}\nend\n@enduml\n",
+                               common.to_str().unwrap(),
+                               common.to_str().unwrap()))
+            .unwrap();
+
+        let uml_tokens = ::parse_uml_file(top.to_str().unwrap(), None).unwrap();
+        let resolved = uml_tokens.resolve_with_provenance(&top);
+        let files: Vec<&std::path::Path> =
+            resolved.iter().map(|(file, _)| file.as_path()).collect();
+        assert_eq!(files,
+                   vec![&top, &top, &common, &common, &common, &top, &common, &common, &common, &top]);
+
+        // The loop in top.uml has the included tokens spliced into it, and is
+        // followed by each of them attributed to common.uml.
+        assert_eq!(format!("{}", resolved[5].1),
+                   "loop 1\nb->c\nloop 2\nc->b\nend loop\nend loop\n");
+        assert_eq!(format!("{}", resolved[8].1), "c->b\n");
+        assert!(!resolved.iter().any(|(_, token)| matches!(*token, UMLToken::Include { .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mermaid_multiline_labels() {
+        let test_uml = "a->b:first \\\n  second\nnote over a\nline one\nline two\nend note\n";
+        let uml_tokens = match ::uml_parser(test_uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+        let mermaid = "sequenceDiagram\n    \
+                       a->>b: first<br/>second\n    \
+                       Note over a: line one<br/>line two\n";
+
+        assert_eq!(uml_tokens.to_mermaid(), mermaid);
+        assert_eq!(::from_mermaid(mermaid), Ok(uml_tokens));
+    }
+
+    #[test]
+    fn test_kind_name() {
+        let empty = UMLTokens::new(vec![]);
+        let name = || "a".to_string();
+        let tokens = vec![(UMLToken::StartUML { name: None }, "startuml"),
+                          (UMLToken::EndUML, "enduml"),
+                          (UMLToken::Note {
+                               position: NotePosition::Left(None),
+                               text: name(),
+                           },
+                           "note"),
+                          (UMLToken::SplitNote { notes: vec![] }, "split_note"),
+                          (UMLToken::Parallel {
+                               sequences: vec![],
+                               labels: vec![],
+                           },
+                           "par"),
+                          (UMLToken::Alt {
+                               sequences: vec![],
+                               labels: vec![],
+                           },
+                           "alt"),
+                          (UMLToken::Message {
+                               from: name(),
+                               to: name(),
+                               text: None,
+                               colour: None,
+                               arrow: Arrow::default(),
+                               creates_target: false,
+                           },
+                           "message"),
+                          (UMLToken::Participant {
+                               kind: ParticipantKind::Participant,
+                               long_name: None,
+                               short_name: name(),
+                               stereotype: None,
+                               colour: None,
+                               order: None,
+                           },
+                           "participant"),
+                          (UMLToken::Activate {
+                               name: name(),
+                               colour: None,
+                           },
+                           "activate"),
+                          (UMLToken::Deactivate {
+                               name: name(),
+                               colour: None,
+                           },
+                           "deactivate"),
+                          (UMLToken::Loop {
+                               sequence: empty.clone(),
+                               count: LoopCount::Times(1),
+                           },
+                           "loop"),
+                          (UMLToken::Include {
+                               file: name(),
+                               sequence: empty.clone(),
+                               once: false,
+                           },
+                           "include"),
+                          (UMLToken::Box {
+                               name: name(),
+                               colour: None,
+                               sequence: empty.clone(),
+                           },
+                           "box"),
+                          (UMLToken::Create { name: name() }, "create"),
+                          (UMLToken::Destroy { name: name() }, "destroy"),
+                          (UMLToken::Delay {
+                               text: name(),
+                               amount: None,
+                           },
+                           "delay"),
+                          (UMLToken::Divider { text: name() }, "divider"),
+                          (UMLToken::Opt {
+                               label: None,
+                               sequence: empty.clone(),
+                           },
+                           "opt"),
+                          (UMLToken::Group {
+                               label: name(),
+                               sequence: empty.clone(),
+                           },
+                           "group"),
+                          (UMLToken::Title { text: name() }, "title"),
+                          (UMLToken::Comment {
+                               text: name(),
+                               block: false,
+                           },
+                           "comment"),
+                          (UMLToken::Autonumber {
+                               start: None,
+                               stop: false,
+                               inc: None,
+                               format: None,
+                           },
+                           "autonumber"),
+                          (UMLToken::Ref {
+                               participants: vec![],
+                               text: name(),
+                           },
+                           "ref")];
+
+        for &(ref token, kind_name) in &tokens {
+            assert_eq!(token.kind_name(), kind_name);
+        }
+    }
+
+    #[test]
+    fn test_participant_flexible_as() {
+        let expected = |long_name: &str, short_name: &str| {
+            Done(&[][..],
+                 UMLToken::Participant {
+                     kind: ParticipantKind::Participant,
+                     long_name: Some(long_name.to_string()),
+                     short_name: short_name.to_string(),
+                     stereotype: None,
+                     colour: None,
+                     order: None,
+                 })
+        };
+
+        assert_eq!(::participant_parser(&b"participant Foo\tas\tBar\n"[..]),
+                   expected("Foo", "Bar"));
+        assert_eq!(::participant_parser(&b"participant\tFoo \t as  Bar\n"[..]),
+                   expected("Foo", "Bar"));
+        assert_eq!(::participant_parser(&b"participant \"Foo\"as Bar\n"[..]),
+                   expected("\"Foo\"", "Bar"));
+        assert_eq!(::participant_parser(&b"participant Bar as\"Foo\"\n"[..]),
+                   expected("Bar", "\"Foo\""));
+    }
+
+    #[test]
+    fn test_participant_predicates() {
+        let uml = "@startuml\n\
+                   participant declared\n\
+                   loop 2\n\
+                   participant both\n\
+                   both->referenced\n\
+                   end\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert!(uml_tokens.contains_participant("declared"));
+        assert!(!uml_tokens.references_participant("declared"));
+
+        assert!(!uml_tokens.contains_participant("referenced"));
+        assert!(uml_tokens.references_participant("referenced"));
+
+        assert!(uml_tokens.contains_participant("both"));
+        assert!(uml_tokens.references_participant("both"));
+
+        assert!(!uml_tokens.contains_participant("absent"));
+        assert!(!uml_tokens.references_participant("absent"));
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        use LifecycleEvent::*;
+
+        let uml = "@startuml\n\
+                   a->b\n\
+                   create c\n\
+                   a->c\n\
+                   loop 2\n\
+                   activate c\n\
+                   c->a\n\
+                   deactivate c\n\
+                   end loop\n\
+                   destroy c\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.lifecycle("c"),
+                   vec![Created(2), Activated(5), Deactivated(7), Destroyed(8)]);
+        assert_eq!(uml_tokens.lifecycle("a"), vec![]);
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[test]
+    fn test_strict_note_position() {
+        let uml = "@startuml\n\
+                   note left of a\n\
+                   valid\n\
+                   end note\n\
+                   loop 2\n\
+                   note leftx\n\
+                   misspelled\n\
+                   end note\n\
+                   end loop\n\
+                   @enduml\n";
+
+        let (lenient, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", lenient), uml);
+
+        let cfg = ParserConfig {
+            strict: true,
+            ..ParserConfig::default()
+        };
+        let (strict, errors, _) = ::parse_uml_with_config(uml, &cfg);
+        assert_eq!(errors,
+                   vec![UMLError::Parse {
+                            line: 6,
+                            column: 1,
+                            snippet: "note leftx".to_string(),
+                        }]);
+        assert_eq!(format!("{}", strict),
+                   "@startuml\nnote left of a\nvalid\nend note\n@enduml\n");
+    }
+
+    #[test]
+    fn test_preprocessor_conditionals() {
+        let uml = "@startuml\n\
+                   !$target = \"mermaid\"\n\
+                   !if ($target == \"mermaid\")\n\
+                   a->b:mermaid\n\
+                   !if $verbose\n\
+                   a->b:verbose\n\
+                   !endif\n\
+                   !else\n\
+                   a->b:other\n\
+                   !endif\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", uml_tokens),
+                   "@startuml\na->b:mermaid\n@enduml\n");
+
+        let uml = uml.replacen("mermaid", "dot", 1);
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(&uml);
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", uml_tokens), "@startuml\na->b:other\n@enduml\n");
+    }
+
+    #[test]
+    fn test_compact_loop() {
+        let uml = "@startuml\n\
+                   loop 5: a->b: ping\n\
+                   @enduml\n";
+
+        let cfg = ParserConfig {
+            compact_loops: true,
+            ..ParserConfig::default()
+        };
+        let (uml_tokens, errors, _) = ::parse_uml_with_config(uml, &cfg);
+        assert!(errors.is_empty());
+        assert_eq!(uml_tokens.tokens[1],
+                   UMLToken::Loop {
+                       sequence: UMLTokens::new(vec![UMLToken::Message {
+                                                         from: "a".to_string(),
+                                                         to: "b".to_string(),
+                                                         text: Some("ping".to_string()),
+                                                         colour: None,
+                                                         arrow: Arrow::default(),
+                                                         creates_target: false,
+                                                     }]),
+                       count: LoopCount::Times(5),
+                   });
+        assert_eq!(format!("{}", uml_tokens),
+                   "@startuml\nloop 5\na->b:ping\nend loop\n@enduml\n");
+
+        let (_, errors) = ::parse_uml_collect_errors(uml);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_empty_blocks() {
+        let uml = "@startuml\n\
+                   loop 2\n\
+                   note over a\n\
+                   only a note\n\
+                   end note\n\
+                   end loop\n\
+                   alt\n\
+                   note over a\n\
+                   another note\n\
+                   end note\n\
+                   else\n\
+                   a->b\n\
+                   end\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        uml_tokens.walk_mut(&mut |token| match *token {
+            UMLToken::Loop { ref mut sequence, .. } => {
+                sequence.tokens.retain(|token| token.kind_name() != "note")
+            }
+            UMLToken::Alt { ref mut sequences, .. } => {
+                for sequence in sequences {
+                    sequence.tokens.retain(|token| token.kind_name() != "note");
+                }
+            }
+            _ => {}
+        });
+
+        assert_eq!(format!("{}", uml_tokens.prune_empty_blocks()),
+                   "@startuml\nalt\na->b\nend alt\n@enduml\n");
+    }
+
+    #[test]
+    fn test_coloured_dashed_reply() {
+        let reply = |head| UMLToken::Message {
+            from: "B".to_string(),
+            to: "A".to_string(),
+            text: Some("done".to_string()),
+            colour: Some("gray".to_string()),
+            arrow: Arrow {
+                direction: ArrowDirection::ToRight,
+                style: ArrowStyle::Dashed,
+                head,
+            },
+            creates_target: false,
+        };
+
+        let cases = [("B -->[#gray] A : done\n", ArrowHead::Filled, "B-[#gray]->A:done\n"),
+                     ("B -[#gray]->> A : done\n", ArrowHead::Open, "B-[#gray]->>A:done\n"),
+                     ("B -->>[#gray] A : done\n", ArrowHead::Open, "B-[#gray]->>A:done\n")];
+
+        for &(uml, head, canonical) in &cases {
+            let result = ::message_parser(uml.as_bytes());
+            assert_eq!(result, Done(&[][..], reply(head)), "{}", uml);
+
+            let printed = format!("{}", reply(head));
+            assert_eq!(printed, canonical);
+            assert_eq!(::message_parser(printed.as_bytes()), Done(&[][..], reply(head)));
+        }
+    }
+
+    #[test]
+    fn test_statements() {
+        let uml = "@startuml\n\
+                   a->b\n\
+                   loop 2\n\
+                   b->a\n\
+                   end loop\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let kinds: Vec<&str> = uml_tokens.statements().iter().map(|token| token.kind_name()).collect();
+        assert_eq!(kinds, vec!["startuml", "message", "loop", "enduml"]);
+
+        if let UMLToken::Message { ref mut text, .. } = uml_tokens.statements_mut()[1] {
+            *text = Some("hello".to_string());
+        }
+        assert_eq!(format!("{}", uml_tokens),
+                   "@startuml\na->b:hello\nloop 2\nb->a\nend loop\n@enduml\n");
+    }
+
+    #[test]
+    fn test_message_creates_target() {
+        let expected = UMLToken::Message {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            text: Some("new".to_string()),
+            colour: None,
+            arrow: Arrow::default(),
+            creates_target: true,
+        };
+
+        let result = ::message_parser("A -> B **: new\n".as_bytes());
+        assert_eq!(result, Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), "A->B **:new\n");
+
+        let result = ::message_parser("B ** <- A : new\n".as_bytes());
+        if let Done(_, UMLToken::Message { ref to, creates_target, .. }) = result {
+            assert_eq!(to, "B");
+            assert!(creates_target);
+        } else {
+            panic!("{:?}", result);
+        }
+
+        let (uml_tokens, _) = ::parse_uml_collect_errors("A->B **:new\ndestroy B\n");
+        assert_eq!(uml_tokens.lifecycle("B"),
+                   vec![LifecycleEvent::Created(0), LifecycleEvent::Destroyed(1)]);
+    }
+
+    #[test]
+    fn test_activation_leaks_fragment() {
+        let uml = "@startuml\n\
+                   loop 2\n\
+                   activate a\n\
+                   a->b\n\
+                   activate b\n\
+                   deactivate b\n\
+                   end loop\n\
+                   deactivate a\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.validate(&ParserConfig::default()), vec![]);
+
+        let cfg = ParserConfig {
+            warn_activation_leaks: true,
+            ..ParserConfig::default()
+        };
+        assert_eq!(uml_tokens.validate(&cfg),
+                   vec![UMLWarning::ActivationLeaksFragment {
+                            participant: "a".to_string(),
+                            fragment: "loop".to_string(),
+                        }]);
+    }
+
+    #[test]
+    fn test_retain_participants() {
+        let uml = "@startuml\n\
+                   participant a\n\
+                   participant b\n\
+                   participant c\n\
+                   participant d\n\
+                   a->b:kept\n\
+                   b->c:one end kept\n\
+                   loop 2\n\
+                   c->d:dropped\n\
+                   end loop\n\
+                   note over a, b\n\
+                   kept note\n\
+                   end note\n\
+                   note over b, d\n\
+                   dropped note\n\
+                   end note\n\
+                   activate b\n\
+                   activate c\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(format!("{}", uml_tokens.retain_participants(&["a", "b"])),
+                   "@startuml\n\
+                    participant a\n\
+                    participant b\n\
+                    a->b:kept\n\
+                    note over a, b\n\
+                    kept note\n\
+                    end note\n\
+                    activate b\n\
+                    @enduml\n");
+    }
+
+    #[test]
+    fn test_autonumber_format() {
+        let cases = [("autonumber \"<b>[00]</b>\"\n", None, "<b>[00]</b>"),
+                     ("autonumber 10 \"<font color=red> [000] \"\n", Some(10), "<font color=red> [000] ")];
+
+        for &(uml, start, format) in &cases {
+            let result = ::autonumber_parser(uml.as_bytes());
+            assert_eq!(result,
+                       Done(&[][..],
+                            UMLToken::Autonumber {
+                                start,
+                                stop: false,
+                                inc: None,
+                                format: Some(format.to_string()),
+                            }));
+
+            let (_, token) = result.unwrap();
+            assert_eq!(format!("{}", token), uml);
+        }
+
+        assert!(::autonumber_parser("autonumber \"<b>[00]\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_without_include_bodies() {
+        let include = |file: &str, tokens| UMLToken::Include {
+            file: file.to_string(),
+            sequence: UMLTokens::new(tokens),
+            once: false,
+        };
+        let message = UMLToken::Message {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            text: None,
+            colour: None,
+            arrow: Arrow::default(),
+            creates_target: false,
+        };
+
+        let uml_tokens = UMLTokens::new(vec![include("outer.uml",
+                                                     vec![message.clone(),
+                                                          include("inner.uml", vec![message.clone()])]),
+                                             UMLToken::Loop {
+                                                 sequence: UMLTokens::new(vec![include("loop.uml",
+                                                                                       vec![message])]),
+                                                 count: LoopCount::Times(2),
+                                             }]);
+
+        assert_eq!(uml_tokens.without_include_bodies(),
+                   UMLTokens::new(vec![include("outer.uml", vec![]),
+                                       UMLToken::Loop {
+                                           sequence: UMLTokens::new(vec![include("loop.uml", vec![])]),
+                                           count: LoopCount::Times(2),
+                                       }]));
+    }
+
+    #[test]
+    fn test_skip_preamble() {
+        let uml = "#!/usr/bin/env plantuml\n\
+                   ---\n\
+                   title: diagram\n\
+                   ---\n\
+                   @startuml\n\
+                   a->b\n\
+                   @enduml\n";
+
+        assert!(!::parse_uml_collect_errors(uml).1.is_empty());
+
+        let cfg = ParserConfig {
+            skip_preamble_until_startuml: true,
+            ..ParserConfig::default()
+        };
+        let (uml_tokens, errors, _) = ::parse_uml_with_config(uml, &cfg);
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", uml_tokens), "@startuml\na->b\n@enduml\n");
+
+        assert_eq!(::split_preamble(uml),
+                   ("#!/usr/bin/env plantuml\n---\ntitle: diagram\n---\n",
+                    "@startuml\na->b\n@enduml\n"));
+        assert_eq!(::split_preamble("a->b\n"), ("", "a->b\n"));
+    }
+
+    #[test]
+    fn test_semantically_eq() {
+        let message = |uml: &str| ::message_parser(uml.as_bytes()).unwrap().1;
+
+        let plain = message("a->b:hello\n");
+        let coloured = message("a-[#red]->b:hello\n");
+        assert!(plain != coloured);
+        assert!(plain.semantically_eq(&coloured));
+        assert!(plain.semantically_eq(&message("b<--a:hello\n")));
+
+        assert!(!plain.semantically_eq(&message("a->b:goodbye\n")));
+        assert!(!plain.semantically_eq(&message("b->a:hello\n")));
+        assert!(!plain.semantically_eq(&UMLToken::EndUML));
+        assert!(UMLToken::EndUML.semantically_eq(&UMLToken::EndUML));
+    }
+
+    #[test]
+    fn test_divider_in_alt() {
+        let uml = "@startuml\n\
+                   == setup ==\n\
+                   alt\n\
+                   == only ==\n\
+                   else\n\
+                   == end of the road ==\n\
+                   a->b\n\
+                   end alt\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let divider = |text: &str| UMLToken::Divider { text: text.to_string() };
+        assert_eq!(uml_tokens.tokens[1], divider("setup"));
+        match uml_tokens.tokens[2] {
+            UMLToken::Alt { ref sequences, .. } => {
+                assert_eq!(sequences[0].tokens, vec![divider("only")]);
+                assert_eq!(sequences[1].tokens[0], divider("end of the road"));
+            }
+            ref token => panic!("{:?}", token),
+        }
+        assert_eq!(format!("{}", uml_tokens), uml);
+
+        assert!(::divider_parser("== unterminated\n".as_bytes()).is_err());
+        assert!(::divider_parser("====\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parser_context_warnings() {
+        let uml = "@startuml\n\
+                   note left of a\n\
+                   valid\n\
+                   end note\n\
+                   note leftx\n\
+                   misspelled\n\
+                   end note\n\
+                   @enduml\n";
+
+        let (uml_tokens, errors, context) = ::parse_uml_with_config(uml, &ParserConfig::default());
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", uml_tokens), uml);
+        assert_eq!(context.warnings,
+                   vec![UMLWarning::UnknownNotePosition {
+                            line: 5,
+                            position: "leftx".to_string(),
+                        }]);
+
+        let cfg = ParserConfig {
+            strict: true,
+            ..ParserConfig::default()
+        };
+        let (_, errors, context) = ::parse_uml_with_config(uml, &cfg);
+        assert_eq!(errors.len(), 1);
+        assert!(context.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_message_notes() {
+        let uml = "@startuml\n\
+                   a->b:first\n\
+                   note left\n\
+                   one\n\
+                   end note\n\
+                   note right\n\
+                   two\n\
+                   end note\n\
+                   note over a\n\
+                   not attached\n\
+                   end note\n\
+                   loop 2\n\
+                   b->a:second\n\
+                   end loop\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let note = |position: &str, text: &str| UMLToken::Note {
+            position: NotePosition::parse(position),
+            text: text.to_string(),
+        };
+        let message_notes: Vec<(Option<String>, Vec<UMLToken>)> = uml_tokens.message_notes()
+            .into_iter()
+            .map(|(message, notes)| match *message {
+                UMLToken::Message { ref text, .. } => {
+                    (text.clone(), notes.into_iter().cloned().collect())
+                }
+                _ => panic!("{:?}", message),
+            })
+            .collect();
+
+        assert_eq!(message_notes,
+                   vec![(Some("first".to_string()), vec![note("left", "one"), note("right", "two")]),
+                        (Some("second".to_string()), vec![])]);
+    }
+
+    #[test]
+    fn test_include_print_modes() {
+        let message = ::message_parser(b"a->b\n").unwrap().1;
+        let uml_tokens = UMLTokens::new(vec![UMLToken::StartUML { name: None },
+                                             UMLToken::Include {
+                                                 file: "common.uml".to_string(),
+                                                 sequence: UMLTokens::new(vec![message.clone()]),
+                                                 once: false,
+                                             },
+                                             UMLToken::Loop {
+                                                 sequence: UMLTokens::new(vec![UMLToken::Include {
+                                                     file: "my file.uml".to_string(),
+                                                     sequence: UMLTokens::new(vec![message]),
+                                                     once: true,
+                                                 }]),
+                                                 count: LoopCount::Times(2),
+                                             },
+                                             UMLToken::EndUML]);
+
+        let print = |uml_tokens: &UMLTokens, includes| {
+            uml_tokens.to_string_with_options(&PrintOptions {
+                includes,
+                ..PrintOptions::default()
+            })
+        };
+
+        assert_eq!(print(&uml_tokens, IncludeMode::Inline), format!("{}", uml_tokens));
+        assert_eq!(print(&uml_tokens, IncludeMode::Inline),
+                   "@startuml\na->b\nloop 2\na->b\nend loop\n@enduml\n");
+        assert_eq!(print(&uml_tokens, IncludeMode::Directive),
+                   "@startuml\n\
+                    !include common.uml\n\
+                    loop 2\n\
+                    !include_once \"my file.uml\"\n\
+                    end loop\n\
+                    @enduml\n");
+        assert_eq!(print(&uml_tokens, IncludeMode::Both),
+                   "@startuml\n\
+                    ' !include common.uml\n\
+                    a->b\n\
+                    loop 2\n\
+                    ' !include_once \"my file.uml\"\n\
+                    a->b\n\
+                    end loop\n\
+                    @enduml\n");
+
+        // Without the `fs` feature the directives parse back to the same
+        // includes, with empty bodies.
+        if cfg!(not(feature = "fs")) {
+            let directives = print(&uml_tokens, IncludeMode::Directive);
+            let (reparsed, errors) = ::parse_uml_collect_errors(&directives);
+            assert!(errors.is_empty());
+            assert_eq!(print(&reparsed, IncludeMode::Directive), directives);
+        }
+    }
+
+    #[test]
+    fn test_unicode_names() {
+        let uml = "@startuml\n\
+                   participant \"🚀 Launcher\" as 🚀 <<服务>> #red\n\
+                   participant 客户端\n\
+                   🚀->客户端:こんにちは 👋 ' a comment\n\
+                   客户端 x<-- 🚀 :Ünïcödé: ✓\n\
+                   note over 🚀, 客户端\n\
+                   📝 ノート\n\
+                   end note\n\
+                   == 第二部 ==\n\
+                   activate 🚀 #FF0000\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.tokens[1],
+                   UMLToken::Participant {
+                       kind: ParticipantKind::Participant,
+                       long_name: Some("\"🚀 Launcher\"".to_string()),
+                       short_name: "🚀".to_string(),
+                       stereotype: Some(Stereotype {
+                           spot: None,
+                           text: "服务".to_string(),
+                       }),
+                       colour: Some("red".to_string()),
+                       order: None,
+                   });
+        assert_eq!(uml_tokens.tokens[3],
+                   UMLToken::Message {
+                       from: "🚀".to_string(),
+                       to: "客户端".to_string(),
+                       text: Some("こんにちは 👋".to_string()),
+                       colour: None,
+                       arrow: Arrow::default(),
+                       creates_target: false,
+                   });
+        match uml_tokens.tokens[4] {
+            UMLToken::Message {
+                ref from,
+                ref to,
+                ref text,
+                ..
+            } => {
+                assert_eq!((from.as_str(), to.as_str()), ("🚀", "客户端"));
+                assert_eq!(text.as_deref(), Some("Ünïcödé: ✓"));
+            }
+            ref token => panic!("{:?}", token),
+        }
+        assert_eq!(uml_tokens.undeclared_participants(), Vec::<String>::new());
+
+        let printed = format!("{}", uml_tokens);
+        assert_eq!(::parse_uml_collect_errors(&printed), (uml_tokens, vec![]));
+    }
+
+    #[test]
+    fn test_validate_all() {
+        let uml = "@startuml\n\
+                   participant a\n\
+                   participant b\n\
+                   participant d\n\
+                   a->b\n\
+                   deactivate a\n\
+                   activate b\n\
+                   b->c\n\
+                   destroy c\n\
+                   a->c\n\
+                   c->a\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        uml_tokens.tokens.insert(11,
+                                 UMLToken::Loop {
+                                     sequence: UMLTokens::new(vec![]),
+                                     count: LoopCount::Times(2),
+                                 });
+
+        assert_eq!(uml_tokens.validate_all(&ValidationConfig::default()),
+                   vec![UMLWarning::UnusedParticipant { participant: "d".to_string() },
+                        UMLWarning::DeactivateWithoutActivate { participant: "a".to_string() },
+                        UMLWarning::ActivationNotEnded { participant: "b".to_string() },
+                        UMLWarning::UndeclaredParticipant { participant: "c".to_string() },
+                        UMLWarning::MessageToDestroyed { participant: "c".to_string() },
+                        UMLWarning::EmptyBlock { kind: "loop".to_string() }]);
+
+        let cfg = ValidationConfig {
+            activation_balance: false,
+            unused_participants: false,
+            ..ValidationConfig::default()
+        };
+        assert_eq!(uml_tokens.validate_all(&cfg),
+                   vec![UMLWarning::UndeclaredParticipant { participant: "c".to_string() },
+                        UMLWarning::MessageToDestroyed { participant: "c".to_string() },
+                        UMLWarning::EmptyBlock { kind: "loop".to_string() }]);
+    }
+
+    #[test]
+    fn test_nested_traversals() {
+        let uml = "@startuml\n\
+                   a->b:outer\n\
+                   loop 2\n\
+                   alt\n\
+                   a->b:inner\n\
+                   else\n\
+                   note over a\n\
+                   text\n\
+                   end note\n\
+                   end alt\n\
+                   end loop\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let texts = |uml_tokens: &UMLTokens| -> Vec<String> {
+            uml_tokens.messages()
+                .into_iter()
+                .map(|message| match *message {
+                    UMLToken::Message { ref text, .. } => text.clone().unwrap(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(texts(&uml_tokens), vec!["outer", "inner"]);
+        assert_eq!(uml_tokens.find_all(|token| token.kind_name() == "note").len(), 1);
+        assert_eq!(uml_tokens.token_count(), 7);
+        assert_eq!(uml_tokens.max_depth(), 2);
+        assert_eq!(UMLTokens::new(vec![UMLToken::EndUML]).max_depth(), 0);
+
+        uml_tokens.map_messages(|text| text.to_uppercase());
+        assert_eq!(texts(&uml_tokens), vec!["OUTER", "INNER"]);
+    }
+
+    #[test]
+    fn test_parse_uml_str() {
+        let uml = "@startuml\r\n\
+                   a->b:hello\r\n\
+                   loop 2\r\n\
+                   b->a\r\n\
+                   end loop\r\n\
+                   @enduml\r\n";
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(::parse_uml_str(uml),
+                   Ok(::parse_uml_collect_errors(uml).0));
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+
+        assert_eq!(::parse_uml_str("@startuml\na->b\nloop 4294967296\nend loop\n@enduml\n"),
+                   Err(UMLError::Parse {
+                       line: 3,
+                       column: 1,
+                       snippet: "loop 4294967296".to_string(),
+                   }));
+        assert!(::parse_uml_str("").is_err());
+    }
+
+    #[test]
+    fn test_branch_labels() {
+        let uml = "@startuml\n\
+                   alt user is logged in ' a comment\n\
+                   a->b:welcome\n\
+                   else unknown user\n\
+                   a->b:login\n\
+                   else\n\
+                   a->b:error\n\
+                   end alt\n\
+                   par\n\
+                   a->b\n\
+                   else second\n\
+                   b->a\n\
+                   end par\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        match uml_tokens.tokens[1] {
+            UMLToken::Alt {
+                ref sequences,
+                ref labels,
+            } => {
+                assert_eq!(sequences.len(), 3);
+                assert_eq!(*labels,
+                           vec![Some("user is logged in".to_string()),
+                                Some("unknown user".to_string()),
+                                None]);
+            }
+            ref token => panic!("{:?}", token),
+        }
+        match uml_tokens.tokens[2] {
+            UMLToken::Parallel { ref labels, .. } => {
+                assert_eq!(*labels, vec![None, Some("second".to_string())])
+            }
+            ref token => panic!("{:?}", token),
+        }
+
+        let printed = format!("{}", uml_tokens);
+        assert!(printed.contains("alt user is logged in\n"));
+        assert!(printed.contains("else\na->b:error\n"));
+        assert!(printed.contains("par\na->b\nelse second\n"));
+        assert_eq!(::parse_uml_collect_errors(&printed), (uml_tokens.clone(), vec![]));
+
+        let mermaid = uml_tokens.to_mermaid();
+        assert!(mermaid.contains("    alt user is logged in\n"));
+        assert!(mermaid.contains("    and second\n"));
+        assert_eq!(::from_mermaid(&mermaid).unwrap().tokens[0], uml_tokens.tokens[1]);
+
+        let mut pruned = uml_tokens;
+        if let UMLToken::Parallel { ref mut sequences, .. } = pruned.tokens[2] {
+            sequences[0].tokens.clear();
+        }
+        match pruned.prune_empty_blocks().tokens[2] {
+            UMLToken::Parallel { ref labels, .. } => {
+                assert_eq!(*labels, vec![Some("second".to_string())])
+            }
+            ref token => panic!("{:?}", token),
+        }
+    }
+
+    #[test]
+    fn test_dashed_return_arrows() {
+        let uml = "@startuml\n\
+                   Alice -> Bob : request\n\
+                   Bob --> Alice : reply -> done\n\
+                   Alice <-- Bob\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let arrows: Vec<(&str, &str, Option<&str>, Arrow)> = uml_tokens.messages()
+            .into_iter()
+            .map(|message| match *message {
+                UMLToken::Message {
+                    ref from,
+                    ref to,
+                    ref text,
+                    arrow,
+                    ..
+                } => (from.as_str(), to.as_str(), text.as_deref(), arrow),
+                _ => unreachable!(),
+            })
+            .collect();
+        let dashed = |direction| {
+            Arrow {
+                direction,
+                style: ArrowStyle::Dashed,
+                head: ArrowHead::Filled,
+            }
+        };
+        assert_eq!(arrows,
+                   vec![("Alice", "Bob", Some("request"), Arrow::default()),
+                        ("Bob", "Alice", Some("reply -> done"), dashed(ArrowDirection::ToRight)),
+                        ("Bob", "Alice", None, dashed(ArrowDirection::ToLeft))]);
+
+        let printed = format!("{}", uml_tokens);
+        assert!(printed.contains("Bob-->Alice:reply -> done\nAlice<--Bob\n"));
+        assert_eq!(::parse_uml_collect_errors(&printed), (uml_tokens, vec![]));
+    }
+
+    #[test]
+    fn test_inline_message_colour() {
+        let message = |text: &str, colour: &str, direction| {
+            UMLToken::Message {
+                from: "A".to_string(),
+                to: "B".to_string(),
+                text: Some(text.to_string()),
+                colour: Some(colour.to_string()),
+                arrow: Arrow {
+                    direction,
+                    ..Arrow::default()
+                },
+                creates_target: false,
+            }
+        };
+
+        let cases = [("A-[#red]>B:Hi\n", message("Hi", "red", ArrowDirection::ToRight)),
+                     ("B<[#00FF00]-A:at 10:30\n",
+                      message("at 10:30", "00FF00", ArrowDirection::ToLeft)),
+                     ("A-[#DarkBlue]>B:a:b\n", message("a:b", "DarkBlue", ArrowDirection::ToRight))];
+
+        for &(input, ref expected) in cases.iter() {
+            assert_eq!(::message_parser(input.as_bytes()),
+                       Done(&[][..], expected.clone()),
+                       "{}",
+                       input);
+            assert_eq!(format!("{}", expected), input);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_tokens_json() {
+        let uml = "@startuml\n\
+                   participant a <<(C,#ADD1B2) service>> #red\n\
+                   a-[#blue]->b:hello\n\
+                   loop 2\n\
+                   par first\n\
+                   note over a\n\
+                   text\n\
+                   end note\n\
+                   else\n\
+                   a->b\n\
+                   end par\n\
+                   alt\n\
+                   b->a\n\
+                   end alt\n\
+                   end loop\n\
+                   box \"Group\"\n\
+                   participant c\n\
+                   end box\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        uml_tokens.tokens.insert(1,
+                                 UMLToken::Include {
+                                     file: "common.uml".to_string(),
+                                     sequence: UMLTokens::new(vec![UMLToken::Destroy {
+                                                                       name: "c".to_string(),
+                                                                   }]),
+                                     once: true,
+                                 });
+
+        let json = ::serde_json::to_string(&uml_tokens).unwrap();
+        assert!(json.starts_with(r#"{"tokens":[{"type":"StartUML","name":null},"#));
+        assert_eq!(::serde_json::from_str::<UMLTokens>(&json).unwrap(), uml_tokens);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_file_threads() {
+        let cwd = std::env::current_dir().unwrap();
+        let threads: Vec<_> = ["left", "right"]
+            .iter()
+            .map(|&name| {
+                let dir = std::env::temp_dir()
+                    .join(format!("uml_parser_threads_{}_{}", name, std::process::id()));
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join("top.uml"), "@startuml\n!include inc.uml\n@enduml\n")
+                    .unwrap();
+                std::fs::write(dir.join("inc.uml"), format!("{}->x\n", name)).unwrap();
+
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let uml_tokens = ::parse_uml_file("top.uml", Some(&dir)).unwrap();
+                        assert_eq!(uml_tokens.messages().len(), 1);
+                        match *uml_tokens.messages()[0] {
+                            UMLToken::Message { ref from, .. } => assert_eq!(from, name),
+                            _ => unreachable!(),
+                        }
+                    }
+                    std::fs::remove_dir_all(&dir).unwrap();
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn test_comments() {
+        let uml = "@startuml\n\
+                   ' a comment\n\
+                   loop 2\n\
+                   a->b:first\n\
+                   \x20   'between messages\n\
+                   b->a:second\n\
+                   end loop\n\
+                   /' a block\n\
+                   comment with a->b '/\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.tokens[1],
+                   UMLToken::Comment {
+                       text: " a comment".to_string(),
+                       block: false,
+                   });
+        match uml_tokens.tokens[2] {
+            UMLToken::Loop { ref sequence, .. } => {
+                assert_eq!(sequence.tokens.len(), 3);
+                assert_eq!(sequence.tokens[1],
+                           UMLToken::Comment {
+                               text: "between messages".to_string(),
+                               block: false,
+                           });
+            }
+            ref token => panic!("{:?}", token),
+        }
+        assert_eq!(uml_tokens.tokens[3],
+                   UMLToken::Comment {
+                       text: " a block\ncomment with a->b ".to_string(),
+                       block: true,
+                   });
+        assert_eq!(uml_tokens.messages().len(), 2);
+
+        let printed = format!("{}", uml_tokens);
+        assert!(printed.starts_with("@startuml\n' a comment\nloop 2\n"));
+        assert!(printed.contains("/' a block\ncomment with a->b '/\n"));
+        assert_eq!(::parse_uml_collect_errors(&printed), (uml_tokens, vec![]));
+    }
+
+    #[test]
+    fn test_title_parser() {
+        let test_uml = "title Call Flow\n";
+        let expected = UMLToken::Title { text: "Call Flow".to_string() };
+
+        assert_eq!(::title_parser(test_uml.as_bytes()), Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+        assert_eq!(::title_parser(&b"  title   Call Flow  ' draft\n"[..]),
+                   Done(&[][..], expected.clone()));
+
+        let uml = "@startuml\ntitle Call Flow\na->b\n@enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        assert_eq!(uml_tokens.tokens[1], expected);
+        assert!(uml_tokens.to_mermaid().contains("    title Call Flow\n"));
+    }
+
+    #[test]
+    fn test_autonumber_parser() {
+        let cases = [("autonumber\n", None, false),
+                     ("autonumber 10\n", Some(10), false),
+                     ("autonumber stop\n", None, true)];
+
+        for &(uml, start, stop) in &cases {
+            let result = ::autonumber_parser(uml.as_bytes());
+            assert_eq!(result,
+                       Done(&[][..],
+                            UMLToken::Autonumber {
+                                start,
+                                stop,
+                                inc: None,
+                                format: None,
+                            }));
+
+            let (_, token) = result.unwrap();
+            assert_eq!(format!("{}", token), uml);
+        }
+
+        let uml = "@startuml\nautonumber 10\na->b\nautonumber stop\nb->a\n@enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[test]
+    fn test_opt_parser() {
+        let test_uml = "opt cache miss\na->db:fetch\nend opt\n";
+        let expected = UMLToken::Opt {
+            label: Some("cache miss".to_string()),
+            sequence: UMLTokens::new(vec![UMLToken::Message {
+                                              from: "a".to_string(),
+                                              to: "db".to_string(),
+                                              text: Some("fetch".to_string()),
+                                              colour: None,
+                                              arrow: Arrow::default(),
+                                              creates_target: false,
+                                          }]),
+        };
+
+        assert_eq!(::opt_parser(test_uml.as_bytes(), &ParseState::default()), Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+
+        // A bare `end`, and `opt` with no label.
+        let uml = "@startuml\n\
+                   loop 3\n\
+                   opt\n\
+                   \x20 opt->b:not a block\n\
+                   end\n\
+                   end loop\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+        assert_eq!(uml_tokens.messages().len(), 1);
+        assert_eq!(uml_tokens.max_depth(), 2);
+
+        let printed = format!("{}", uml_tokens);
+        assert_eq!(printed,
+                   "@startuml\nloop 3\nopt\nopt->b:not a block\nend opt\nend loop\n@enduml\n");
+        assert_eq!(::parse_uml_collect_errors(&printed), (uml_tokens.clone(), vec![]));
+        assert_eq!(::from_mermaid(&uml_tokens.to_mermaid()).unwrap(),
+                   UMLTokens::new(uml_tokens.tokens[1..2].to_vec()));
+
+        assert_eq!(::parse_uml_collect_errors("opt\na->b\n").1,
+                   vec![UMLError::UnterminatedBlock {
+                            keyword: "opt".to_string(),
+                            start_offset: 0,
+                        }]);
+    }
+
+    #[test]
+    fn test_group_parser() {
+        let message = |from: &str, to: &str| {
+            UMLToken::Message {
+                from: from.to_string(),
+                to: to.to_string(),
+                text: None,
+                colour: None,
+                arrow: Arrow::default(),
+                creates_target: false,
+            }
+        };
+        let test_uml = "group Authentication\nclient->server\nserver->client\nend group\n";
+        let expected = UMLToken::Group {
+            label: "Authentication".to_string(),
+            sequence: UMLTokens::new(vec![message("client", "server"), message("server", "client")]),
+        };
+
+        assert_eq!(::group_parser(test_uml.as_bytes(), &ParseState::default()), Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+        assert_eq!(::group_parser(&b"group Authentication\n  client->server\n  \
+                                     server->client\nend\n"[..], &ParseState::default()),
+                   Done(&[][..], expected));
+
+        // The label is mandatory.
+        assert!(::group_parser(&b"group\na->b\nend\n"[..], &ParseState::default()).is_err());
+    }
+
+    #[test]
+    fn test_note_positions() {
+        let note = |position: NotePosition| {
+            UMLToken::Note {
+                position,
+                text: "text".to_string(),
+            }
+        };
+        let cases = vec![("note over A, B\ntext\nend note\n",
+                          NotePosition::Over(vec!["A".to_string(), "B".to_string()])),
+                         ("note right of Server\ntext\nend note\n",
+                          NotePosition::Right(Some("Server".to_string()))),
+                         ("note left\ntext\nend note\n", NotePosition::Left(None))];
+
+        for (test_uml, position) in cases {
+            let expected = note(position);
+            assert_eq!(::note_parser(test_uml.as_bytes()), Done(&[][..], expected.clone()));
+            assert_eq!(format!("{}", expected), test_uml);
+        }
+
+        assert_eq!(::note_parser(&b"note over A,B\ntext\nend note\n"[..]),
+                   Done(&[][..],
+                        note(NotePosition::Over(vec!["A".to_string(), "B".to_string()]))));
+        assert_eq!(NotePosition::parse("left of"),
+                   NotePosition::Other("left of".to_string()));
+        assert_eq!(NotePosition::parse("over A,"),
+                   NotePosition::Other("over A,".to_string()));
+    }
+
+    #[test]
+    fn test_inline_note() {
+        let expected = UMLToken::Note {
+            position: NotePosition::Right(Some("A".to_string())),
+            text: "hello".to_string(),
+        };
+
+        assert_eq!(::note_parser(&b"note right of A: hello\n"[..]),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(::note_parser(&b"  note right of A : hello ' greeting\n"[..]),
+                   Done(&[][..], expected.clone()));
+
+        // Inline notes are printed in block form, which parses to the same
+        // token.
+        let printed = format!("{}", expected);
+        assert_eq!(printed, "note right of A\nhello\nend note\n");
+        assert_eq!(::note_parser(printed.as_bytes()), Done(&[][..], expected.clone()));
+
+        let test_uml = "@startuml\nnote right of A: hello\nnote left: aside\n@enduml\n";
+        let cfg = ParserConfig {
+            strict: true,
+            ..ParserConfig::default()
+        };
+        let (uml_tokens, errors, _) = ::parse_uml_with_config(test_uml, &cfg);
+        assert!(errors.is_empty());
+        assert_eq!(uml_tokens.tokens[1], expected);
+    }
+
+    #[test]
+    fn test_ref_over_round_trip() {
+        let uml = "@startuml\n\
+                   ref over Client, Server : See the login diagram\n\
+                   ref over Client, Server\n\
+                   first\n\
+                   second\n\
+                   end ref\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(uml_tokens.tokens[1..3],
+                   [UMLToken::Ref {
+                        participants: vec!["Client".to_string(), "Server".to_string()],
+                        text: "See the login diagram".to_string(),
+                    },
+                    UMLToken::Ref {
+                        participants: vec!["Client".to_string(), "Server".to_string()],
+                        text: "first\nsecond".to_string(),
+                    }]);
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[test]
+    fn test_loop_counts() {
+        let message = UMLToken::Message {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            text: None,
+            colour: None,
+            arrow: Arrow::default(),
+            creates_target: false,
+        };
+        let test_loop = |count: LoopCount| {
+            UMLToken::Loop {
+                sequence: UMLTokens::new(vec![message.clone()]),
+                count,
+            }
+        };
+
+        let expected = test_loop(LoopCount::Label("until done".to_string()));
+        let test_uml = "loop until done\na->b\nend loop\n";
+        assert_eq!(::loop_parser(test_uml.as_bytes(), &ParseState::default()), Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+        assert_eq!(::loop_parser(&b"loop until done ' retry\na->b\nend\n"[..], &ParseState::default()),
+                   Done(&[][..], expected));
+
+        let expected = test_loop(LoopCount::Times(1000));
+        assert_eq!(::loop_parser(&b"loop 1000\na->b\nend\n"[..], &ParseState::default()),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), "loop 1000\na->b\nend loop\n");
+
+        assert_eq!(::parse_uml_str("@startuml\nloop 4294967296\na->b\nend\n@enduml\n"),
+                   Err(UMLError::Parse {
+                       line: 2,
+                       column: 1,
+                       snippet: "loop 4294967296".to_string(),
+                   }));
+        assert!(::loop_parser(&b"loop\na->b\nend\n"[..], &ParseState::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let uml = "@startuml\n\
+                   a->b\n\
+                   \n\
+                   b->a: reply\n\
+                   \t  <<bad>>\n\
+                   @enduml\n";
+        let error = UMLError::Parse {
+            line: 5,
+            column: 4,
+            snippet: "<<bad>>".to_string(),
+        };
+
+        assert_eq!(::parse_uml_str(uml), Err(error.clone()));
+        assert_eq!(::parse_uml_collect_errors(uml).1, vec![error.clone()]);
+        assert_eq!(error.to_string(), "Failed to parse line 5, column 4: <<bad>>");
+    }
+
+    #[test]
+    fn test_walk_nested_messages() {
+        let uml = "@startuml\n\
+                   a->b: start\n\
+                   loop 3\n\
+                   alt ok\n\
+                   b->a: yes\n\
+                   else\n\
+                   b->a: no\n\
+                   b->c: retry\n\
+                   end\n\
+                   end\n\
+                   @enduml\n";
+        let (mut uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let mut count = 0;
+        uml_tokens.walk(&mut |token| if let UMLToken::Message { .. } = *token {
+            count += 1;
+        });
+        assert_eq!(count, 4);
+
+        uml_tokens.walk_mut(&mut |token| if let UMLToken::Message { ref mut to, .. } = *token {
+            if to == "c" {
+                *to = "a".to_string();
+            }
+        });
+        assert!(!uml_tokens.contains_participant("c"));
+    }
+
+    #[test]
+    fn test_participants() {
+        let uml = "@startuml\n\
+                   participant Server\n\
+                   box Clients\n\
+                   Client->Server: request\n\
+                   end box\n\
+                   loop 2\n\
+                   Server->Database: query\n\
+                   Server->Client: response\n\
+                   end\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.participants(), vec!["Server", "Client", "Database"]);
+    }
+
+    #[test]
+    fn test_activation_balance() {
+        let warnings = |uml: &str| {
+            let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+            assert!(errors.is_empty());
+            uml_tokens.validate(&ParserConfig::default())
+        };
+
+        // A missing deactivate.
+        assert_eq!(warnings("@startuml\na->b\nactivate b\nb->a\n@enduml\n"),
+                   vec![UMLWarning::ActivationNotEnded { participant: "b".to_string() }]);
+
+        // A stray deactivate.
+        assert_eq!(warnings("@startuml\na->b\nactivate b\ndeactivate b\ndeactivate b\n@enduml\n"),
+                   vec![UMLWarning::DeactivateWithoutActivate { participant: "b".to_string() }]);
+
+        assert_eq!(warnings("@startuml\nactivate b\ndestroy b\n@enduml\n"),
+                   vec![UMLWarning::DestroyedWhileActive { participant: "b".to_string() }]);
+
+        // Each branch starts from the activations before the block, and
+        // anything left active by a branch is still active after it.
+        let uml = "@startuml\n\
+                   activate a\n\
+                   alt ok\n\
+                   activate b\n\
+                   deactivate a\n\
+                   else\n\
+                   activate b\n\
+                   deactivate a\n\
+                   end\n\
+                   deactivate b\n\
+                   @enduml\n";
+        assert_eq!(warnings(uml), vec![]);
+
+        let uml = "@startuml\n\
+                   par\n\
+                   activate a\n\
+                   else\n\
+                   deactivate a\n\
+                   end\n\
+                   deactivate a\n\
+                   @enduml\n";
+        assert_eq!(warnings(uml),
+                   vec![UMLWarning::DeactivateWithoutActivate { participant: "a".to_string() }]);
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let uml = "@startuml\n\
+                   participant Client\n\
+                   box \"Backend\" #FFAAAA\n\
+                   participant Server\n\
+                   end box\n\
+                   box Store #LightBlue\n\
+                   participant Cache\n\
+                   end box\n\
+                   Client->Server: request\n\
+                   opt cached\n\
+                   Server-->Client: hit\n\
+                   end\n\
+                   par\n\
+                   Server->Client: one\n\
+                   else\n\
+                   note left of Client\n\
+                   two\n\
+                   end note\n\
+                   end\n\
+                   delay later\n\
+                   destroy Server\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        assert_eq!(uml_tokens.to_mermaid(),
+                   "sequenceDiagram\n    \
+                    participant Client\n    \
+                    box rgb(255,170,170) Backend\n        \
+                    participant Server\n    \
+                    end\n    \
+                    box LightBlue Store\n        \
+                    participant Cache\n    \
+                    end\n    \
+                    Client->>Server: request\n    \
+                    opt cached\n        \
+                    Server-->>Client: hit\n    \
+                    end\n    \
+                    par\n        \
+                    Server->>Client: one\n    \
+                    and\n        \
+                    Note left of Client: two\n    \
+                    end\n    \
+                    %% delay later\n    \
+                    %% destroy Server\n");
+    }
+
+    #[test]
+    fn test_participant_kinds() {
+        let test_uml = "database DB as db\n";
+        let expected = UMLToken::Participant {
+            kind: ParticipantKind::Database,
+            long_name: Some("DB".to_string()),
+            short_name: "db".to_string(),
+            stereotype: None,
+            colour: None,
+            order: None,
+        };
+
+        assert_eq!(::participant_parser(test_uml.as_bytes()),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+
+        for keyword in &["participant", "actor", "boundary", "control", "entity", "database",
+                         "collections", "queue"] {
+            let test_uml = format!("{} A\n", keyword);
+            match ::participant_parser(test_uml.as_bytes()) {
+                Done(_, ref token @ UMLToken::Participant { kind, .. }) => {
+                    assert_eq!(kind.keyword(), *keyword);
+                    assert_eq!(format!("{}", token), test_uml);
+                }
+                result => panic!("Failed to parse {}: {:?}", keyword, result),
+            }
+        }
+
+        // A participant named like a keyword is still a message.
+        assert_eq!(::uml_statement(&b"queue->A\n"[..], &ParseState::default()).map(|token| token.kind_name()),
+                   Done(&[][..], "message"));
+    }
+
+    #[test]
+    fn test_self_message_and_attached_note() {
+        let message = |from: &str, to: &str, text: &str| {
+            UMLToken::Message {
+                from: from.to_string(),
+                to: to.to_string(),
+                text: Some(text.to_string()),
+                colour: None,
+                arrow: Arrow::default(),
+                creates_target: false,
+            }
+        };
+
+        assert_eq!(::message_parser(&b"A -> A: loopback\n"[..]),
+                   Done(&[][..], message("A", "A", "loopback")));
+        assert_eq!(::message_parser(&b"A->A\n"[..]).map(|token| format!("{}", token)),
+                   Done(&[][..], "A->A\n".to_string()));
+
+        let uml = "@startuml\n\
+                   A->B: hi\n\
+                   note right of B\n\
+                   attached\n\
+                   end note\n\
+                   B -> B: think\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(rest, uml_tokens) => {
+                assert!(rest.is_empty());
+                uml_tokens
+            }
+            result => panic!("Failed to parse: {:?}", result),
+        };
+        let note = UMLToken::Note {
+            position: NotePosition::Right(Some("B".to_string())),
+            text: "attached".to_string(),
+        };
+
+        assert_eq!(uml_tokens.tokens[1..4],
+                   [message("A", "B", "hi"), note, message("B", "B", "think")]);
+    }
+
+    #[test]
+    fn test_divider_sections() {
+        assert_eq!(::divider_parser(&b"== Setup ==\n"[..]),
+                   Done(&[][..], UMLToken::Divider { text: "Setup".to_string() }));
+        assert_eq!(::divider_parser(&b"  ==== Phase 2 ====  \n"[..]),
+                   Done(&[][..], UMLToken::Divider { text: "Phase 2".to_string() }));
+
+        let uml = "@startuml\n\
+                   == Setup ==\n\
+                   a->b\n\
+                   == Teardown ==\n\
+                   b->a\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert!(errors.is_empty());
+
+        let setup = UMLToken::Divider { text: "Setup".to_string() };
+        let teardown = UMLToken::Divider { text: "Teardown".to_string() };
+        assert_eq!(format!("{}", uml_tokens.slice_between(&setup, &teardown)), "a->b\n");
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_include_relative_to_including_file() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_relative_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub").join("deeper")).unwrap();
+
+        // Each include is relative to the directory of the file containing it,
+        // so the b.uml next to top.uml is never read.
+        std::fs::write(dir.join("top.uml"), "@startuml\n!include sub/a.uml\n@enduml\n").unwrap();
+        std::fs::write(dir.join("b.uml"), "wrong->x\n").unwrap();
+        std::fs::write(dir.join("sub").join("a.uml"), "a->x\n!include b.uml\n").unwrap();
+        std::fs::write(dir.join("sub").join("b.uml"), "b->x\n!include deeper/c.uml\n").unwrap();
+        std::fs::write(dir.join("sub").join("deeper").join("c.uml"), "c->x\n").unwrap();
+
+        let senders = |uml_tokens: &UMLTokens| -> Vec<String> {
+            uml_tokens.messages()
+                .into_iter()
+                .map(|message| match *message {
+                    UMLToken::Message { ref from, .. } => from.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+
+        let uml_tokens = ::parse_uml_file("top.uml", Some(&dir)).unwrap();
+        assert_eq!(senders(&uml_tokens), vec!["a", "b", "c"]);
+
+        let diagrams = ::parse_uml_dir(&dir, &ParserConfig::default()).unwrap();
+        assert_eq!(senders(&diagrams[&dir.join("top.uml")]), vec!["a", "b", "c"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_block_end_forms() {
+        let message = UMLToken::Message {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            text: None,
+            colour: None,
+            arrow: Arrow::default(),
+            creates_target: false,
+        };
+        let test_loop = UMLToken::Loop {
+            sequence: UMLTokens::new(vec![message.clone()]),
+            count: LoopCount::Times(3),
+        };
+        let test_box = UMLToken::Box {
+            name: "\"Backend\"".to_string(),
+            colour: None,
+            sequence: UMLTokens::new(vec![message]),
+        };
+
+        for end in &["end", "end loop", "end  loop  ", "end ' done", "end loop ' done"] {
+            let test_uml = format!("loop 3\na->b\n{}\n", end);
+            assert_eq!(::loop_parser(test_uml.as_bytes(), &ParseState::default()),
+                       Done(&[][..], test_loop.clone()),
+                       "{}",
+                       end);
+        }
+        for end in &["end", "end box", "end box ' done", "end ' done"] {
+            let test_uml = format!("box \"Backend\"\na->b\n{}\n", end);
+            assert_eq!(::box_parser(test_uml.as_bytes(), &ParseState::default()),
+                       Done(&[][..], test_box.clone()),
+                       "{}",
+                       end);
+        }
+
+        // An end naming a different block, or run into another word, doesn't
+        // close the block.
+        for end in &["end par", "end loops", "endloop", "end loop x"] {
+            let test_uml = format!("loop 3\na->b\n{}\n", end);
+            assert!(::loop_parser(test_uml.as_bytes(), &ParseState::default()).is_err(), "{}", end);
+        }
+        assert!(::box_parser(&b"box\na->b\nend loop\n"[..], &ParseState::default()).is_err());
+
+        let (_, errors) = ::parse_uml_collect_errors("loop 2\na->b\nend par\n");
+        assert_eq!(errors[0],
+                   UMLError::UnterminatedBlock {
+                       keyword: "loop".to_string(),
+                       start_offset: 0,
+                   });
+    }
+
+    #[test]
+    fn test_builder() {
+        let uml_tokens = UMLBuilder::new()
+            .start()
+            .participant("A")
+            .message("A", "B", Some("hi"))
+            .loop_block(3, |b| {
+                b.activate("B")
+                    .alt_block(Some("ok"), |b| b.message("B", "A", Some("yes")))
+                    .else_block(None, |b| {
+                        b.note(NotePosition::Over(vec!["A".to_string(), "B".to_string()]), "no")
+                    })
+                    .deactivate("B")
+            })
+            .end()
+            .build()
+            .unwrap();
+
+        let uml = "@startuml\n\
+                   participant A\n\
+                   A->B:hi\n\
+                   loop 3\n\
+                   activate B\n\
+                   alt ok\n\
+                   B->A:yes\n\
+                   else\n\
+                   note over A, B\n\
+                   no\n\
+                   end note\n\
+                   end alt\n\
+                   deactivate B\n\
+                   end loop\n\
+                   @enduml\n";
+        assert_eq!(format!("{}", uml_tokens), uml);
+        assert_eq!(::parse_uml_str(uml), Ok(uml_tokens));
+
+        assert_eq!(UMLBuilder::new().participant("A").end().build(),
+                   Err(UMLError::Unbracketed));
+        assert_eq!(UMLBuilder::new().start().participant("A").build(),
+                   Err(UMLError::Unbracketed));
+        assert_eq!(UMLBuilder::new().start().opt_block(None, |b| b.end()).end().build(),
+                   Err(UMLError::Unbracketed));
+    }
+
+    #[test]
+    fn test_activate_colour() {
+        for &(uml, ref token) in &[("activate A #red\n",
+                                    UMLToken::Activate {
+                                        name: "A".to_string(),
+                                        colour: Some("red".to_string()),
+                                    }),
+                                   ("activate A\n",
+                                    UMLToken::Activate {
+                                        name: "A".to_string(),
+                                        colour: None,
+                                    }),
+                                   ("deactivate A #red\n",
+                                    UMLToken::Deactivate {
+                                        name: "A".to_string(),
+                                        colour: Some("red".to_string()),
+                                    }),
+                                   ("deactivate A\n",
+                                    UMLToken::Deactivate {
+                                        name: "A".to_string(),
+                                        colour: None,
+                                    })] {
+            assert_eq!(::uml_statement(uml.as_bytes(), &ParseState::default()),
+                       Done(&[][..], token.clone()));
+            assert_eq!(format!("{}", token), uml);
+        }
+    }
+
+    #[test]
+    fn test_to_string_indented() {
+        let uml = "@startuml\n\
+                   box Internal\n\
+                   participant a\n\
+                   end box\n\
+                   loop 2\n\
+                   alt ok\n\
+                   a->b:call\n\
+                   else\n\
+                   note over a\n\
+                   failed\n\
+                   end note\n\
+                   end alt\n\
+                   end loop\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(uml_tokens.to_string_indented(2),
+                   "@startuml\n\
+                    box Internal\n  \
+                      participant a\n\
+                    end box\n\
+                    loop 2\n  \
+                      alt ok\n    \
+                        a->b:call\n  \
+                      else\n    \
+                        note over a\n    \
+                        failed\n    \
+                        end note\n  \
+                      end alt\n\
+                    end loop\n\
+                    @enduml\n");
+        assert_eq!(uml_tokens.to_string_indented(0), uml);
+        assert_eq!(format!("{}", uml_tokens), uml);
+        assert_eq!(::uml_parser(uml_tokens.to_string_indented(4).as_bytes()),
+                   Done(&[][..], uml_tokens));
+    }
+
+    #[test]
+    fn test_startuml_name_trimmed() {
+        assert_eq!(::startuml(&b"@startuml   MyDiagram  \n"[..]),
+                   Done(&[][..], UMLToken::StartUML { name: Some("MyDiagram".to_string()) }));
+        assert_eq!(::startuml(&b"@startuml\n"[..]),
+                   Done(&[][..], UMLToken::StartUML { name: None }));
+        assert_eq!(format!("{}", UMLToken::StartUML { name: Some("MyDiagram".to_string()) }),
+                   "@startuml MyDiagram\n");
+        assert_eq!(format!("{}", UMLToken::StartUML { name: None }), "@startuml\n");
+    }
+
+    #[test]
+    fn test_try_parse() {
+        let uml = "@startuml\na->b:hello\n@enduml\n";
+
+        assert_eq!(::try_parse(uml.as_bytes()),
+                   ParseOutcome::Done(&[][..], ::uml_parser(uml.as_bytes()).unwrap().1));
+        for &partial in &["@startuml\nloop 2\na->b\n", "@startuml\na->b:hel"] {
+            match ::try_parse(partial.as_bytes()) {
+                ParseOutcome::Incomplete(_) => {}
+                outcome => panic!("Expected Incomplete for {:?}: {:?}", partial, outcome),
+            }
+        }
+        assert_eq!(::try_parse(&b"end loop\n"[..]), ParseOutcome::Error);
+    }
+
+    #[test]
+    fn test_participant_alias_and_stereotype() {
+        let stereotype = |text: &str| {
+            Some(Stereotype {
+                spot: None,
+                text: text.to_string(),
+            })
+        };
+
+        for &(uml, long_name, short_name, text) in
+            &[("participant \"Order API\" as api <<external>>\n", Some("\"Order API\""), "api", "external"),
+              ("participant A <<service>>\n", None, "A", "service"),
+              ("participant A as B <<external service>>\n", Some("A"), "B", "external service")] {
+            let expected = UMLToken::Participant {
+                kind: ParticipantKind::Participant,
+                long_name: long_name.map(|name| name.to_string()),
+                short_name: short_name.to_string(),
+                stereotype: stereotype(text),
+                colour: None,
+                order: None,
+            };
+
+            assert_eq!(::participant_parser(uml.as_bytes()),
+                       Done(&[][..], expected.clone()));
+            assert_eq!(format!("{}", expected), uml);
+        }
+    }
+
+    #[test]
+    fn test_create_participant() {
+        for test_uml in &["create A\n", "create participant A\n", "create actor  A \n"] {
+            assert_eq!(::uml_statement(test_uml.as_bytes(), &ParseState::default()),
+                       Done(&[][..], UMLToken::Create { name: "A".to_string() }));
+        }
+
+        assert_eq!(::create_parser(&b"create participant\n"[..]),
+                   Done(&[][..], UMLToken::Create { name: "participant".to_string() }));
+        assert_eq!(format!("{}", UMLToken::Create { name: "A".to_string() }), "create A\n");
+    }
+
+    #[test]
+    fn test_newpage_parser() {
+        assert_eq!(::newpage_parser(&b"newpage\n"[..]),
+                   Done(&[][..], UMLToken::NewPage { title: None }));
+        assert_eq!(::newpage_parser(&b"newpage  Second page ' continued\n"[..]),
+                   Done(&[][..], UMLToken::NewPage { title: Some("Second page".to_string()) }));
+
+        let uml = "@startuml\na->b\nnewpage\nb->c\nnewpage Third\nc->a\n@enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(uml_tokens.tokens[2], UMLToken::NewPage { title: None });
+        assert_eq!(uml_tokens.tokens[4],
+                   UMLToken::NewPage { title: Some("Third".to_string()) });
+        assert_eq!(format!("{}", uml_tokens), uml);
+    }
+
+    #[test]
+    fn test_preserve_blank_lines() {
+        let uml = "@startuml\n\
+                   \n\
+                   ' Setup\n\
+                   participant a\n\
+                   \n\
+                   \n\
+                   loop 2\n\
+                   a->b\n\
+                   \n\
+                   end loop\n\
+                   alt ok\n\
+                   a->b\n\
+                   \n\
+                   else\n\
+                   \n\
+                   b->a\n\
+                   end alt\n\
+                   @enduml\n\
+                   \n";
+        let cfg = ParserConfig {
+            preserve_blank_lines: true,
+            ..ParserConfig::default()
+        };
+        let (uml_tokens, errors, _) = ::parse_uml_with_config(uml, &cfg);
+
+        assert_eq!(errors, vec![]);
+        assert_eq!(uml_tokens.tokens[1], UMLToken::Blank);
+        assert_eq!(uml_tokens.tokens[4], UMLToken::Blank);
+        assert_eq!(format!("{}", uml_tokens), uml);
+
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert_eq!(errors, vec![]);
+        assert!(uml_tokens.find_all(|token| *token == UMLToken::Blank).is_empty());
+    }
+
+    #[test]
+    fn test_critical_in_loop() {
+        let uml = "@startuml\n\
+                   loop 3\n\
+                   critical Commit\n\
+                   a->b:write\n\
+                   end critical\n\
+                   critical\n\
+                   b->a:ack\n\
+                   end\n\
+                   end loop\n\
+                   @enduml\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        let expected = UMLBuilder::new()
+            .start()
+            .loop_block(3, |b| {
+                b.critical_block(Some("Commit"), |b| b.message("a", "b", Some("write")))
+                    .critical_block(None, |b| b.message("b", "a", Some("ack")))
+            })
+            .end()
+            .build()
+            .unwrap();
+        assert_eq!(uml_tokens, expected);
+        assert_eq!(format!("{}", uml_tokens), uml.replace("end\n", "end critical\n"));
+
+        let (_, errors) = ::parse_uml_collect_errors("critical\na->b\n");
+        assert_eq!(errors,
+                   vec![UMLError::UnterminatedBlock {
+                            keyword: "critical".to_string(),
+                            start_offset: 0,
+                        }]);
+    }
+
+    #[test]
+    fn test_break_parser() {
+        let test_uml = "break timeout\na->b:abort\nend break\n";
+        let expected = UMLToken::Break {
+            label: Some("timeout".to_string()),
+            sequence: UMLTokens::new(vec![UMLToken::Message {
+                                              from: "a".to_string(),
+                                              to: "b".to_string(),
+                                              text: Some("abort".to_string()),
+                                              colour: None,
+                                              arrow: Arrow::default(),
+                                              creates_target: false,
+                                          }]),
+        };
+
+        assert_eq!(::uml_statement(test_uml.as_bytes(), &ParseState::default()),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(::uml_statement(&b"break timeout\na->b:abort\nend\n"[..], &ParseState::default()),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), test_uml);
+    }
+
+    #[test]
+    fn test_case_insensitive_keywords() {
+        let uml = "@StartUML\nPARTICIPANT foo\n@EndUML\n";
+        let uml_tokens = match ::uml_parser(uml.as_bytes()) {
+            Done(_, uml_tokens) => uml_tokens,
+            result => panic!("Failed to parse: {:?}", result),
+        };
+
+        assert_eq!(uml_tokens,
+                   UMLBuilder::new().start().participant("foo").end().build().unwrap());
+
+        let uml = "Loop 3\n\
+                   Note Left Of Foo\n\
+                   Some Text\n\
+                   END NOTE\n\
+                   Activate Foo #Red\n\
+                   End Loop\n";
+        assert_eq!(::uml_statement(uml.as_bytes(), &ParseState::default()),
+                   Done(&[][..],
+                        UMLToken::Loop {
+                            sequence: UMLTokens::new(vec![UMLToken::Note {
+                                                              position:
+                                                                  NotePosition::Left(Some("Foo".to_string())),
+                                                              text: "Some Text".to_string(),
+                                                          },
+                                                          UMLToken::Activate {
+                                                              name: "Foo".to_string(),
+                                                              colour: Some("Red".to_string()),
+                                                          }]),
+                            count: LoopCount::Times(3),
+                        }));
+
+        // Names that merely start with a keyword are still names.
+        for &(uml, from) in &[("Parser->b:x\n", "Parser"),
+                              ("activated->b:x\n", "activated"),
+                              ("Notebook->b:x\n", "Notebook"),
+                              ("endpoint->b:x\n", "endpoint")] {
+            match ::uml_statement(uml.as_bytes(), &ParseState::default()) {
+                Done(_, UMLToken::Message { from: ref parsed, .. }) if parsed == from => {}
+                result => panic!("Failed to parse {:?} as a message: {:?}", uml, result),
+            }
+        }
+
+        let (_, errors) = ::parse_uml_collect_errors("LOOP 3\na->b\n");
+        assert_eq!(errors,
+                   vec![UMLError::UnterminatedBlock {
+                            keyword: "loop".to_string(),
+                            start_offset: 0,
+                        }]);
+    }
+
+    #[test]
+    fn test_mismatched_block_end() {
+        for keyword in &["loop 2", "box", "par", "alt", "opt", "critical", "break", "group g"] {
+            let name = keyword.split(' ').next().unwrap();
+            let other = if name == "loop" { "par" } else { "loop" };
+
+            let test_uml = format!("{}\na->b\nend {} ' done\n", keyword, name);
+            match ::uml_statement(test_uml.as_bytes(), &ParseState::default()) {
+                Done(rest, ref token) if rest.is_empty() && token.kind_name() == name => {}
+                result => panic!("Failed to parse {:?}: {:?}", test_uml, result),
+            }
+
+            let test_uml = format!("{}\na->b\nend {}\n", keyword, other);
+            assert!(!::uml_statement(test_uml.as_bytes(), &ParseState::default()).is_done(), "{}", test_uml);
+        }
+    }
+
+    #[test]
+    fn test_iter_flat() {
+        let uml = "@startuml\n\
+                   box\n\
+                   participant a\n\
+                   end box\n\
+                   loop 2\n\
+                   alt ok\n\
+                   a->b\n\
+                   else\n\
+                   note left : x\n\
+                   / note right : y\n\
+                   end alt\n\
+                   opt\n\
+                   b->a\n\
+                   end opt\n\
+                   end loop\n\
+                   @enduml\n";
+        let uml_tokens = ::parse_uml_str(uml).unwrap();
+
+        let kinds: Vec<&str> = uml_tokens.iter_flat().map(UMLToken::kind_name).collect();
+        assert_eq!(kinds,
+                   vec!["startuml", "box", "participant", "loop", "alt", "message",
+                        "split_note", "note", "note", "opt", "message", "enduml"]);
+
+        let mut walked = Vec::new();
+        uml_tokens.walk(&mut |token| walked.push(token));
+        assert_eq!(uml_tokens.iter_flat().collect::<Vec<_>>(), walked);
+        assert_eq!(UMLTokens::new(vec![]).iter_flat().next(), None);
+    }
+
+    #[test]
+    fn test_participant_quoted_name_with_as() {
+        let expected = UMLToken::Participant {
+            kind: ParticipantKind::Participant,
+            long_name: Some("\"Parse as JSON\"".to_string()),
+            short_name: "p".to_string(),
+            stereotype: None,
+            colour: None,
+            order: None,
+        };
+
+        assert_eq!(::participant_parser(&b"participant \"Parse as JSON\" as p\n"[..]),
+                   Done(&[][..], expected.clone()));
+        assert_eq!(format!("{}", expected), "participant \"Parse as JSON\" as p\n");
+
+        // Without an alias the quoted name is the short name.
+        match ::participant_parser(&b"participant \"Parse as JSON\"\n"[..]) {
+            Done(_, UMLToken::Participant { long_name: None, ref short_name, .. }) => {
+                assert_eq!(short_name, "\"Parse as JSON\"")
+            }
+            result => panic!("Failed to parse: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_structurally_eq() {
+        let uml_tokens = |uml: &str| ::parse_uml_str(uml).unwrap();
+        let uml = uml_tokens("@startuml\n\
+                              delay 5 minutes\n\
+                              note left of a\n\
+                              first  line\n\
+                              second line\n\
+                              end note\n\
+                              loop 2\n\
+                              a->b:some text\n\
+                              end loop\n\
+                              @enduml\n");
+        let spaced = uml_tokens("@startuml\n\
+                                 delay 5   minutes\n\
+                                 note left of  a\n\
+                                 first line\n\
+                                 second  line  \n\
+                                 end note\n\
+                                 loop 2\n\
+                                 a->b:some\ttext\n\
+                                 end loop\n\
+                                 @enduml\n");
+
+        assert!(uml != spaced);
+        assert!(uml.structurally_eq(&spaced));
+
+        // Line breaks and the text itself still matter.
+        let differs = uml_tokens("@startuml\n\
+                                  delay 5 minutes\n\
+                                  note left of a\n\
+                                  first line second line\n\
+                                  end note\n\
+                                  loop 2\n\
+                                  a->b:some text\n\
+                                  end loop\n\
+                                  @enduml\n");
+        assert!(!uml.structurally_eq(&differs));
+        assert!(!uml.structurally_eq(&uml_tokens("@startuml\ndelay 5 hours\n@enduml\n")));
+    }
+
+    #[test]
+    fn test_bidirectional_message() {
+        let expected = UMLToken::Message {
+            from: "A".to_string(),
+            to: "B".to_string(),
+            text: Some("sync".to_string()),
+            colour: None,
+            arrow: Arrow {
+                direction: ArrowDirection::Both,
+                ..Arrow::default()
+            },
+            creates_target: false,
+        };
+
+        for test_uml in &["A<->B:sync\n", "A <-> B : sync\n"] {
+            assert_eq!(::message_parser(test_uml.as_bytes()),
+                       Done(&[][..], expected.clone()));
+        }
+        assert_eq!(format!("{}", expected), "A<->B:sync\n");
+    }
+
+    #[test]
+    fn test_delay_amount() {
+        for &(test_uml, text, amount) in &[("delay 50\n", "50", Some(50)),
+                                           ("delay 5 seconds\n", "5 seconds", Some(5)),
+                                           ("delay ...pause...\n", "...pause...", None),
+                                           ("delay 5s\n", "5s", None)] {
+            let expected = UMLToken::Delay {
+                text: text.to_string(),
+                amount,
+            };
+
+            assert_eq!(::delay_parser(test_uml.as_bytes()),
+                       Done(&[][..], expected.clone()));
+            assert_eq!(expected.duration(), amount);
+            assert_eq!(format!("{}", expected), test_uml);
+        }
+
+        let uml_tokens = ::parse_uml_str("@startuml\ndelay 5 seconds\n@enduml\n").unwrap();
+        assert_eq!(uml_tokens.to_render_model().events[0].kind,
+                   EventKind::Delay {
+                       text: "5 seconds".to_string(),
+                       amount: Some(5),
+                   });
+    }
+
+    #[test]
+    fn test_no_trailing_newline() {
+        let uml = "@startuml\nparticipant a\nloop 2\na->b:hi\nend loop\n@enduml";
+        let expected = ::parse_uml_str(&format!("{}\n", uml)).unwrap();
+
+        assert_eq!(expected.tokens.len(), 4);
+        assert_eq!(::parse_uml_str(uml), Ok(expected.clone()));
+        assert_eq!(::parse_uml_collect_errors(uml), (expected, vec![]));
+        assert_eq!(::parse_uml_str("@startuml\r\na->b\r\n@enduml").map(|uml| uml.tokens.len()),
+                   Ok(3));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_file_no_trailing_newline() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_newline_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("top.uml"), "@startuml\n!include inc.uml\n@enduml").unwrap();
+        std::fs::write(dir.join("inc.uml"), "a->b").unwrap();
+
+        let uml_tokens = ::parse_uml_file("top.uml", Some(&dir)).unwrap();
+        assert_eq!(format!("{}", uml_tokens), "@startuml\na->b\n@enduml\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_dir_missing_include() {
+        let dir = std::env::temp_dir()
+            .join(format!("uml_parser_missing_include_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.uml"), "@startuml
+a->b
+@enduml
+").unwrap();
+        std::fs::write(dir.join("top.uml"), "@startuml
+!include missing.uml
+@enduml
+")
+            .unwrap();
+
+        let mut cfg = ParserConfig::default();
+        match ::parse_uml_dir(&dir, &cfg) {
+            Err(UMLError::File { path, error }) => {
+                assert_eq!(path, dir.join("top.uml"));
+                match *error {
+                    UMLError::Io { path, .. } => assert_eq!(path, dir.join("missing.uml")),
+                    error => panic!("Unexpected error: {:?}", error),
+                }
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        cfg.continue_on_error = true;
+        let diagrams = ::parse_uml_dir(&dir, &cfg).unwrap();
+        assert_eq!(diagrams.keys().collect::<Vec<_>>(), vec![&dir.join("good.uml")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_str_missing_include() {
+        match ::parse_uml_str("@startuml\n!include missing.uml\n@enduml\n") {
+            Err(UMLError::Io { path, .. }) => assert_eq!(path, PathBuf::from("missing.uml")),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_parse_uml_str_partly_bad_include() {
+        let dir = std::env::temp_dir()
+            .join(format!("uml_parser_bad_include_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let included = dir.join("part.uml");
+        std::fs::write(&included, "a->b\nloop 4294967296\nb->a\nend\n").unwrap();
+
+        let uml = format!("@startuml\n!include {}\n@enduml\n", included.to_str().unwrap());
+        assert_eq!(::parse_uml_str(&uml),
+                   Err(UMLError::File {
+                       path: included,
+                       error: Box::new(UMLError::Parse {
+                           line: 2,
+                           column: 1,
+                           snippet: "loop 4294967296".to_string(),
+                       }),
+                   }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_include_cycle() {
+        let dir = std::env::temp_dir().join(format!("uml_parser_cycle_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // a includes b, which includes a again under another name.
+        std::fs::write(dir.join("a.uml"), "@startuml\n!include_once b.uml\na->b\n@enduml\n")
+            .unwrap();
+        std::fs::write(dir.join("b.uml"), "!include_once ./a.uml\nb->a\n").unwrap();
+        let uml_tokens = ::parse_uml_file("a.uml", Some(&dir)).unwrap();
+        assert_eq!(format!("{}", uml_tokens), "@startuml\nb->a\na->b\n@enduml\n");
+
+        std::fs::write(dir.join("b.uml"), "!include a.uml\nb->a\n").unwrap();
+        assert_eq!(::parse_uml_file("a.uml", Some(&dir)),
+                   Err(UMLError::File {
+                       path: dir.join("a.uml"),
+                       error: Box::new(UMLError::File {
+                           path: dir.join("b.uml"),
+                           error: Box::new(UMLError::IncludeCycle { path: dir.join("a.uml") }),
+                       }),
+                   }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_quoted_label() {
+        let message = |text: &str| {
+            UMLToken::Message {
+                from: "a".to_string(),
+                to: "b".to_string(),
+                text: Some(text.to_string()),
+                colour: None,
+                arrow: Arrow::default(),
+                creates_target: false,
+            }
+        };
+
+        assert_eq!(::message_parser(&b"a->b: say 'hello' now\n"[..]),
+                   Done(&[][..], message("say 'hello' now")));
+        assert_eq!(::message_parser(&b"a->b: say ' hello ' now ' greeting\n"[..]),
+                   Done(&[][..], message("say ' hello ' now")));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_resolve_with_provenance_nested_dirs() {
+        let dir = std::env::temp_dir()
+            .join(format!("uml_parser_provenance_nested_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let top = dir.join("top.uml");
+
+        std::fs::write(&top, "@startuml\n!include sub/a.uml\n@enduml\n").unwrap();
+        std::fs::write(dir.join("sub").join("a.uml"), "a->b\n!include b.uml\n").unwrap();
+        std::fs::write(dir.join("sub").join("b.uml"), "b->a\n").unwrap();
+
+        let uml_tokens = ::parse_uml_file(top.to_str().unwrap(), None).unwrap();
+        let files: Vec<PathBuf> =
+            uml_tokens.resolve_with_provenance(&top).into_iter().map(|(file, _)| file).collect();
+        assert_eq!(files,
+                   vec![top.clone(),
+                        dir.join("sub").join("a.uml"),
+                        dir.join("sub").join("b.uml"),
+                        top.clone()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_autonumber_keywords_any_case() {
+        assert_eq!(::autonumber_parser(&b"autonumber STOP\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Autonumber {
+                            start: None,
+                            stop: true,
+                            inc: None,
+                            format: None,
+                        }));
+        assert_eq!(::autonumber_parser(&b"Autonumber Inc aB\n"[..]),
+                   Done(&[][..],
+                        UMLToken::Autonumber {
+                            start: None,
+                            stop: false,
+                            inc: Some("aB".to_string()),
+                            format: None,
+                        }));
+    }
+
+    #[test]
+    fn test_collect_errors_within_block() {
+        let uml = "@startuml\n\
+                   a->b\n\
+                   loop 2\n\
+                   b->c\n\
+                   !!bad\n\
+                   end loop\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert_eq!(errors,
+                   vec![UMLError::Parse {
+                            line: 5,
+                            column: 1,
+                            snippet: "!!bad".to_string(),
+                        }]);
+        assert_eq!(format!("{}", uml_tokens),
+                   "@startuml\na->b\nloop 2\nb->c\nend loop\n@enduml\n");
+
+        // Nested blocks and branches keep what does parse, and a block left
+        // with nothing in it is dropped without complaining about its header.
+        let uml = "@startuml\n\
+                   alt ok\n\
+                   loop 2\n\
+                   !!bad\n\
+                   end\n\
+                   a->b\n\
+                   else\n\
+                   b->a\n\
+                   !!worse\n\
+                   end\n\
+                   @enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert_eq!(errors.iter().map(|error| error.to_string()).collect::<Vec<_>>(),
+                   vec!["Failed to parse line 4, column 1: !!bad",
+                        "Failed to parse line 9, column 1: !!worse"]);
+        assert_eq!(format!("{}", uml_tokens),
+                   "@startuml\nalt ok\na->b\nelse\nb->a\nend alt\n@enduml\n");
+
+        // A bad header is reported once, and the block is skipped.
+        let uml = "@startuml\nloop 4294967296\na->b\nend loop\nb->a\n@enduml\n";
+        let (uml_tokens, errors) = ::parse_uml_collect_errors(uml);
+        assert_eq!(errors,
+                   vec![UMLError::Parse {
+                            line: 2,
+                            column: 1,
+                            snippet: "loop 4294967296".to_string(),
+                        }]);
+        assert_eq!(format!("{}", uml_tokens), "@startuml\nb->a\n@enduml\n");
+    }
+
+    #[test]
+    fn test_token_at_offset_blank_lines() {
+        let uml = "@startuml\n\nA -> B: one\n\nA -> B: two\nloop 2\nA -> B: three\n\n\nA -> B: four\nend\n@enduml\n";
+        let cfg = ParserConfig {
+            preserve_blank_lines: true,
+            ..ParserConfig::default()
+        };
+        let (uml_tokens, errors, _) = ::parse_uml_with_config(uml, &cfg);
+        assert_eq!(errors, vec![]);
+
+        let path = |text: &str| {
+            uml_tokens.token_at_offset(uml, uml.find(text).unwrap()).map(|(_, path)| path)
+        };
+        assert_eq!(path("two"), Some(vec![4]));
+        assert_eq!(path("four"), Some(vec![5, 3]));
+        assert_eq!(path("\n\nA -> B: one").map(|path| path.len()), Some(1));
+        assert_eq!(uml_tokens.token_at_offset(uml, uml.find("\n\nA -> B: two").unwrap() + 1),
+                   Some((&UMLToken::Blank, vec![3])));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn test_token_at_offset_after_include() {
+        let dir = std::env::temp_dir()
+            .join(format!("uml_parser_token_at_offset_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let uml = "@startuml\n!include common.uml\na->b: after\n@enduml\n";
+        std::fs::write(dir.join("main.uml"), uml).unwrap();
+        std::fs::write(dir.join("common.uml"), "b->a\n").unwrap();
+
+        let uml_tokens = ::parse_uml_file("main.uml", Some(&dir)).unwrap();
+        let offset = uml.find("after").unwrap();
+        assert_eq!(uml_tokens.token_at_offset(uml, offset).map(|(_, path)| path),
+                   Some(vec![2]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
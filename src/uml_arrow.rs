@@ -0,0 +1,258 @@
+//! Message arrows: `->`, `<--`, `<->`, `->>`, `->x`, `o<-`, `-[#red]>` and so
+//! on.
+
+use nom::{ErrorKind, IResult, Needed};
+
+/// The shape of a message arrow.  The default is a solid arrow pointing right
+/// with a filled head (`->`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Arrow {
+    pub direction: ArrowDirection,
+    pub style: ArrowStyle,
+    pub head: ArrowHead,
+}
+
+/// Which end(s) of the arrow have a head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ArrowDirection {
+    /// `->`: the message goes from the left participant to the right one.
+    #[default]
+    ToRight,
+    /// `<-`: the message goes from the right participant to the left one.
+    ToLeft,
+    /// `<->`
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ArrowStyle {
+    /// `-`
+    #[default]
+    Solid,
+    /// `--`, typically used for replies.
+    Dashed,
+}
+
+/// The head drawn at the end(s) the arrow points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ArrowHead {
+    /// `>`
+    #[default]
+    Filled,
+    /// `>>`
+    Open,
+    /// `>x`, a lost message.
+    Cross,
+    /// `>o`
+    Circle,
+}
+
+/// Parses a message arrow, returning its shape and its `[#colour]`, if any.
+///
+/// The arrow is one or two dashes, optionally with a `[#colour]` anywhere
+/// among them or straight after a plain right hand head, with a head on one or
+/// both ends.  A head is `<`/`>`, `<<`/`>>`,
+/// or `<`/`>` together with an outer `x` or `o`; both heads must match.  The
+/// outer `x` or `o` of a right hand head must be followed by whitespace, so
+/// that `a->bob` still sends to `bob`.
+pub fn parse_arrow(input: &[u8]) -> IResult<&[u8], (Arrow, Option<String>)> {
+    let error = IResult::Error(error_position!(ErrorKind::Custom(0), input));
+    let mut index = 0;
+
+    let left_decoration = match input.get(index) {
+        Some(&c) if (c == b'x' || c == b'o') && input.get(index + 1) == Some(&b'<') => {
+            index += 1;
+            Some(c)
+        }
+        _ => None,
+    };
+
+    let left = if input[index..].starts_with(b"<<") && left_decoration.is_none() {
+        index += 2;
+        Some(ArrowHead::Open)
+    } else if input[index..].starts_with(b"<") {
+        index += 1;
+        Some(decorated_head(left_decoration))
+    } else {
+        None
+    };
+
+    let mut dashes = 0;
+    let mut colour = None;
+    loop {
+        match input.get(index) {
+            Some(&b'-') => {
+                dashes += 1;
+                index += 1;
+            }
+            Some(&b'[') if colour.is_none() && input[index + 1..].starts_with(b"#") => {
+                match bracketed_colour(input, index) {
+                    Some((text, end)) => {
+                        colour = Some(text);
+                        index = end;
+                    }
+                    None => return error,
+                }
+            }
+            Some(_) => break,
+            None => return IResult::Incomplete(Needed::Size(index + 1)),
+        }
+    }
+
+    let style = match dashes {
+        1 => ArrowStyle::Solid,
+        2 => ArrowStyle::Dashed,
+        _ => return error,
+    };
+
+    let right = if input[index..].starts_with(b">>") {
+        index += 2;
+        Some(ArrowHead::Open)
+    } else if input[index..].starts_with(b">") {
+        index += 1;
+        let decoration = match input.get(index) {
+            Some(&c) if (c == b'x' || c == b'o') &&
+                        input.get(index + 1).is_some_and(|c| c.is_ascii_whitespace()) => {
+                index += 1;
+                Some(c)
+            }
+            _ => None,
+        };
+        Some(decorated_head(decoration))
+    } else {
+        None
+    };
+
+    // The colour can also follow an undecorated right hand head, as in
+    // `-->[#gray]`.
+    let undecorated = right == Some(ArrowHead::Filled) || right == Some(ArrowHead::Open);
+    if undecorated && colour.is_none() && input[index..].starts_with(b"[#") {
+        match bracketed_colour(input, index) {
+            Some((text, end)) => {
+                colour = Some(text);
+                index = end;
+            }
+            None => return error,
+        }
+    }
+
+    let (direction, head) = match (left, right) {
+        (Some(left), Some(right)) if left == right => (ArrowDirection::Both, left),
+        (Some(left), None) => (ArrowDirection::ToLeft, left),
+        (None, Some(right)) => (ArrowDirection::ToRight, right),
+        _ => return error,
+    };
+
+    IResult::Done(&input[index..],
+                  (Arrow {
+                       direction,
+                       style,
+                       head,
+                   },
+                   colour))
+}
+
+/// Parses the `[#colour]` starting at `index`, returning the colour and the
+/// index just after the `]`.
+fn bracketed_colour(input: &[u8], index: usize) -> Option<(String, usize)> {
+    let end = index + input[index..].iter().position(|&c| c == b']' || c == b'\n')?;
+    if input[end] != b']' {
+        return None;
+    }
+
+    match std::str::from_utf8(&input[index + 2..end]) {
+        Ok(text) if !text.is_empty() => Some((text.to_string(), end + 1)),
+        _ => None,
+    }
+}
+
+fn decorated_head(decoration: Option<u8>) -> ArrowHead {
+    match decoration {
+        Some(b'x') => ArrowHead::Cross,
+        Some(_) => ArrowHead::Circle,
+        None => ArrowHead::Filled,
+    }
+}
+
+/// Finds the first arrow in a message line, returning the text before it, the
+/// arrow and its colour, and the text after it.
+///
+/// Only the part of the line before any `:` is searched, so arrows in the
+/// message text are ignored.  An `x` or `o` before a left hand head only
+/// counts as part of the arrow if it's preceded by whitespace.
+pub(crate) fn find_arrow(line: &str) -> Option<(&str, Arrow, Option<String>, &str)> {
+    let end = line.find(':').unwrap_or(line.len());
+    let bytes = line.as_bytes();
+
+    for index in 0..end {
+        match bytes[index] {
+            b'-' | b'<' => {}
+            b'x' | b'o' if index > 0 && bytes[index - 1].is_ascii_whitespace() => {}
+            _ => continue,
+        }
+
+        if let IResult::Done(rest, (arrow, colour)) = parse_arrow(&bytes[index..end]) {
+            return Some((&line[..index], arrow, colour, &line[end - rest.len()..]));
+        }
+    }
+
+    None
+}
+
+/// Formats an arrow with an optional colour.  The colour goes straight after
+/// the head of a left pointing arrow, and after the first dash otherwise.
+///
+/// An outer `x` or `o` is separated from the participant name by a space, so
+/// that it isn't read back as part of the name.
+pub(crate) fn format_arrow(arrow: &Arrow, colour: &Option<String>) -> String {
+    let colour = match *colour {
+        Some(ref colour) => format!("[#{}]", colour),
+        None => String::new(),
+    };
+    let decorated = arrow.head == ArrowHead::Cross || arrow.head == ArrowHead::Circle;
+    let mut arrow_str = String::new();
+
+    if decorated && arrow.direction != ArrowDirection::ToRight {
+        arrow_str.push(' ');
+    }
+
+    if arrow.direction != ArrowDirection::ToRight {
+        arrow_str.push_str(match arrow.head {
+            ArrowHead::Filled => "<",
+            ArrowHead::Open => "<<",
+            ArrowHead::Cross => "x<",
+            ArrowHead::Circle => "o<",
+        });
+    }
+
+    if arrow.direction == ArrowDirection::ToLeft {
+        arrow_str.push_str(&colour);
+        arrow_str.push('-');
+    } else {
+        arrow_str.push('-');
+        arrow_str.push_str(&colour);
+    }
+
+    if arrow.style == ArrowStyle::Dashed {
+        arrow_str.push('-');
+    }
+
+    if arrow.direction != ArrowDirection::ToLeft {
+        arrow_str.push_str(match arrow.head {
+            ArrowHead::Filled => ">",
+            ArrowHead::Open => ">>",
+            ArrowHead::Cross => ">x",
+            ArrowHead::Circle => ">o",
+        });
+    }
+
+    if decorated && arrow.direction != ArrowDirection::ToLeft {
+        arrow_str.push(' ');
+    }
+
+    arrow_str
+}
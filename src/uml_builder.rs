@@ -0,0 +1,212 @@
+//! A fluent builder for constructing diagrams in code, e.g. from traces.
+
+use {Arrow, LoopCount, NotePosition, ParticipantKind, UMLError, UMLToken, UMLTokens};
+
+/// Builds `UMLTokens` one statement at a time.
+///
+/// Blocks are built by a closure that is given a builder for their contents.
+/// A diagram must be bracketed by `start` and `end`, which `build` checks.
+#[derive(Debug, Clone, Default)]
+pub struct UMLBuilder {
+    tokens: Vec<UMLToken>,
+}
+
+impl UMLBuilder {
+    pub fn new() -> UMLBuilder {
+        UMLBuilder::default()
+    }
+
+    /// Adds `@startuml`.
+    pub fn start(self) -> UMLBuilder {
+        self.token(UMLToken::StartUML { name: None })
+    }
+
+    /// Adds `@enduml`.
+    pub fn end(self) -> UMLBuilder {
+        self.token(UMLToken::EndUML)
+    }
+
+    /// Adds any token, for statements without a method of their own.
+    pub fn token(mut self, token: UMLToken) -> UMLBuilder {
+        self.tokens.push(token);
+        self
+    }
+
+    /// Declares a participant.
+    pub fn participant(self, name: &str) -> UMLBuilder {
+        self.token(UMLToken::Participant {
+            kind: ParticipantKind::Participant,
+            long_name: None,
+            short_name: name.to_string(),
+            stereotype: None,
+            colour: None,
+            order: None,
+        })
+    }
+
+    /// Adds a `->` message.
+    pub fn message(self, from: &str, to: &str, text: Option<&str>) -> UMLBuilder {
+        self.token(UMLToken::Message {
+            from: from.to_string(),
+            to: to.to_string(),
+            text: text.map(|text| text.to_string()),
+            colour: None,
+            arrow: Arrow::default(),
+            creates_target: false,
+        })
+    }
+
+    pub fn note(self, position: NotePosition, text: &str) -> UMLBuilder {
+        self.token(UMLToken::Note {
+            position,
+            text: text.to_string(),
+        })
+    }
+
+    pub fn activate(self, name: &str) -> UMLBuilder {
+        self.token(UMLToken::Activate {
+            name: name.to_string(),
+            colour: None,
+        })
+    }
+
+    pub fn deactivate(self, name: &str) -> UMLBuilder {
+        self.token(UMLToken::Deactivate {
+            name: name.to_string(),
+            colour: None,
+        })
+    }
+
+    /// Adds a `loop` run `count` times, with the contents built by `f`.
+    pub fn loop_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self, count: u32, f: F) -> UMLBuilder {
+        self.token(UMLToken::Loop {
+            sequence: f(UMLBuilder::new()).into_tokens(),
+            count: LoopCount::Times(count),
+        })
+    }
+
+    /// Adds an `opt` block, with the contents built by `f`.
+    pub fn opt_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self,
+                                                          label: Option<&str>,
+                                                          f: F)
+                                                          -> UMLBuilder {
+        self.token(UMLToken::Opt {
+            label: label.map(|label| label.to_string()),
+            sequence: f(UMLBuilder::new()).into_tokens(),
+        })
+    }
+
+    /// Adds a `critical` block, with the contents built by `f`.
+    pub fn critical_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self,
+                                                               label: Option<&str>,
+                                                               f: F)
+                                                               -> UMLBuilder {
+        self.token(UMLToken::Critical {
+            label: label.map(|label| label.to_string()),
+            sequence: f(UMLBuilder::new()).into_tokens(),
+        })
+    }
+
+    /// Adds a `break` block, with the contents built by `f`.
+    pub fn break_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self,
+                                                            label: Option<&str>,
+                                                            f: F)
+                                                            -> UMLBuilder {
+        self.token(UMLToken::Break {
+            label: label.map(|label| label.to_string()),
+            sequence: f(UMLBuilder::new()).into_tokens(),
+        })
+    }
+
+    /// Adds a `group` block, with the contents built by `f`.
+    pub fn group_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self, label: &str, f: F) -> UMLBuilder {
+        self.token(UMLToken::Group {
+            label: label.to_string(),
+            sequence: f(UMLBuilder::new()).into_tokens(),
+        })
+    }
+
+    /// Adds a `box` grouping participants, with the contents built by `f`.
+    pub fn box_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self, name: &str, f: F) -> UMLBuilder {
+        self.token(UMLToken::Box {
+            name: name.to_string(),
+            colour: None,
+            sequence: f(UMLBuilder::new()).into_tokens(),
+        })
+    }
+
+    /// Adds an `alt` block with a single branch built by `f`.  Further
+    /// branches are added with `else_block`.
+    pub fn alt_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self,
+                                                          label: Option<&str>,
+                                                          f: F)
+                                                          -> UMLBuilder {
+        self.token(UMLToken::Alt {
+            sequences: vec![f(UMLBuilder::new()).into_tokens()],
+            labels: vec![label.map(|label| label.to_string())],
+        })
+    }
+
+    /// Adds a `par` block with a single branch built by `f`.  Further
+    /// branches are added with `else_block`.
+    pub fn par_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(self,
+                                                          label: Option<&str>,
+                                                          f: F)
+                                                          -> UMLBuilder {
+        self.token(UMLToken::Parallel {
+            sequences: vec![f(UMLBuilder::new()).into_tokens()],
+            labels: vec![label.map(|label| label.to_string())],
+        })
+    }
+
+    /// Adds an `else` branch built by `f` to the `alt` or `par` just added.
+    ///
+    /// # Panics
+    ///
+    /// If the last statement added isn't an `alt` or `par`.
+    pub fn else_block<F: FnOnce(UMLBuilder) -> UMLBuilder>(mut self,
+                                                           label: Option<&str>,
+                                                           f: F)
+                                                           -> UMLBuilder {
+        let sequence = f(UMLBuilder::new()).into_tokens();
+
+        match self.tokens.last_mut() {
+            Some(&mut UMLToken::Alt {
+                ref mut sequences,
+                ref mut labels,
+            }) |
+            Some(&mut UMLToken::Parallel {
+                ref mut sequences,
+                ref mut labels,
+            }) => {
+                sequences.push(sequence);
+                labels.push(label.map(|label| label.to_string()));
+            }
+            _ => panic!("else_block must follow alt_block or par_block"),
+        }
+
+        self
+    }
+
+    /// Returns the diagram, failing with `UMLError::Unbracketed` unless it
+    /// starts with `start`, ends with `end`, and has neither anywhere else.
+    pub fn build(self) -> Result<UMLTokens, UMLError> {
+        let uml_tokens = self.into_tokens();
+
+        let starts = uml_tokens.find_all(|token| matches!(*token, UMLToken::StartUML { .. }));
+        let ends = uml_tokens.find_all(|token| *token == UMLToken::EndUML);
+        let bracketed = starts.len() == 1 && ends.len() == 1 &&
+                        matches!(uml_tokens.tokens.first(), Some(&UMLToken::StartUML { .. })) &&
+                        uml_tokens.tokens.last() == Some(&UMLToken::EndUML);
+
+        if bracketed {
+            Ok(uml_tokens)
+        } else {
+            Err(UMLError::Unbracketed)
+        }
+    }
+
+    fn into_tokens(self) -> UMLTokens {
+        UMLTokens::new(self.tokens)
+    }
+}
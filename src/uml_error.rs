@@ -0,0 +1,198 @@
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::path::PathBuf;
+use loop_count;
+
+/// Errors that can occur while parsing UML.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UMLError {
+    /// A statement could not be parsed.  `line` and `column` are 1-based and
+    /// give where parsing stopped, and `snippet` is the offending line.
+    Parse {
+        line: usize,
+        column: usize,
+        snippet: String,
+    },
+    /// A `loop`, `opt`, `critical`, `break`, `group`, `alt`, `par` or `box`
+    /// block that is never closed.
+    /// `start_offset` is the byte offset of its opening keyword.
+    UnterminatedBlock {
+        keyword: String,
+        start_offset: usize,
+    },
+    /// A file or directory couldn't be read.
+    Io { path: PathBuf, message: String },
+    /// A diagram built with `UMLBuilder` that isn't bracketed by a single
+    /// `@startuml` and `@enduml`.
+    Unbracketed,
+    /// A file that `!include`s itself, directly or through other files.
+    /// `path` is the included file that closes the cycle.
+    IncludeCycle { path: PathBuf },
+    /// An error in a particular file.
+    File {
+        path: PathBuf,
+        error: Box<UMLError>,
+    },
+}
+
+impl UMLError {
+    /// Build the error for a statement starting at `offset` bytes into `input`
+    /// that failed to parse: an `UnterminatedBlock` if it opens a block that is
+    /// never closed, and a `Parse` error otherwise.
+    pub(crate) fn parse_at(input: &str, offset: usize) -> UMLError {
+        let start = offset + input[offset..].len() - input[offset..].trim_start().len();
+        if let Some(keyword) = unterminated_block(&input[start..]) {
+            return UMLError::UnterminatedBlock {
+                keyword: keyword.to_string(),
+                start_offset: start,
+            };
+        }
+
+        let line_start = input[..offset].rfind('\n').map_or(0, |index| index + 1);
+        let line_end = input[offset..].find('\n').map_or(input.len(), |index| offset + index);
+        let stopped = line_end - input[offset..line_end].trim_start().len();
+
+        UMLError::Parse {
+            line: input[..offset].matches('\n').count() + 1,
+            column: input[line_start..stopped].chars().count() + 1,
+            snippet: input[line_start..line_end].trim().to_string(),
+        }
+    }
+
+    #[cfg(feature = "fs")]
+    pub(crate) fn io(path: &Path, error: &std::io::Error) -> UMLError {
+        UMLError::Io {
+            path: path.to_path_buf(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// The keywords that open a block closed by `end`.  Notes are included so that
+/// the `end note` closing a note within a block isn't mistaken for the end of
+/// the block.
+const BLOCK_KEYWORDS: [&str; 9] =
+    ["loop", "opt", "critical", "break", "group", "alt", "par", "box", "note"];
+
+/// The entry in `BLOCK_KEYWORDS` matching `word` in any case, if any.
+fn block_keyword(word: &str) -> Option<&'static str> {
+    BLOCK_KEYWORDS.iter().find(|keyword| keyword.eq_ignore_ascii_case(word)).cloned()
+}
+
+/// `word` in lower case if it is `end` or one of `BLOCK_KEYWORDS`, as written
+/// otherwise.
+fn normalise_keyword(word: &str) -> &str {
+    if word.eq_ignore_ascii_case("end") {
+        "end"
+    } else {
+        block_keyword(word).unwrap_or(word)
+    }
+}
+
+/// If `input` starts with a block that is never closed, returns its keyword.
+fn unterminated_block(input: &str) -> Option<&'static str> {
+    let header = input.lines().next().unwrap_or("").trim_start();
+    let first = header.split_whitespace().next().unwrap_or("");
+    let opener = block_keyword(first).filter(|&opener| opener != "note")?;
+
+    // A loop without a valid count or label is reported as a bad statement
+    // instead.
+    if opener == "loop" && loop_count(&header[first.len()..]).is_err() {
+        return None;
+    }
+
+    match block_len(input) {
+        Some(_) => None,
+        None => Some(opener),
+    }
+}
+
+/// If `input` starts with a block that is closed, returns its length up to
+/// the end of the line that closes it.
+///
+/// Blocks nested inside it are tracked so that their `end`s aren't counted.
+/// An `end` naming a block closes the nearest open block of that kind, so an
+/// inner block left open by an `end` for the outer one leaves the outer block
+/// unclosed too.
+pub(crate) fn block_len(input: &str) -> Option<usize> {
+    let first = input.split_whitespace().next().unwrap_or("");
+    block_keyword(first).filter(|&opener| opener != "note")?;
+
+    let mut open = Vec::new();
+    let mut len = 0;
+    for line in input.split_inclusive('\n') {
+        len += line.len();
+        let mut words = line.split_whitespace().map(normalise_keyword);
+        let keyword = words.next().unwrap_or("");
+
+        if open.last() == Some(&"note") {
+            if keyword == "end" && words.next() == Some("note") {
+                open.pop();
+            }
+        } else if keyword == "end" {
+            match words.next() {
+                Some(kind) if BLOCK_KEYWORDS.contains(&kind) => {
+                    match open.iter().rposition(|open| *open == kind) {
+                        Some(0) => return Some(len),
+                        Some(index) => open.truncate(index),
+                        None => return None,
+                    }
+                }
+                _ => {
+                    open.pop();
+                    if open.is_empty() {
+                        return Some(len);
+                    }
+                }
+            }
+        } else if BLOCK_KEYWORDS.contains(&keyword) && !(keyword == "note" && line.contains(':')) {
+            open.push(keyword);
+        }
+    }
+
+    None
+}
+
+impl fmt::Display for UMLError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UMLError::Parse {
+                line,
+                column,
+                ref snippet,
+            } => write!(f, "Failed to parse line {}, column {}: {}", line, column, snippet),
+            UMLError::UnterminatedBlock {
+                ref keyword,
+                start_offset,
+            } => {
+                write!(f,
+                       "Unterminated {} block starting at byte {}",
+                       keyword,
+                       start_offset)
+            }
+            UMLError::Unbracketed => {
+                write!(f, "Diagram isn't bracketed by @startuml and @enduml")
+            }
+            UMLError::Io {
+                ref path,
+                ref message,
+            } => write!(f, "Failed to read {:?}: {}", path, message),
+            UMLError::IncludeCycle { ref path } => write!(f, "{:?} includes itself", path),
+            UMLError::File {
+                ref path,
+                ref error,
+            } => write!(f, "{:?}: {}", path, error),
+        }
+    }
+}
+
+impl Error for UMLError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            UMLError::File { ref error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
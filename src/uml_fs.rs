@@ -0,0 +1,176 @@
+//! File system access: reading diagrams from disk and resolving `!include`
+//! directives.  Only built with the `fs` feature.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use {parse_complete, parse_uml_with_state, preprocess, ParseState, ParserConfig, UMLError,
+     UMLTokens};
+
+/// Parse a UML file and return the `UMLTokens` that were parsed.
+///
+/// If `path` is given, `file` is found relative to it rather than to the
+/// current directory.  Each included file is found relative to the directory
+/// of the file that includes it.  The current directory is never changed, so
+/// files can safely be parsed on several threads at once.
+///
+/// Fails if the file, or any file it includes, can't be read or parsed.
+pub fn parse_uml_file(file: &str,
+                      path: Option<&std::path::Path>)
+                      -> Result<UMLTokens, UMLError> {
+    let file_path = match path {
+        Some(path) => path.join(file),
+        None => PathBuf::from(file),
+    };
+
+    read_uml_file(&file_path, &ParseState::default())
+}
+
+/// Parse a UML file included from the file `state` is for, or the top level
+/// file if `state` isn't for a file, parsing it as set out in `state`.
+///
+/// A file that can't be read gives a `UMLError::Io`, and one that can't be
+/// parsed a `UMLError::File` wrapping the reason.
+fn read_uml_file(file_path: &Path, state: &ParseState) -> Result<UMLTokens, UMLError> {
+    let mut uml = String::new();
+    File::open(file_path)
+        .and_then(|mut f| f.read_to_string(&mut uml))
+        .map_err(|error| UMLError::io(file_path, &error))?;
+
+    // Strip out any \r characters from the file to cope with DOS line endings.
+    uml = preprocess(&uml.replace("\r", ""));
+
+    info!("Parsing {:?}", file_path);
+    let state = state.for_file(file_path);
+    let uml_tokens = parse_complete(&uml, &state).map_err(|error| {
+        UMLError::File {
+            path: file_path.to_path_buf(),
+            error: Box::new(error),
+        }
+    })?;
+    info!("Done parsing {:?}", file_path);
+
+    Ok(uml_tokens)
+}
+
+/// Parse every `.uml` file in a directory, returning the tokens for each file
+/// keyed by its path.
+///
+/// Subdirectories are only searched if `cfg.recursive` is set.  If a file
+/// can't be read or parsed, the error is returned (wrapped in
+/// `UMLError::File`) unless `cfg.continue_on_error` is set, in which case the
+/// file is skipped.
+pub fn parse_uml_dir(dir: &Path,
+                     cfg: &ParserConfig)
+                     -> Result<HashMap<PathBuf, UMLTokens>, UMLError> {
+    let mut diagrams = HashMap::new();
+    add_uml_dir(dir, cfg, &mut diagrams)?;
+
+    Ok(diagrams)
+}
+
+fn add_uml_dir(dir: &Path,
+               cfg: &ParserConfig,
+               diagrams: &mut HashMap<PathBuf, UMLTokens>)
+               -> Result<(), UMLError> {
+    let entries = std::fs::read_dir(dir).map_err(|error| UMLError::io(dir, &error))?;
+
+    for entry in entries {
+        let path = entry.map_err(|error| UMLError::io(dir, &error))?.path();
+
+        if path.is_dir() {
+            if cfg.recursive {
+                add_uml_dir(&path, cfg, diagrams)?;
+            }
+        } else if path.extension().is_some_and(|extension| extension == "uml") {
+            match parse_path(&path, cfg) {
+                Ok(tokens) => {
+                    diagrams.insert(path, tokens);
+                }
+                Err(error) => {
+                    if !cfg.continue_on_error {
+                        return Err(error);
+                    }
+                    warn!("Skipping {:?}: {}", path, error);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read and parse a single file, failing if any of it can't be parsed.
+fn parse_path(path: &Path, cfg: &ParserConfig) -> Result<UMLTokens, UMLError> {
+    let uml = std::fs::read_to_string(path).map_err(|error| UMLError::io(path, &error))?;
+
+    info!("Parsing {:?}", path);
+    let state = ParseState::new(cfg).for_file(path);
+    let (tokens, errors, _) = parse_uml_with_state(&uml, cfg, &state);
+
+    match errors.into_iter().next() {
+        Some(error) => {
+            Err(UMLError::File {
+                path: path.to_path_buf(),
+                error: Box::new(error),
+            })
+        }
+        None => Ok(tokens),
+    }
+}
+
+/// Read and parse the file named by an `!include` directive, relative to the
+/// directory of the file that includes it, if any.
+///
+/// A file that has already been read for the diagram is skipped by
+/// `!include_once`, giving an empty sequence, while a plain `!include` of a
+/// file that includes it is an `UMLError::IncludeCycle`.
+pub(crate) fn load_include(file: &str,
+                           once: bool,
+                           state: &ParseState)
+                           -> Result<UMLTokens, UMLError> {
+    let file_path = match state.base {
+        Some(ref base) => base.join(file),
+        None => PathBuf::from(file),
+    };
+
+    let resolved = resolve(&file_path);
+    if once && state.included.borrow().contains(&resolved) {
+        return Ok(UMLTokens::new(Vec::new()));
+    }
+    if state.ancestors.contains(&resolved) {
+        return Err(UMLError::IncludeCycle { path: file_path });
+    }
+
+    read_uml_file(&file_path, state)
+}
+
+/// The path that identifies a file however it is named, so that repeated and
+/// cyclic includes are found.  A path that can't be resolved, e.g. because
+/// the file doesn't exist, is used as it is.
+fn resolve(file_path: &Path) -> PathBuf {
+    std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf())
+}
+
+impl ParseState {
+    /// The state for parsing `file_path`, which is included by the file this
+    /// state is for, if any.  The file is recorded as read for the diagram.
+    fn for_file(&self, file_path: &Path) -> ParseState {
+        let resolved = resolve(file_path);
+        self.included.borrow_mut().insert(resolved.clone());
+
+        let mut ancestors = self.ancestors.clone();
+        ancestors.push(resolved);
+
+        ParseState {
+            base: file_path.parent().map(Path::to_path_buf),
+            included: self.included.clone(),
+            ancestors,
+            preserve_blank_lines: self.preserve_blank_lines,
+            skip_includes: self.skip_includes,
+            include_error: RefCell::new(None),
+        }
+    }
+}
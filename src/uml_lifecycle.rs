@@ -0,0 +1,51 @@
+//! The lifecycle of a single participant: when it is created, activated,
+//! deactivated and destroyed.
+
+use {UMLToken, UMLTokens};
+
+/// A change in a participant's lifecycle.  Each event holds the index of the
+/// token that caused it, counting tokens in the order `UMLTokens::walk` visits
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Created(usize),
+    Activated(usize),
+    Deactivated(usize),
+    Destroyed(usize),
+}
+
+impl UMLTokens {
+    /// Returns the `create`, `activate`, `deactivate` and `destroy` events for
+    /// `participant`, in document order.  A message to `participant` with a
+    /// `**` after its name also creates it.
+    pub fn lifecycle(&self, participant: &str) -> Vec<LifecycleEvent> {
+        let mut events = Vec::new();
+        let mut index = 0;
+
+        self.walk(&mut |token| {
+            let event = match *token {
+                UMLToken::Create { ref name } |
+                UMLToken::Message {
+                    to: ref name,
+                    creates_target: true,
+                    ..
+                } if name == participant => Some(LifecycleEvent::Created(index)),
+                UMLToken::Activate { ref name, .. } if name == participant => {
+                    Some(LifecycleEvent::Activated(index))
+                }
+                UMLToken::Deactivate { ref name, .. } if name == participant => {
+                    Some(LifecycleEvent::Deactivated(index))
+                }
+                UMLToken::Destroy { ref name } if name == participant => {
+                    Some(LifecycleEvent::Destroyed(index))
+                }
+                _ => None,
+            };
+
+            events.extend(event);
+            index += 1;
+        });
+
+        events
+    }
+}
@@ -0,0 +1,596 @@
+use {Arrow, ArrowDirection, ArrowHead, ArrowStyle, LoopCount, NotePosition, ParticipantKind,
+     UMLError, UMLToken, UMLTokens};
+
+impl UMLTokens {
+    /// Render the diagram as a Mermaid `sequenceDiagram`.
+    ///
+    /// Tokens that Mermaid has no equivalent for are emitted as `%%` comments.
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = "sequenceDiagram\n".to_string();
+        write_mermaid(self, 1, &mut mermaid);
+
+        mermaid
+    }
+}
+
+fn write_mermaid(sequence: &UMLTokens, depth: usize, mermaid: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for token in &sequence.tokens {
+        match *token {
+            UMLToken::StartUML { .. } | UMLToken::EndUML | UMLToken::Blank => {}
+
+            // Mermaid only has participants and actors.
+            UMLToken::Participant {
+                kind,
+                ref long_name,
+                ref short_name,
+                ..
+            } => {
+                let keyword = match kind {
+                    ParticipantKind::Actor => "actor",
+                    _ => "participant",
+                };
+                mermaid.push_str(&format!("{}{} {}", indent, keyword, short_name));
+                if let Some(ref long_name) = *long_name {
+                    mermaid.push_str(&format!(" as {}", long_name.trim_matches('"')));
+                }
+                mermaid.push('\n');
+            }
+
+            UMLToken::Message {
+                ref from,
+                ref to,
+                ref text,
+                ref arrow,
+                ..
+            } => {
+                mermaid.push_str(&format!("{}{}{}{}:", indent, from, mermaid_arrow(arrow), to));
+                if let Some(ref text) = *text {
+                    mermaid.push_str(&format!(" {}", mermaid_text(text)));
+                }
+                mermaid.push('\n');
+            }
+
+            UMLToken::Note {
+                ref position,
+                ref text,
+            } => {
+                let side = match *position {
+                    NotePosition::Left(Some(_)) => Some("left of"),
+                    NotePosition::Right(Some(_)) => Some("right of"),
+                    NotePosition::Over(_) => Some("over"),
+                    _ => None,
+                };
+
+                if let Some(side) = side {
+                    let participants = position.participants();
+                    mermaid.push_str(&format!("{}Note {} {}: {}\n",
+                                              indent,
+                                              side,
+                                              participants.join(","),
+                                              mermaid_text(text)));
+                } else {
+                    mermaid.push_str(&format!("{}%% note {}: {}\n",
+                                              indent,
+                                              position,
+                                              mermaid_text(text)));
+                }
+            }
+
+            UMLToken::SplitNote { ref notes } => {
+                write_mermaid(&UMLTokens::new(notes.clone()), depth, mermaid);
+            }
+
+            UMLToken::Activate { ref name, .. } => {
+                mermaid.push_str(&format!("{}activate {}\n", indent, name));
+            }
+
+            UMLToken::Deactivate { ref name, .. } => {
+                mermaid.push_str(&format!("{}deactivate {}\n", indent, name));
+            }
+
+            UMLToken::Create { ref name } => {
+                mermaid.push_str(&format!("{}%% create {}\n", indent, name));
+            }
+
+            UMLToken::Destroy { ref name } => {
+                mermaid.push_str(&format!("{}%% destroy {}\n", indent, name));
+            }
+
+            UMLToken::Divider { ref text } => {
+                mermaid.push_str(&format!("{}%% == {} ==\n", indent, text));
+            }
+
+            UMLToken::Title { ref text } => {
+                mermaid.push_str(&format!("{}title {}\n", indent, mermaid_text(text)));
+            }
+
+            UMLToken::NewPage { ref title } => {
+                match *title {
+                    Some(ref title) => mermaid.push_str(&format!("{}%% newpage {}\n", indent, title)),
+                    None => mermaid.push_str(&format!("{}%% newpage\n", indent)),
+                }
+            }
+
+            UMLToken::Comment { ref text, .. } => {
+                for line in text.trim().lines() {
+                    mermaid.push_str(&format!("{}%% {}\n", indent, line.trim()));
+                }
+            }
+
+            UMLToken::Delay { ref text, .. } => {
+                mermaid.push_str(&format!("{}%% delay {}\n", indent, text));
+            }
+
+            UMLToken::Ref {
+                ref participants,
+                ref text,
+            } => {
+                mermaid.push_str(&format!("{}%% ref over {}: {}\n",
+                                          indent,
+                                          participants.join(","),
+                                          mermaid_text(text)));
+            }
+
+            UMLToken::Autonumber {
+                start: None,
+                stop: false,
+                inc: None,
+                format: None,
+            } => mermaid.push_str(&format!("{}autonumber\n", indent)),
+
+            UMLToken::Autonumber { .. } => {
+                mermaid.push_str(&format!("{}%% {}", indent, token));
+            }
+
+            UMLToken::Loop {
+                ref sequence,
+                ref count,
+            } => {
+                mermaid.push_str(&format!("{}loop {}\n", indent, count));
+                write_mermaid(sequence, depth + 1, mermaid);
+                mermaid.push_str(&format!("{}end\n", indent));
+            }
+
+            UMLToken::Opt {
+                ref label,
+                ref sequence,
+            } => {
+                match *label {
+                    Some(ref label) => {
+                        mermaid.push_str(&format!("{}opt {}\n", indent, mermaid_text(label)))
+                    }
+                    None => mermaid.push_str(&format!("{}opt\n", indent)),
+                }
+                write_mermaid(sequence, depth + 1, mermaid);
+                mermaid.push_str(&format!("{}end\n", indent));
+            }
+
+            UMLToken::Critical {
+                ref label,
+                ref sequence,
+            } => {
+                match *label {
+                    Some(ref label) => {
+                        mermaid.push_str(&format!("{}critical {}\n", indent, mermaid_text(label)))
+                    }
+                    None => mermaid.push_str(&format!("{}critical\n", indent)),
+                }
+                write_mermaid(sequence, depth + 1, mermaid);
+                mermaid.push_str(&format!("{}end\n", indent));
+            }
+
+            UMLToken::Break {
+                ref label,
+                ref sequence,
+            } => {
+                match *label {
+                    Some(ref label) => {
+                        mermaid.push_str(&format!("{}break {}\n", indent, mermaid_text(label)))
+                    }
+                    None => mermaid.push_str(&format!("{}break\n", indent)),
+                }
+                write_mermaid(sequence, depth + 1, mermaid);
+                mermaid.push_str(&format!("{}end\n", indent));
+            }
+
+            // Mermaid has no plain grouping, so just the contents are kept.
+            UMLToken::Group {
+                ref label,
+                ref sequence,
+            } => {
+                mermaid.push_str(&format!("{}%% group {}\n", indent, label));
+                write_mermaid(sequence, depth, mermaid);
+                mermaid.push_str(&format!("{}%% end group\n", indent));
+            }
+
+            UMLToken::Box {
+                ref name,
+                ref colour,
+                ref sequence,
+            } => {
+                let mut header = vec!["box".to_string()];
+                if let Some(ref colour) = *colour {
+                    header.push(mermaid_colour(colour));
+                }
+                if !name.is_empty() {
+                    header.push(name.trim_matches('"').to_string());
+                }
+                mermaid.push_str(&format!("{}{}\n", indent, header.join(" ")));
+                write_mermaid(sequence, depth + 1, mermaid);
+                mermaid.push_str(&format!("{}end\n", indent));
+            }
+
+            UMLToken::Parallel {
+                ref sequences,
+                ref labels,
+            } => write_branches(sequences, labels, "par", "and", depth, mermaid),
+
+            UMLToken::Alt {
+                ref sequences,
+                ref labels,
+            } => write_branches(sequences, labels, "alt", "else", depth, mermaid),
+
+            UMLToken::Include { ref sequence, .. } => write_mermaid(sequence, depth, mermaid),
+        }
+    }
+}
+
+/// Mermaid labels must fit on one line, so line breaks are written as `<br/>`.
+fn mermaid_text(text: &str) -> String {
+    text.replace('\n', "<br/>")
+}
+
+/// Returns a PlantUML colour, stored without its `#`, as Mermaid writes it.
+/// Colour names are the same in both, but a hex colour such as `FFAAAA` has
+/// to be written as `rgb(255,170,170)`.
+fn mermaid_colour(colour: &str) -> String {
+    let digits: Vec<u32> = match colour.chars().map(|c| c.to_digit(16)).collect() {
+        Some(digits) => digits,
+        None => return colour.to_string(),
+    };
+    let channels: Vec<u32> = match digits.len() {
+        3 => digits.iter().map(|digit| digit * 17).collect(),
+        6 => digits.chunks(2).map(|pair| pair[0] * 16 + pair[1]).collect(),
+        _ => return colour.to_string(),
+    };
+
+    format!("rgb({},{},{})", channels[0], channels[1], channels[2])
+}
+
+/// Undoes `mermaid_text`, accepting any of the spellings of `<br>`.
+fn from_mermaid_text(text: &str) -> String {
+    text.replace("<br/>", "\n").replace("<br />", "\n").replace("<br>", "\n")
+}
+
+/// Returns the Mermaid arrow closest to `arrow`.  Mermaid has no circle heads,
+/// and its bidirectional arrows always have filled heads.
+fn mermaid_arrow(arrow: &Arrow) -> String {
+    let line = match arrow.style {
+        ArrowStyle::Solid => "-",
+        ArrowStyle::Dashed => "--",
+    };
+
+    if arrow.direction == ArrowDirection::Both {
+        return format!("<<{}>>", line);
+    }
+
+    let head = match arrow.head {
+        ArrowHead::Filled | ArrowHead::Circle => ">>",
+        ArrowHead::Open => ")",
+        ArrowHead::Cross => "x",
+    };
+
+    format!("{}{}", line, head)
+}
+
+fn write_branches(sequences: &[UMLTokens],
+                  labels: &[Option<String>],
+                  keyword: &str,
+                  separator: &str,
+                  depth: usize,
+                  mermaid: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for (index, sequence) in sequences.iter().enumerate() {
+        let keyword = if index == 0 { keyword } else { separator };
+        match labels.get(index) {
+            Some(Some(label)) => {
+                mermaid.push_str(&format!("{}{} {}\n", indent, keyword, mermaid_text(label)))
+            }
+            _ => mermaid.push_str(&format!("{}{}\n", indent, keyword)),
+        }
+        write_mermaid(sequence, depth + 1, mermaid);
+    }
+
+    mermaid.push_str(&format!("{}end\n", indent));
+}
+
+/// Parse a Mermaid `sequenceDiagram` into `UMLTokens`.
+///
+/// The supported subset is: a `title`, `participant`/`actor` declarations
+/// (with an optional `as` alias), messages with `->>`, `-x`, `-)` and `<<->>`
+/// arrows or their dotted forms, `Note left of`/`right of`/`over`,
+/// `activate`/`deactivate`, a bare `autonumber`, `loop` blocks with a count
+/// or label, `opt`, `critical`, `break`, `alt`/`else` and `par`/`and` blocks
+/// with optional labels, and `%%` comments, which are dropped.  Anything else,
+/// including arrows without heads, `rect`, `box` and the `option` branches of
+/// a `critical`, results in a `UMLError::Parse` for the offending line.
+pub fn from_mermaid(input: &str) -> Result<UMLTokens, UMLError> {
+    let lines: Vec<Line> = input.lines()
+        .enumerate()
+        .map(|(index, line)| {
+            let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+            ((index + 1, indent + 1), line.trim())
+        })
+        .filter(|&(_, line)| !line.is_empty() && !line.starts_with("%%"))
+        .collect();
+
+    match lines.first() {
+        Some(&(_, "sequenceDiagram")) => {}
+        Some(&(line, snippet)) => return Err(mermaid_error(line, snippet)),
+        None => return Err(mermaid_error((1, 1), "")),
+    }
+
+    let mut index = 1;
+    let (tokens, end) = parse_sequence(&lines, &mut index)?;
+    if let Some((line, snippet)) = end {
+        return Err(mermaid_error(line, snippet));
+    }
+
+    Ok(tokens)
+}
+
+/// The 1-based line number of a source line and the column its text starts at.
+type Position = (usize, usize);
+
+/// A non-empty source line, trimmed, and its position.
+type Line<'a> = (Position, &'a str);
+
+fn mermaid_error((line, column): Position, snippet: &str) -> UMLError {
+    UMLError::Parse {
+        line,
+        column,
+        snippet: snippet.to_string(),
+    }
+}
+
+/// Parses lines into a sequence until a block terminator (`end`, `else` or
+/// `and`) or the end of the input, returning the terminating line if there
+/// was one.
+fn parse_sequence<'a>(lines: &[Line<'a>],
+                      index: &mut usize)
+                      -> Result<(UMLTokens, Option<Line<'a>>), UMLError> {
+    let mut tokens = Vec::new();
+
+    while *index < lines.len() {
+        let (line, text) = lines[*index];
+        let (keyword, rest) = match text.find(' ') {
+            Some(split) => (&text[..split], text[split..].trim()),
+            None => (text, ""),
+        };
+        *index += 1;
+
+        let token = match keyword {
+            "end" | "else" | "and" => return Ok((UMLTokens::new(tokens), Some((line, text)))),
+
+            "participant" | "actor" if !rest.is_empty() => {
+                let kind = if keyword == "actor" {
+                    ParticipantKind::Actor
+                } else {
+                    ParticipantKind::Participant
+                };
+                let (short_name, long_name) = match rest.find(" as ") {
+                    Some(split) => {
+                        (rest[..split].trim(),
+                         Some(format!("\"{}\"", rest[split + " as ".len()..].trim())))
+                    }
+                    None => (rest, None),
+                };
+
+                UMLToken::Participant {
+                    kind,
+                    long_name,
+                    short_name: short_name.to_string(),
+                    stereotype: None,
+                    colour: None,
+                    order: None,
+                }
+            }
+
+            "activate" if !rest.is_empty() => {
+                UMLToken::Activate {
+                    name: rest.to_string(),
+                    colour: None,
+                }
+            }
+
+            "deactivate" if !rest.is_empty() => {
+                UMLToken::Deactivate {
+                    name: rest.to_string(),
+                    colour: None,
+                }
+            }
+
+            "title" if !rest.is_empty() => UMLToken::Title { text: from_mermaid_text(rest) },
+
+            "autonumber" if rest.is_empty() => {
+                UMLToken::Autonumber {
+                    start: None,
+                    stop: false,
+                    inc: None,
+                    format: None,
+                }
+            }
+
+            "Note" | "note" => parse_note(rest).ok_or_else(|| mermaid_error(line, text))?,
+
+            "loop" => {
+                let count = LoopCount::parse(rest).ok_or_else(|| mermaid_error(line, text))?;
+                let sequence = parse_block(lines, index, line, text, &[])?.0.remove(0);
+
+                UMLToken::Loop { sequence, count }
+            }
+
+            "opt" => {
+                let (mut sequences, mut labels) = parse_block(lines, index, line, text, &[])?;
+
+                UMLToken::Opt {
+                    label: labels.remove(0),
+                    sequence: sequences.remove(0),
+                }
+            }
+
+            "critical" => {
+                let (mut sequences, mut labels) = parse_block(lines, index, line, text, &[])?;
+
+                UMLToken::Critical {
+                    label: labels.remove(0),
+                    sequence: sequences.remove(0),
+                }
+            }
+
+            "break" => {
+                let (mut sequences, mut labels) = parse_block(lines, index, line, text, &[])?;
+
+                UMLToken::Break {
+                    label: labels.remove(0),
+                    sequence: sequences.remove(0),
+                }
+            }
+
+            "alt" => {
+                let (sequences, labels) = parse_block(lines, index, line, text, &["else"])?;
+                UMLToken::Alt { sequences, labels }
+            }
+
+            "par" => {
+                let (sequences, labels) = parse_block(lines, index, line, text, &["and"])?;
+                UMLToken::Parallel { sequences, labels }
+            }
+
+            _ => parse_message(text).ok_or_else(|| mermaid_error(line, text))?,
+        };
+
+        tokens.push(token);
+    }
+
+    Ok((UMLTokens::new(tokens), None))
+}
+
+/// Parses the branches of a block up to its `end`, where each of `separators`
+/// starts a new branch, returning them with the label of each: the text after
+/// the keyword that starts it.
+fn parse_block(lines: &[Line],
+               index: &mut usize,
+               start_line: Position,
+               start_text: &str,
+               separators: &[&str])
+               -> Result<(Vec<UMLTokens>, Vec<Option<String>>), UMLError> {
+    let mut sequences = Vec::new();
+    let mut labels = vec![block_label(start_text)];
+
+    loop {
+        let (sequence, end) = parse_sequence(lines, index)?;
+        sequences.push(sequence);
+
+        match end {
+            Some((_, "end")) => return Ok((sequences, labels)),
+            Some((_, text)) if separators.iter().any(|separator| {
+                text.split(' ').next() == Some(separator)
+            }) => labels.push(block_label(text)),
+            Some((line, text)) => return Err(mermaid_error(line, text)),
+            None => return Err(mermaid_error(start_line, start_text)),
+        }
+    }
+}
+
+/// The text after the keyword of a block line, if any.
+fn block_label(text: &str) -> Option<String> {
+    text.find(' ')
+        .map(|split| from_mermaid_text(text[split..].trim()))
+        .filter(|label| !label.is_empty())
+}
+
+/// Parses the remainder of a `Note` line, e.g. `over A,B: text`.
+fn parse_note(rest: &str) -> Option<UMLToken> {
+    let colon = rest.find(':')?;
+    let (position, text) = (rest[..colon].trim(), rest[colon + 1..].trim());
+
+    let position = if let Some(participants) = position.strip_prefix("over ") {
+        let participants: Vec<String> =
+            participants.split(',').map(|name| name.trim().to_string()).collect();
+        if participants.iter().any(|name| name.is_empty()) {
+            return None;
+        }
+        NotePosition::Over(participants)
+    } else if let Some(name) = position.strip_prefix("left of ") {
+        NotePosition::Left(Some(note_target(name)?))
+    } else if let Some(name) = position.strip_prefix("right of ") {
+        NotePosition::Right(Some(note_target(name)?))
+    } else {
+        return None;
+    };
+
+    Some(UMLToken::Note {
+        position,
+        text: from_mermaid_text(text),
+    })
+}
+
+/// The single participant a `left of` or `right of` note is attached to.
+fn note_target(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty() || name.contains(',') {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// The Mermaid arrows that `from_mermaid` accepts, longest first so that a
+/// line is matched against `-->>` before `->>`.
+const MERMAID_ARROWS: [(&str, ArrowDirection, ArrowStyle, ArrowHead); 8] =
+    [("<<-->>", ArrowDirection::Both, ArrowStyle::Dashed, ArrowHead::Filled),
+     ("<<->>", ArrowDirection::Both, ArrowStyle::Solid, ArrowHead::Filled),
+     ("-->>", ArrowDirection::ToRight, ArrowStyle::Dashed, ArrowHead::Filled),
+     ("->>", ArrowDirection::ToRight, ArrowStyle::Solid, ArrowHead::Filled),
+     ("--x", ArrowDirection::ToRight, ArrowStyle::Dashed, ArrowHead::Cross),
+     ("-x", ArrowDirection::ToRight, ArrowStyle::Solid, ArrowHead::Cross),
+     ("--)", ArrowDirection::ToRight, ArrowStyle::Dashed, ArrowHead::Open),
+     ("-)", ArrowDirection::ToRight, ArrowStyle::Solid, ArrowHead::Open)];
+
+/// Parses a message such as `A->>B: text` or `A--xB`.
+fn parse_message(line: &str) -> Option<UMLToken> {
+    let end = line.find(':').unwrap_or(line.len());
+    let (index, &(spelling, direction, style, head)) = line[..end]
+        .char_indices()
+        .filter_map(|(index, _)| {
+            MERMAID_ARROWS.iter()
+                .find(|&&(spelling, ..)| line[index..end].starts_with(spelling))
+                .map(|arrow| (index, arrow))
+        })
+        .next()?;
+
+    let from = line[..index].trim();
+    let to = line[index + spelling.len()..end].trim();
+    let text = line[end..].trim_start_matches(':').trim();
+
+    if from.is_empty() || to.is_empty() {
+        return None;
+    }
+
+    Some(UMLToken::Message {
+        from: from.to_string(),
+        to: to.to_string(),
+        text: if text.is_empty() { None } else { Some(from_mermaid_text(text)) },
+        colour: None,
+        arrow: Arrow {
+            direction,
+            style,
+            head,
+        },
+        creates_target: false,
+    })
+}
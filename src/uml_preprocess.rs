@@ -0,0 +1,158 @@
+//! The preprocessor pass run over a diagram before it is parsed: `!$`
+//! variable definitions and `!if` / `!else` / `!endif` conditional blocks.
+//!
+//! A variable is defined with `!$name = value`, where the value is a quoted
+//! string or a bare word.  A condition is one of:
+//!
+//! - `operand`, which is true unless it is empty, `0` or `false`
+//! - `!operand`, the negation of the above
+//! - `operand == operand` or `operand != operand`, comparing text
+//!
+//! where an operand is a `$name` variable (empty if undefined), a quoted
+//! string or a bare word.  The whole condition may be wrapped in parentheses.
+//! Conditional blocks may be nested, and definitions in a branch that isn't
+//! selected are ignored.
+//!
+//! Parse errors report line numbers in the preprocessed text.
+
+use std::collections::HashMap;
+
+/// An `!if` block being preprocessed.
+struct Conditional {
+    /// Whether the enclosing text is being kept.
+    outer: bool,
+    /// Whether the `!if` condition held.
+    condition: bool,
+    /// Whether an `!else` has been seen.
+    in_else: bool,
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.outer && (self.condition != self.in_else)
+    }
+}
+
+/// Evaluates the preprocessor directives in `uml`, returning the text of the
+/// selected branches with the directives themselves removed.  Text without
+/// any directives is returned unchanged, except that a missing newline at the
+/// end is added, as every statement is parsed up to a line ending.
+pub(crate) fn preprocess(uml: &str) -> String {
+    let mut variables = HashMap::new();
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    let mut output = String::with_capacity(uml.len());
+
+    for line in uml.split_inclusive('\n') {
+        let active = conditionals.last().is_none_or(|conditional| conditional.active());
+        let directive = line.trim();
+
+        if let Some(condition) = directive_argument(directive, "!if") {
+            conditionals.push(Conditional {
+                outer: active,
+                condition: active && evaluate(condition, &variables),
+                in_else: false,
+            });
+        } else if let (Some(""), Some(conditional)) =
+                      (directive_argument(directive, "!else"), conditionals.last_mut()) {
+            conditional.in_else = true;
+        } else if directive_argument(directive, "!endif") == Some("") &&
+                  !conditionals.is_empty() {
+            conditionals.pop();
+        } else if let Some(definition) = directive.strip_prefix("!$") {
+            if active {
+                if let Some(index) = definition.find('=') {
+                    variables.insert(definition[..index].trim().to_string(),
+                                     literal(definition[index + 1..].trim()).to_string());
+                }
+            }
+        } else if active {
+            output.push_str(line);
+        }
+    }
+
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Rewrites each single line loop, `loop <count>: <statement>`, as a loop
+/// block containing the statement.
+pub(crate) fn expand_compact_loops(uml: &str) -> String {
+    let mut output = String::with_capacity(uml.len());
+
+    for line in uml.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+
+        let compact = trimmed.strip_prefix("loop")
+            .filter(|rest| rest.starts_with(char::is_whitespace))
+            .and_then(|rest| rest.split_once(':'))
+            .filter(|&(count, body)| count.trim().parse::<u32>().is_ok() && !body.trim().is_empty());
+
+        match compact {
+            Some((count, body)) => {
+                output.push_str(&format!("{}loop {}\n{}{}\n{}end loop\n",
+                                         indent,
+                                         count.trim(),
+                                         indent,
+                                         body.trim(),
+                                         indent));
+            }
+            None => output.push_str(line),
+        }
+    }
+
+    output
+}
+
+/// If `line` is the directive `name`, returns the rest of the line.
+fn directive_argument<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(name)?;
+
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) || rest.starts_with('(') {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/// Strips the quotes from a quoted string.
+fn literal(text: &str) -> &str {
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        &text[1..text.len() - 1]
+    } else {
+        text
+    }
+}
+
+fn operand<'a>(text: &'a str, variables: &'a HashMap<String, String>) -> &'a str {
+    let text = text.trim();
+
+    match text.strip_prefix('$') {
+        Some(name) => variables.get(name).map_or("", |value| value.as_str()),
+        None => literal(text),
+    }
+}
+
+fn evaluate(condition: &str, variables: &HashMap<String, String>) -> bool {
+    let mut condition = condition.trim();
+    if condition.starts_with('(') && condition.ends_with(')') {
+        condition = &condition[1..condition.len() - 1];
+    }
+
+    if let Some(index) = condition.find("==") {
+        operand(&condition[..index], variables) == operand(&condition[index + 2..], variables)
+    } else if let Some(index) = condition.find("!=") {
+        operand(&condition[..index], variables) != operand(&condition[index + 2..], variables)
+    } else if let Some(negated) = condition.trim_start().strip_prefix('!') {
+        !truthy(operand(negated, variables))
+    } else {
+        truthy(operand(condition, variables))
+    }
+}
+
+fn truthy(value: &str) -> bool {
+    !(value.is_empty() || value == "0" || value == "false")
+}
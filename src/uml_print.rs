@@ -1,44 +1,196 @@
-use {UMLToken, UMLTokens};
+use uml_arrow::format_arrow;
+use {ArrowDirection, LoopCount, NotePosition, Stereotype, UMLToken, UMLTokens};
 use std::fmt;
-use std::ops::Deref;
 
-impl fmt::Display for UMLTokens {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+/// The line ending to use when writing a diagram out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+/// How `!include`s are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncludeMode {
+    /// Just the `!include` directive.
+    Directive,
+    /// The included tokens, in place of the directive.
+    #[default]
+    Inline,
+    /// The directive as a `'` comment, followed by the included tokens.
+    Both,
+}
+
+/// Options for writing a diagram out.  The defaults match `Display`.
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    pub line_ending: LineEnding,
+    pub includes: IncludeMode,
+    /// The number of spaces to indent the contents of each block by.
+    pub indent: usize,
+}
+
+impl LineEnding {
+    /// The line ending used by some existing text, based on its first line.
+    pub fn detect(text: &str) -> LineEnding {
+        match text.find('\n') {
+            Some(index) if text[..index].ends_with('\r') => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        }
+    }
+}
+
+impl UMLTokens {
+    /// Like `to_string`, but with the given line ending after every line.
+    pub fn to_string_with_line_ending(&self, eol: LineEnding) -> String {
+        self.to_string_with_options(&PrintOptions {
+            line_ending: eol,
+            ..PrintOptions::default()
+        })
+    }
+
+    /// Like `to_string`, but with the contents of each block indented by
+    /// `spaces` more than the block's own keywords.
+    pub fn to_string_indented(&self, spaces: usize) -> String {
+        self.to_string_with_options(&PrintOptions {
+            indent: spaces,
+            ..PrintOptions::default()
+        })
+    }
+
+    /// Like `to_string`, but written out as `options` specify.
+    pub fn to_string_with_options(&self, options: &PrintOptions) -> String {
+        let uml = self.to_uml(options);
+
+        match options.line_ending {
+            LineEnding::Lf => uml,
+            LineEnding::CrLf => uml.replace('\n', "\r\n"),
+        }
+    }
+
+    fn to_uml(&self, options: &PrintOptions) -> String {
         let mut tokens_str = String::new();
 
         for token in &self.tokens {
-            tokens_str.push_str(&format!("{}", token));
+            tokens_str.push_str(&token.to_uml(options));
+        }
+
+        tokens_str
+    }
+
+    /// Writes the tokens out as the contents of a block, indented by
+    /// `options.indent`.
+    fn to_uml_nested(&self, options: &PrintOptions) -> String {
+        let uml = self.to_uml(options);
+
+        if options.indent == 0 {
+            return uml;
         }
 
-        write!(f, "{}", tokens_str)
+        let indent = " ".repeat(options.indent);
+
+        uml.lines()
+            .map(|line| if line.is_empty() { "\n".to_string() } else { format!("{}{}\n", indent, line) })
+            .collect()
+    }
+}
+
+impl fmt::Display for UMLTokens {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_uml(&PrintOptions::default()))
+    }
+}
+
+impl fmt::Display for Stereotype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.spot {
+            Some((character, ref colour)) if self.text.is_empty() => {
+                write!(f, "({},#{})", character, colour)
+            }
+            Some((character, ref colour)) => write!(f, "({},#{}) {}", character, colour, self.text),
+            None => write!(f, "{}", self.text),
+        }
+    }
+}
+
+impl fmt::Display for LoopCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoopCount::Times(count) => write!(f, "{}", count),
+            LoopCount::Label(ref label) => write!(f, "{}", label),
+        }
+    }
+}
+
+impl fmt::Display for NotePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NotePosition::Left(None) => write!(f, "left"),
+            NotePosition::Left(Some(ref name)) => write!(f, "left of {}", name),
+            NotePosition::Right(None) => write!(f, "right"),
+            NotePosition::Right(Some(ref name)) => write!(f, "right of {}", name),
+            NotePosition::Over(ref names) => write!(f, "over {}", names.join(", ")),
+            NotePosition::Other(ref text) => write!(f, "{}", text),
+        }
     }
 }
 
 impl fmt::Display for UMLToken {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_uml(&PrintOptions::default()))
+    }
+}
 
-        let uml_str = match *self {
-            UMLToken::StartUML => "@startuml\n".to_string(),
+impl UMLToken {
+    /// Writes the token out with `\n` line endings; `options.line_ending` is
+    /// applied to the whole diagram afterwards.
+    fn to_uml(&self, options: &PrintOptions) -> String {
+        match *self {
+            UMLToken::StartUML { ref name } => {
+                match *name {
+                    Some(ref name) => format!("@startuml {}\n", name),
+                    None => "@startuml\n".to_string(),
+                }
+            }
 
             UMLToken::EndUML => "@enduml\n".to_string(),
 
+            UMLToken::Blank => "\n".to_string(),
+
             UMLToken::Note {
                 ref position,
                 ref text,
             } => format!("note {}\n{}\nend note\n", position, text),
 
-            UMLToken::Parallel { ref sequences } => {
-                let mut par_str = "par\n".to_string();
-                let mut first_loop = true;
+            UMLToken::SplitNote { ref notes } => {
+                let mut notes_str = String::new();
 
-                for sequence in sequences.deref() {
-                    if !first_loop {
-                        par_str.push_str("else\n");
+                for (index, note) in notes.iter().enumerate() {
+                    if index > 0 {
+                        notes_str.push_str("/ ");
                     }
 
-                    par_str.push_str(&format!("{}", sequence));
+                    notes_str.push_str(&note.to_uml(options));
+                }
+
+                notes_str
+            }
+
+            UMLToken::Parallel {
+                ref sequences,
+                ref labels,
+            } => {
+                let mut par_str = String::new();
 
-                    first_loop = false;
+                for (index, sequence) in sequences.iter().enumerate() {
+                    let keyword = if index == 0 { "par" } else { "else" };
+                    match labels.get(index) {
+                        Some(Some(label)) => par_str.push_str(&format!("{} {}\n", keyword, label)),
+                        _ => par_str.push_str(&format!("{}\n", keyword)),
+                    }
+
+                    par_str.push_str(&sequence.to_uml_nested(options));
                 }
 
                 par_str.push_str("end par\n");
@@ -46,18 +198,20 @@ impl fmt::Display for UMLToken {
                 par_str
             }
 
-            UMLToken::Alt { ref sequences } => {
-                let mut par_str = "alt\n".to_string();
-                let mut first_loop = true;
+            UMLToken::Alt {
+                ref sequences,
+                ref labels,
+            } => {
+                let mut par_str = String::new();
 
-                for sequence in sequences.deref() {
-                    if !first_loop {
-                        par_str.push_str("else\n");
+                for (index, sequence) in sequences.iter().enumerate() {
+                    let keyword = if index == 0 { "alt" } else { "else" };
+                    match labels.get(index) {
+                        Some(Some(label)) => par_str.push_str(&format!("{} {}\n", keyword, label)),
+                        _ => par_str.push_str(&format!("{}\n", keyword)),
                     }
 
-                    par_str.push_str(&format!("{}", sequence));
-
-                    first_loop = false;
+                    par_str.push_str(&sequence.to_uml_nested(options));
                 }
 
                 par_str.push_str("end alt\n");
@@ -70,46 +224,84 @@ impl fmt::Display for UMLToken {
                 ref to,
                 ref text,
                 ref colour,
+                ref arrow,
+                creates_target,
             } => {
-                let seperator = match *colour {
-                    Some(ref colour) => format!("-[#{}]>", colour),
-                    None => "->".to_string(),
+                let to = if creates_target { format!("{} **", to) } else { to.clone() };
+
+                // Left pointing arrows are written with the receiver first.
+                let (left, right) = match arrow.direction {
+                    ArrowDirection::ToLeft => (&to, from),
+                    _ => (from, &to),
                 };
 
-                let mut msg_str = format!("{}{}{}", from, seperator, to);
+                let mut msg_str = format!("{}{}{}", left, format_arrow(arrow, colour), right);
 
                 if let Some(ref text) = *text {
-                    msg_str.push_str(&format!(":{}", text))
+                    // Multi-line labels are written with continuation lines.
+                    msg_str.push_str(&format!(":{}", text.replace('\n', "\\\n")))
                 }
 
-                msg_str.push_str("\n");
+                msg_str.push('\n');
 
                 msg_str
             }
 
             UMLToken::Participant {
+                kind,
                 ref long_name,
                 ref short_name,
+                ref stereotype,
+                ref colour,
+                ref order,
             } => {
                 let (name1, name2) = match *long_name {
                     Some(ref name) => (name.to_string(), Some(short_name.to_string())),
                     None => (short_name.to_string(), None),
                 };
 
-                let mut participant_str = format!("participant {}", name1);
+                let mut participant_str = format!("{} {}", kind.keyword(), name1);
+
+                if let Some(name2) = name2 {
+                    participant_str.push_str(&format!(" as {}", name2));
+                }
+
+                if let Some(ref stereotype) = *stereotype {
+                    participant_str.push_str(&format!(" <<{}>>", stereotype));
+                }
+
+                if let Some(ref colour) = *colour {
+                    participant_str.push_str(&format!(" #{}", colour));
+                }
 
-                if name2.is_some() {
-                    participant_str.push_str(&format!(" as {}", name2.unwrap()));
+                if let Some(ref order) = *order {
+                    participant_str.push_str(&format!(" order {}", order));
                 }
 
-                participant_str.push_str("\n");
+                participant_str.push('\n');
 
                 participant_str
             }
 
-            UMLToken::Activate { ref name } => format!("activate {}\n", name),
+            UMLToken::Activate {
+                ref name,
+                ref colour,
+            } => {
+                match *colour {
+                    Some(ref colour) => format!("activate {} #{}\n", name, colour),
+                    None => format!("activate {}\n", name),
+                }
+            }
 
-            UMLToken::Deactivate { ref name } => format!("deactivate {}\n", name),
+            UMLToken::Deactivate {
+                ref name,
+                ref colour,
+            } => {
+                match *colour {
+                    Some(ref colour) => format!("deactivate {} #{}\n", name, colour),
+                    None => format!("deactivate {}\n", name),
+                }
+            }
 
             UMLToken::Loop {
                 ref sequence,
@@ -117,33 +309,183 @@ impl fmt::Display for UMLToken {
             } => {
                 let mut loop_str = format!("loop {}\n", count);
 
-                loop_str.push_str(&format!("{}", sequence));
+                loop_str.push_str(&sequence.to_uml_nested(options));
 
                 loop_str.push_str("end loop\n");
 
                 loop_str
             }
 
+            UMLToken::Opt {
+                ref label,
+                ref sequence,
+            } => {
+                let mut opt_str = match *label {
+                    Some(ref label) => format!("opt {}\n", label),
+                    None => "opt\n".to_string(),
+                };
+
+                opt_str.push_str(&sequence.to_uml_nested(options));
+
+                opt_str.push_str("end opt\n");
+
+                opt_str
+            }
+
+            UMLToken::Critical {
+                ref label,
+                ref sequence,
+            } => {
+                let mut critical_str = match *label {
+                    Some(ref label) => format!("critical {}\n", label),
+                    None => "critical\n".to_string(),
+                };
+
+                critical_str.push_str(&sequence.to_uml_nested(options));
+
+                critical_str.push_str("end critical\n");
+
+                critical_str
+            }
+
+            UMLToken::Break {
+                ref label,
+                ref sequence,
+            } => {
+                let mut break_str = match *label {
+                    Some(ref label) => format!("break {}\n", label),
+                    None => "break\n".to_string(),
+                };
+
+                break_str.push_str(&sequence.to_uml_nested(options));
+
+                break_str.push_str("end break\n");
+
+                break_str
+            }
+
+            UMLToken::Group {
+                ref label,
+                ref sequence,
+            } => {
+                let mut group_str = format!("group {}\n", label);
+
+                group_str.push_str(&sequence.to_uml_nested(options));
+
+                group_str.push_str("end group\n");
+
+                group_str
+            }
+
             UMLToken::Box {
                 ref name,
+                ref colour,
                 ref sequence,
             } => {
-                let mut box_str = format!("box {}\n", name);
+                let mut box_str = "box".to_string();
+
+                if !name.is_empty() {
+                    box_str.push_str(&format!(" {}", name));
+                }
+
+                if let Some(ref colour) = *colour {
+                    box_str.push_str(&format!(" #{}", colour));
+                }
 
-                box_str.push_str(&format!("{}", sequence));
+                box_str.push('\n');
+
+                box_str.push_str(&sequence.to_uml_nested(options));
 
                 box_str.push_str("end box\n");
 
                 box_str
             }
 
-            UMLToken::Include { ref sequence, .. } => format!("{}", sequence),
+            UMLToken::Include {
+                ref file,
+                ref sequence,
+                once,
+            } => {
+                let file = if file.contains(char::is_whitespace) {
+                    format!("\"{}\"", file)
+                } else {
+                    file.clone()
+                };
+                let directive = format!("!include{} {}\n", if once { "_once" } else { "" }, file);
+
+                match options.includes {
+                    IncludeMode::Directive => directive,
+                    IncludeMode::Inline => sequence.to_uml(options),
+                    IncludeMode::Both => format!("' {}{}", directive, sequence.to_uml(options)),
+                }
+            }
+
+            UMLToken::Create { ref name } => format!("create {}\n", name),
 
             UMLToken::Destroy { ref name } => format!("destroy {}\n", name),
 
-            UMLToken::Delay { ref text } => format!("delay {}\n", text),
-        };
+            UMLToken::Delay { ref text, .. } => format!("delay {}\n", text),
+
+            UMLToken::Divider { ref text } => format!("== {} ==\n", text),
+
+            UMLToken::Title { ref text } => format!("title {}\n", text),
 
-        write!(f, "{}", uml_str)
+            UMLToken::NewPage { ref title } => {
+                match *title {
+                    Some(ref title) => format!("newpage {}\n", title),
+                    None => "newpage\n".to_string(),
+                }
+            }
+
+            UMLToken::Comment {
+                ref text,
+                block: false,
+            } => format!("'{}\n", text),
+
+            UMLToken::Comment {
+                ref text,
+                block: true,
+            } => format!("/'{}'/\n", text),
+
+            UMLToken::Autonumber {
+                ref start,
+                ref stop,
+                ref inc,
+                ref format,
+            } => {
+                let mut autonumber_str = "autonumber".to_string();
+
+                if *stop {
+                    autonumber_str.push_str(" stop");
+                }
+
+                if let Some(ref inc) = *inc {
+                    autonumber_str.push_str(&format!(" inc {}", inc));
+                }
+
+                if let Some(ref start) = *start {
+                    autonumber_str.push_str(&format!(" {}", start));
+                }
+
+                if let Some(ref format) = *format {
+                    autonumber_str.push_str(&format!(" \"{}\"", format));
+                }
+
+                autonumber_str.push('\n');
+
+                autonumber_str
+            }
+
+            UMLToken::Ref {
+                ref participants,
+                ref text,
+            } => {
+                if text.contains('\n') {
+                    format!("ref over {}\n{}\nend ref\n", participants.join(", "), text)
+                } else {
+                    format!("ref over {} : {}\n", participants.join(", "), text)
+                }
+            }
+        }
     }
 }
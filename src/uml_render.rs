@@ -0,0 +1,314 @@
+use {UMLToken, UMLTokens};
+
+/// A flat, layout-oriented projection of a diagram for rendering frontends.
+///
+/// Participants are resolved to lanes numbered in order of first appearance,
+/// fragments are numbered in document order, and every event records the
+/// lanes it touches and the innermost fragment it belongs to.  With the
+/// `serde` feature enabled the model can be serialized directly, e.g. with
+/// `serde_json::to_string(&tokens.to_render_model())`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RenderModel {
+    pub lanes: Vec<Lane>,
+    pub groups: Vec<Group>,
+    pub events: Vec<Event>,
+}
+
+/// A participant lifeline.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Lane {
+    pub index: usize,
+    pub name: String,
+    /// The long name, if the participant was declared with one.
+    pub label: Option<String>,
+    /// The `box` group the participant was declared in, if any.
+    pub group: Option<usize>,
+}
+
+/// A fragment (`loop`, `opt`, `critical`, `break`, `group`, `alt`, `par` or
+/// `box`).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Group {
+    pub id: usize,
+    pub kind: String,
+    pub label: Option<String>,
+    pub parent: Option<usize>,
+}
+
+/// A single renderable event, in document order.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Event {
+    /// The innermost fragment containing the event, if any.
+    pub group: Option<usize>,
+    /// The branch of that fragment; always 0 except within `alt` and `par`.
+    pub branch: usize,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum EventKind {
+    Message {
+        from: usize,
+        to: usize,
+        text: Option<String>,
+    },
+    Note { lanes: Vec<usize>, text: String },
+    Activate { lane: usize },
+    Deactivate { lane: usize },
+    Create { lane: usize },
+    Destroy { lane: usize },
+    Delay { text: String, amount: Option<u32> },
+    Divider { text: String },
+    Ref { lanes: Vec<usize>, text: String },
+}
+
+impl UMLTokens {
+    /// Build the `RenderModel` for this diagram.
+    pub fn to_render_model(&self) -> RenderModel {
+        let mut model = RenderModel {
+            lanes: Vec::new(),
+            groups: Vec::new(),
+            events: Vec::new(),
+        };
+
+        model.add_sequence(self, None, 0);
+
+        model
+    }
+}
+
+impl RenderModel {
+    fn lane(&mut self, name: &str) -> usize {
+        if let Some(lane) = self.lanes.iter().find(|lane| lane.name == name) {
+            return lane.index;
+        }
+
+        let index = self.lanes.len();
+        self.lanes.push(Lane {
+            index,
+            name: name.to_string(),
+            label: None,
+            group: None,
+        });
+
+        index
+    }
+
+    fn add_group(&mut self, kind: &str, label: Option<String>, parent: Option<usize>) -> usize {
+        let id = self.groups.len();
+        self.groups.push(Group {
+            id,
+            kind: kind.to_string(),
+            label,
+            parent,
+        });
+
+        id
+    }
+
+    fn add_event(&mut self, kind: EventKind, group: Option<usize>, branch: usize) {
+        self.events.push(Event {
+            group,
+            branch,
+            kind,
+        });
+    }
+
+    fn add_sequence(&mut self, sequence: &UMLTokens, group: Option<usize>, branch: usize) {
+        for token in &sequence.tokens {
+            self.add_token(token, group, branch);
+        }
+    }
+
+    fn add_token(&mut self, token: &UMLToken, group: Option<usize>, branch: usize) {
+        match *token {
+            UMLToken::StartUML { .. } |
+            UMLToken::EndUML |
+            UMLToken::Blank |
+            UMLToken::Autonumber { .. } |
+            UMLToken::Title { .. } |
+            UMLToken::NewPage { .. } |
+            UMLToken::Comment { .. } => {}
+
+            UMLToken::Participant {
+                ref long_name,
+                ref short_name,
+                ..
+            } => {
+                let index = self.lane(short_name);
+                let box_group = group.filter(|&id| self.groups[id].kind == "box");
+                let lane = &mut self.lanes[index];
+
+                if long_name.is_some() {
+                    lane.label = long_name.clone();
+                }
+                if lane.group.is_none() {
+                    lane.group = box_group;
+                }
+            }
+
+            UMLToken::Message {
+                ref from,
+                ref to,
+                ref text,
+                ..
+            } => {
+                let from = self.lane(from);
+                let to = self.lane(to);
+                self.add_event(EventKind::Message {
+                                   from,
+                                   to,
+                                   text: text.clone(),
+                               },
+                               group,
+                               branch);
+            }
+
+            UMLToken::Note {
+                ref position,
+                ref text,
+            } => {
+                let lanes = position.participants()
+                    .into_iter()
+                    .map(|name| self.lane(name))
+                    .collect();
+                self.add_event(EventKind::Note {
+                                   lanes,
+                                   text: text.clone(),
+                               },
+                               group,
+                               branch);
+            }
+
+            UMLToken::SplitNote { ref notes } => {
+                for note in notes {
+                    self.add_token(note, group, branch);
+                }
+            }
+
+            UMLToken::Activate { ref name, .. } => {
+                let lane = self.lane(name);
+                self.add_event(EventKind::Activate { lane }, group, branch);
+            }
+
+            UMLToken::Deactivate { ref name, .. } => {
+                let lane = self.lane(name);
+                self.add_event(EventKind::Deactivate { lane }, group, branch);
+            }
+
+            UMLToken::Create { ref name } => {
+                let lane = self.lane(name);
+                self.add_event(EventKind::Create { lane }, group, branch);
+            }
+
+            UMLToken::Destroy { ref name } => {
+                let lane = self.lane(name);
+                self.add_event(EventKind::Destroy { lane }, group, branch);
+            }
+
+            UMLToken::Delay { ref text, amount } => {
+                self.add_event(EventKind::Delay {
+                                   text: text.clone(),
+                                   amount,
+                               },
+                               group,
+                               branch);
+            }
+
+            UMLToken::Divider { ref text } => {
+                self.add_event(EventKind::Divider { text: text.clone() }, group, branch);
+            }
+
+            UMLToken::Ref {
+                ref participants,
+                ref text,
+            } => {
+                let lanes = participants.iter().map(|name| self.lane(name)).collect();
+                self.add_event(EventKind::Ref {
+                                   lanes,
+                                   text: text.clone(),
+                               },
+                               group,
+                               branch);
+            }
+
+            UMLToken::Loop {
+                ref sequence,
+                ref count,
+            } => {
+                let id = self.add_group("loop", Some(count.to_string()), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Opt {
+                ref label,
+                ref sequence,
+            } => {
+                let id = self.add_group("opt", label.clone(), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Critical {
+                ref label,
+                ref sequence,
+            } => {
+                let id = self.add_group("critical", label.clone(), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Break {
+                ref label,
+                ref sequence,
+            } => {
+                let id = self.add_group("break", label.clone(), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Group {
+                ref label,
+                ref sequence,
+            } => {
+                let id = self.add_group("group", Some(label.clone()), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Box {
+                ref name,
+                ref sequence,
+                ..
+            } => {
+                let id = self.add_group("box", Some(name.clone()), group);
+                self.add_sequence(sequence, Some(id), 0);
+            }
+
+            UMLToken::Parallel {
+                ref sequences,
+                ref labels,
+            } => {
+                let id = self.add_group("par", labels.first().cloned().unwrap_or(None), group);
+                for (branch, sequence) in sequences.iter().enumerate() {
+                    self.add_sequence(sequence, Some(id), branch);
+                }
+            }
+
+            UMLToken::Alt {
+                ref sequences,
+                ref labels,
+            } => {
+                let id = self.add_group("alt", labels.first().cloned().unwrap_or(None), group);
+                for (branch, sequence) in sequences.iter().enumerate() {
+                    self.add_sequence(sequence, Some(id), branch);
+                }
+            }
+
+            UMLToken::Include { ref sequence, .. } => self.add_sequence(sequence, group, branch),
+        }
+    }
+}
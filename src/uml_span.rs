@@ -0,0 +1,170 @@
+//! Mapping source offsets back to tokens, for editor integrations.
+
+use nom::IResult;
+use {note_parser, uml_statement, ParseState, UMLToken, UMLTokens};
+
+/// The result of searching a sequence for an offset: either the token found
+/// and its path, or the offset the sequence ends at.
+enum Search<'a> {
+    Found(&'a UMLToken, Vec<usize>),
+    End(usize),
+}
+
+impl UMLTokens {
+    /// Returns the innermost token whose source covers byte `offset` of
+    /// `source`, along with its path: the index of each enclosing token in its
+    /// sequence, with `par` and `alt` also contributing the index of the
+    /// branch.
+    ///
+    /// `source` must be the text these tokens were parsed from, after any
+    /// `!if` and `!$` preprocessing and with any `\r` characters removed.
+    /// Tokens spliced in by `!include` aren't in `source`, so an offset in an
+    /// include directive finds the `Include`; the included files aren't read
+    /// again.  Empty lines are matched to `UMLToken::Blank` tokens if the
+    /// tokens were parsed with `ParserConfig::preserve_blank_lines`.
+    pub fn token_at_offset(&self, source: &str, offset: usize) -> Option<(&UMLToken, Vec<usize>)> {
+        // Statements are only parsed again to find where they end, so includes
+        // are left unread and empty lines within blocks always parse.
+        let state = ParseState {
+            preserve_blank_lines: true,
+            skip_includes: true,
+            ..ParseState::default()
+        };
+
+        match search_sequence(self, source.as_bytes(), 0, offset, &state) {
+            Search::Found(token, path) => Some((token, path)),
+            Search::End(_) => None,
+        }
+    }
+}
+
+/// Skips spaces and tabs, but not newlines.
+fn skip_spaces(source: &[u8], mut position: usize) -> usize {
+    while position < source.len() && (source[position] == b' ' || source[position] == b'\t') {
+        position += 1;
+    }
+
+    position
+}
+
+/// Skips whitespace, including newlines.
+fn skip_whitespace(source: &[u8], mut position: usize) -> usize {
+    while position < source.len() && source[position].is_ascii_whitespace() {
+        position += 1;
+    }
+
+    position
+}
+
+/// Returns the offset just after the line containing `position`.
+fn next_line(source: &[u8], position: usize) -> usize {
+    match source[position..].iter().position(|&c| c == b'\n') {
+        Some(index) => position + index + 1,
+        None => source.len(),
+    }
+}
+
+fn search_sequence<'a>(sequence: &'a UMLTokens,
+                       source: &[u8],
+                       mut position: usize,
+                       offset: usize,
+                       state: &ParseState)
+                       -> Search<'a> {
+    for (index, token) in sequence.tokens.iter().enumerate() {
+        let (start, end) = if let UMLToken::Blank = *token {
+            (skip_spaces(source, position), next_line(source, position))
+        } else {
+            let start = skip_whitespace(source, position);
+            match uml_statement(&source[start..], state) {
+                IResult::Done(rest, _) => (start, source.len() - rest.len()),
+                _ => return Search::End(start),
+            }
+        };
+
+        if start <= offset && offset < end {
+            return match search_token(token, source, start, offset, state) {
+                Some((token, mut path)) => {
+                    path.insert(0, index);
+                    Search::Found(token, path)
+                }
+                None => Search::Found(token, vec![index]),
+            };
+        }
+
+        position = end;
+    }
+
+    Search::End(position)
+}
+
+/// Searches within a token starting at `start` for a nested token covering
+/// `offset`.
+fn search_token<'a>(token: &'a UMLToken,
+                    source: &[u8],
+                    start: usize,
+                    offset: usize,
+                    state: &ParseState)
+                    -> Option<(&'a UMLToken, Vec<usize>)> {
+    match *token {
+        UMLToken::Loop { ref sequence, .. } |
+        UMLToken::Opt { ref sequence, .. } |
+        UMLToken::Critical { ref sequence, .. } |
+        UMLToken::Break { ref sequence, .. } |
+        UMLToken::Group { ref sequence, .. } |
+        UMLToken::Box { ref sequence, .. } => {
+            match search_sequence(sequence, source, next_line(source, start), offset, state) {
+                Search::Found(token, path) => Some((token, path)),
+                Search::End(_) => None,
+            }
+        }
+
+        UMLToken::Parallel { ref sequences, .. } |
+        UMLToken::Alt { ref sequences, .. } => {
+            let mut position = next_line(source, start);
+
+            for (branch, sequence) in sequences.iter().enumerate() {
+                if branch > 0 {
+                    let separator = skip_whitespace(source, position);
+                    if source[separator..].starts_with(b"else") {
+                        position = next_line(source, separator);
+                    }
+                }
+
+                match search_sequence(sequence, source, position, offset, state) {
+                    Search::Found(token, mut path) => {
+                        path.insert(0, branch);
+                        return Some((token, path));
+                    }
+                    Search::End(end) => position = end,
+                }
+            }
+
+            None
+        }
+
+        UMLToken::SplitNote { ref notes } => {
+            let mut position = start;
+
+            for (index, note) in notes.iter().enumerate() {
+                let mut note_start = skip_whitespace(source, position);
+                if index > 0 && source.get(note_start) == Some(&b'/') {
+                    note_start = skip_whitespace(source, note_start + 1);
+                }
+
+                let end = match note_parser(&source[note_start..]) {
+                    IResult::Done(rest, _) => source.len() - rest.len(),
+                    _ => return None,
+                };
+
+                if note_start <= offset && offset < end {
+                    return Some((note, vec![index]));
+                }
+                position = end;
+            }
+
+            None
+        }
+
+        _ => None,
+    }
+}
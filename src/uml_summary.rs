@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use uml_walk::nested_sequences;
+use {UMLToken, UMLTokens};
+
+impl UMLTokens {
+    /// A short human-readable outline of the diagram, for logging.
+    ///
+    /// This lists the participants in order of first use, the number of
+    /// tokens of each kind (including nested tokens) and every path of nested
+    /// fragments, e.g. `loop(5) > par[2]`.
+    pub fn summary(&self) -> String {
+        let mut counts = BTreeMap::new();
+        self.walk(&mut |token| *counts.entry(token.kind_name()).or_insert(0) += 1);
+
+        let participants: Vec<String> = self.participant_first_use()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let counts: Vec<String> = counts.iter()
+            .map(|(kind, count)| format!("{} {}", kind, count))
+            .collect();
+
+        let mut paths = Vec::new();
+        add_paths(self, &mut Vec::new(), &mut paths);
+        if paths.is_empty() {
+            paths.push("none".to_string());
+        }
+
+        format!("participants: {}\ntokens: {}\nstructure: {}\n",
+                participants.join(", "),
+                counts.join(", "),
+                paths.join(", "))
+    }
+}
+
+/// Appends every distinct maximal path of nested fragments within `sequence`
+/// to `paths`, where `path` holds the fragments enclosing it.
+fn add_paths(sequence: &UMLTokens, path: &mut Vec<String>, paths: &mut Vec<String>) {
+    for token in &sequence.tokens {
+        let (label, sequences) = match *token {
+            UMLToken::Loop { ref sequence, ref count } => {
+                (format!("loop({})", count), vec![sequence])
+            }
+            UMLToken::Include { ref sequence, ref file, .. } => {
+                (format!("include({})", file), vec![sequence])
+            }
+            UMLToken::Parallel { ref sequences, .. } => {
+                (format!("par[{}]", sequences.len()), sequences.iter().collect())
+            }
+            UMLToken::Alt { ref sequences, .. } => {
+                (format!("alt[{}]", sequences.len()), sequences.iter().collect())
+            }
+            _ => {
+                let sequences = nested_sequences(token);
+                if sequences.is_empty() {
+                    continue;
+                }
+                (token.kind_name().to_string(), sequences)
+            }
+        };
+
+        path.push(label);
+        let count = paths.len();
+        for sequence in sequences {
+            add_paths(sequence, path, paths);
+        }
+        let joined = path.join(" > ");
+        if paths.len() == count && !paths.contains(&joined) {
+            paths.push(joined);
+        }
+        path.pop();
+    }
+}
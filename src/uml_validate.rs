@@ -0,0 +1,405 @@
+//! Warnings about diagrams that parse but are likely to be mistakes.
+
+use std::collections::HashSet;
+use std::fmt;
+use uml_walk::{nested_sequences, referenced_participants};
+use {ParserConfig, UMLToken, UMLTokens};
+
+/// A likely mistake in a diagram that parsed successfully.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UMLWarning {
+    /// `participant` is activated within a `loop`, `opt` or `group`, or a
+    /// branch of an `alt` or `par`, and is still active at the end of it.  `fragment` is the kind
+    /// of fragment, as given by `UMLToken::kind_name`.
+    ActivationLeaksFragment {
+        participant: String,
+        fragment: String,
+    },
+    /// A note whose position isn't one PlantUML recognizes, accepted because
+    /// strict mode is off.  `line` is 1-based.
+    UnknownNotePosition { line: usize, position: String },
+    /// `participant` is deactivated when it isn't active.
+    DeactivateWithoutActivate { participant: String },
+    /// `participant` is still active at the end of the diagram.
+    ActivationNotEnded { participant: String },
+    /// `participant` is destroyed while it is still active.
+    DestroyedWhileActive { participant: String },
+    /// A message is sent to or from `participant` after it was destroyed.
+    MessageToDestroyed { participant: String },
+    /// `participant` is used without being declared.
+    UndeclaredParticipant { participant: String },
+    /// `participant` is declared but never used.
+    UnusedParticipant { participant: String },
+    /// A block with nothing in it.  `kind` is as given by
+    /// `UMLToken::kind_name`.
+    EmptyBlock { kind: String },
+}
+
+impl fmt::Display for UMLWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UMLWarning::ActivationLeaksFragment {
+                ref participant,
+                ref fragment,
+            } => {
+                write!(f,
+                       "{} is activated in a {} but not deactivated within it",
+                       participant,
+                       fragment)
+            }
+            UMLWarning::UnknownNotePosition {
+                line,
+                ref position,
+            } => write!(f, "Unknown note position on line {}: {}", line, position),
+            UMLWarning::DeactivateWithoutActivate { ref participant } => {
+                write!(f, "{} is deactivated but isn't active", participant)
+            }
+            UMLWarning::ActivationNotEnded { ref participant } => {
+                write!(f, "{} is never deactivated", participant)
+            }
+            UMLWarning::DestroyedWhileActive { ref participant } => {
+                write!(f, "{} is destroyed while active", participant)
+            }
+            UMLWarning::MessageToDestroyed { ref participant } => {
+                write!(f, "{} is used after being destroyed", participant)
+            }
+            UMLWarning::UndeclaredParticipant { ref participant } => {
+                write!(f, "{} is used without being declared", participant)
+            }
+            UMLWarning::UnusedParticipant { ref participant } => {
+                write!(f, "{} is declared but never used", participant)
+            }
+            UMLWarning::EmptyBlock { ref kind } => write!(f, "Empty {} block", kind),
+        }
+    }
+}
+
+/// Selects the checks run by `UMLTokens::validate_all`.  By default every
+/// check is enabled.
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    /// Every `deactivate` matches an earlier `activate`, and every
+    /// activation is ended before the participant is destroyed or the diagram
+    /// ends.  Each branch of an `alt` or `par` is checked separately.
+    pub activation_balance: bool,
+    /// Activations within a fragment end within it.
+    pub activation_leaks: bool,
+    /// No messages involve a participant after it is destroyed, unless it
+    /// is created again.
+    pub destroyed_participants: bool,
+    /// Every participant used is declared.
+    pub undeclared_participants: bool,
+    /// Every participant declared is used.
+    pub unused_participants: bool,
+    /// No `loop`, `opt`, `group`, `box`, `alt` or `par` is empty, as can happen
+    /// when tokens are built or filtered programmatically.
+    pub empty_blocks: bool,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> ValidationConfig {
+        ValidationConfig {
+            activation_balance: true,
+            activation_leaks: true,
+            destroyed_participants: true,
+            undeclared_participants: true,
+            unused_participants: true,
+            empty_blocks: true,
+        }
+    }
+}
+
+/// Warnings with the index of the token each was found at, counting tokens in
+/// the order `UMLTokens::walk` visits them.
+type Found = Vec<(usize, UMLWarning)>;
+
+impl UMLTokens {
+    /// Checks that activations are balanced, as described for
+    /// `ValidationConfig::activation_balance`, along with the checks enabled
+    /// in `cfg`, returning a warning for each problem found, in document order.
+    pub fn validate(&self, cfg: &ParserConfig) -> Vec<UMLWarning> {
+        self.validate_all(&ValidationConfig {
+            activation_balance: true,
+            activation_leaks: cfg.warn_activation_leaks,
+            destroyed_participants: false,
+            undeclared_participants: false,
+            unused_participants: false,
+            empty_blocks: false,
+        })
+    }
+
+    /// Runs every check enabled in `cfg`, returning each distinct warning
+    /// once, ordered by the position of the token it was first found at.
+    pub fn validate_all(&self, cfg: &ValidationConfig) -> Vec<UMLWarning> {
+        let mut found = Found::new();
+
+        if cfg.activation_balance {
+            activation_balance(self, &mut found);
+        }
+        if cfg.activation_leaks {
+            activation_spans(self, &mut 0, &mut found);
+        }
+        if cfg.destroyed_participants {
+            destroyed_participants(self, &mut found);
+        }
+        if cfg.undeclared_participants {
+            let undeclared = self.undeclared_participants();
+            found.extend(self.participant_first_use()
+                .into_iter()
+                .filter(|(name, _)| undeclared.contains(name))
+                .map(|(participant, index)| {
+                    (index, UMLWarning::UndeclaredParticipant { participant })
+                }));
+        }
+        if cfg.unused_participants {
+            unused_participants(self, &mut found);
+        }
+        if cfg.empty_blocks {
+            empty_blocks(self, &mut found);
+        }
+
+        found.sort_by_key(|&(index, _)| index);
+
+        let mut warnings: Vec<UMLWarning> = Vec::new();
+        for (_, warning) in found {
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+
+        warnings
+    }
+}
+
+fn activation_balance(uml_tokens: &UMLTokens, found: &mut Found) {
+    for (participant, index) in balance_sequence(uml_tokens, Vec::new(), &mut 0, found) {
+        found.push((index, UMLWarning::ActivationNotEnded { participant }));
+    }
+}
+
+/// Tracks activations through `sequence`, starting with those in `active`,
+/// and returns the activations left at the end of it.  Each activation is the
+/// participant with the index of its `activate`.
+///
+/// Each branch of an `alt` or `par` starts from the activations before it,
+/// and the block leaves each participant as many activations as the branch
+/// that leaves it the most.
+/// `index` is the index of the first token of `sequence`, and is advanced past
+/// the whole sequence.
+fn balance_sequence(sequence: &UMLTokens,
+                    mut active: Vec<(String, usize)>,
+                    index: &mut usize,
+                    found: &mut Found)
+                    -> Vec<(String, usize)> {
+    for token in &sequence.tokens {
+        let token_index = *index;
+        *index += 1;
+
+        match *token {
+            UMLToken::Activate { ref name, .. } => active.push((name.clone(), token_index)),
+
+            UMLToken::Deactivate { ref name, .. } => {
+                match active.iter().rposition(|(active, _)| active == name) {
+                    Some(position) => {
+                        active.remove(position);
+                    }
+                    None => {
+                        found.push((token_index,
+                                    UMLWarning::DeactivateWithoutActivate {
+                                        participant: name.clone(),
+                                    }))
+                    }
+                }
+            }
+
+            UMLToken::Destroy { ref name } => {
+                if active.iter().any(|(active, _)| active == name) {
+                    found.push((token_index,
+                                UMLWarning::DestroyedWhileActive { participant: name.clone() }));
+                }
+                active.retain(|(active, _)| active != name);
+            }
+
+            UMLToken::Parallel { ref sequences, .. } |
+            UMLToken::Alt { ref sequences, .. } => {
+                let mut left: Vec<(String, usize)> = Vec::new();
+                for sequence in sequences {
+                    let branch = balance_sequence(sequence, active.clone(), index, found);
+
+                    for (position, activation) in branch.iter().enumerate() {
+                        let count = |activations: &[(String, usize)]| {
+                            activations.iter().filter(|(name, _)| *name == activation.0).count()
+                        };
+                        if count(&left) < count(&branch[..=position]) {
+                            left.push(activation.clone());
+                        }
+                    }
+                }
+
+                if !sequences.is_empty() {
+                    left.sort_by_key(|&(_, index)| index);
+                    active = left;
+                }
+            }
+
+            UMLToken::SplitNote { ref notes } => *index += notes.len(),
+
+            _ => {
+                for sequence in nested_sequences(token) {
+                    active = balance_sequence(sequence, active, index, found);
+                }
+            }
+        }
+    }
+
+    active
+}
+
+/// Tracks activations through `sequence`, returning each participant left
+/// active by it with the number of unmatched activations.  A deactivation
+/// with no matching activation in the sequence is assumed to end an
+/// activation from outside it.
+///
+/// `index` is the index of the first token of `sequence`, and is advanced
+/// past the whole sequence.
+fn activation_spans(sequence: &UMLTokens,
+                    index: &mut usize,
+                    found: &mut Found)
+                    -> Vec<(String, usize)> {
+    let mut active: Vec<(String, usize)> = Vec::new();
+
+    for token in &sequence.tokens {
+        let token_index = *index;
+        let mut leaked = Vec::new();
+        *index += 1;
+
+        match *token {
+            UMLToken::Activate { ref name, .. } => leaked.push((name.clone(), 1)),
+
+            UMLToken::Deactivate { ref name, .. } => {
+                if let Some(position) = active.iter().position(|(active, _)| active == name) {
+                    active[position].1 -= 1;
+                    if active[position].1 == 0 {
+                        active.remove(position);
+                    }
+                }
+            }
+
+            UMLToken::Loop { ref sequence, .. } |
+            UMLToken::Opt { ref sequence, .. } |
+            UMLToken::Critical { ref sequence, .. } |
+            UMLToken::Break { ref sequence, .. } |
+            UMLToken::Group { ref sequence, .. } => {
+                leaked = activation_spans(sequence, index, found);
+                fragment_leaks(&leaked, token_index, token.kind_name(), found);
+            }
+
+            UMLToken::Parallel { ref sequences, .. } |
+            UMLToken::Alt { ref sequences, .. } => {
+                for sequence in sequences {
+                    let branch_leaked = activation_spans(sequence, index, found);
+                    fragment_leaks(&branch_leaked, token_index, token.kind_name(), found);
+                    leaked.extend(branch_leaked);
+                }
+            }
+
+            UMLToken::SplitNote { ref notes } => *index += notes.len(),
+
+            _ => {
+                for sequence in nested_sequences(token) {
+                    leaked.extend(activation_spans(sequence, index, found));
+                }
+            }
+        }
+
+        for (name, count) in leaked {
+            match active.iter_mut().find(|(active, _)| *active == name) {
+                Some(active) => active.1 += count,
+                None => active.push((name, count)),
+            }
+        }
+    }
+
+    active
+}
+
+/// Warns about each activation left by the fragment at `index`.
+fn fragment_leaks(leaked: &[(String, usize)], index: usize, fragment: &str, found: &mut Found) {
+    for (participant, _) in leaked {
+        found.push((index,
+                    UMLWarning::ActivationLeaksFragment {
+                        participant: participant.clone(),
+                        fragment: fragment.to_string(),
+                    }));
+    }
+}
+
+fn destroyed_participants(uml_tokens: &UMLTokens, found: &mut Found) {
+    let mut destroyed = HashSet::new();
+    let mut index = 0;
+
+    uml_tokens.walk(&mut |token| {
+        match *token {
+            UMLToken::Destroy { ref name } => {
+                destroyed.insert(name.as_str());
+            }
+            UMLToken::Create { ref name } => {
+                destroyed.remove(name.as_str());
+            }
+            UMLToken::Message {
+                ref from,
+                ref to,
+                creates_target,
+                ..
+            } => {
+                if creates_target {
+                    destroyed.remove(to.as_str());
+                }
+                for name in &[from, to] {
+                    if destroyed.contains(name.as_str()) {
+                        found.push((index,
+                                    UMLWarning::MessageToDestroyed {
+                                        participant: name.to_string(),
+                                    }));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        index += 1;
+    });
+}
+
+fn unused_participants(uml_tokens: &UMLTokens, found: &mut Found) {
+    let mut used = HashSet::new();
+    uml_tokens.walk(&mut |token| used.extend(referenced_participants(token)));
+
+    let mut index = 0;
+    uml_tokens.walk(&mut |token| {
+        if let UMLToken::Participant { ref short_name, .. } = *token {
+            if !used.contains(short_name.as_str()) {
+                found.push((index,
+                            UMLWarning::UnusedParticipant { participant: short_name.clone() }));
+            }
+        }
+
+        index += 1;
+    });
+}
+
+fn empty_blocks(uml_tokens: &UMLTokens, found: &mut Found) {
+    let mut index = 0;
+
+    uml_tokens.walk(&mut |token| {
+        let sequences = nested_sequences(token);
+        let empty = !matches!(*token, UMLToken::Include { .. }) && !sequences.is_empty() &&
+                    sequences.iter().all(|sequence| sequence.tokens.is_empty());
+
+        if empty {
+            found.push((index, UMLWarning::EmptyBlock { kind: token.kind_name().to_string() }));
+        }
+
+        index += 1;
+    });
+}
@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::slice;
+use {NotePosition, ParticipantKind, UMLToken, UMLTokens};
+
+/// An iterator over every token in a diagram, in the order `walk` visits
+/// them.  Returned by `UMLTokens::iter_flat`.
+#[derive(Debug, Clone)]
+pub struct FlatIter<'a> {
+    /// The tokens left to visit in each sequence being visited, innermost
+    /// last.
+    stack: Vec<slice::Iter<'a, UMLToken>>,
+}
+
+impl<'a> Iterator for FlatIter<'a> {
+    type Item = &'a UMLToken;
+
+    fn next(&mut self) -> Option<&'a UMLToken> {
+        loop {
+            let token = match self.stack.last_mut()?.next() {
+                Some(token) => token,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            // Pushed in reverse, so that the first sequence is visited first.
+            for sequence in nested_sequences(token).into_iter().rev() {
+                self.stack.push(sequence.tokens.iter());
+            }
+            if let UMLToken::SplitNote { ref notes } = *token {
+                self.stack.push(notes.iter());
+            }
+
+            return Some(token);
+        }
+    }
+}
+
+impl UMLTokens {
+    /// Call `f` on every token in pre-order, descending into the sequences
+    /// nested inside block tokens.
+    pub fn walk<'a, F: FnMut(&'a UMLToken)>(&'a self, f: &mut F) {
+        for token in &self.tokens {
+            walk_token(token, f);
+        }
+    }
+
+    /// Call `f` on every token in pre-order, descending into the sequences
+    /// nested inside block tokens, so that tokens can be rewritten in place.
+    pub fn walk_mut<F: FnMut(&mut UMLToken)>(&mut self, f: &mut F) {
+        for token in &mut self.tokens {
+            f(token);
+
+            if let UMLToken::SplitNote { ref mut notes } = *token {
+                for note in notes {
+                    f(note);
+                }
+            }
+
+            for sequence in nested_sequences_mut(token) {
+                sequence.walk_mut(f);
+            }
+        }
+    }
+
+    /// Iterates over every token, in the order `walk` visits them.
+    pub fn iter_flat(&self) -> FlatIter<'_> {
+        FlatIter { stack: vec![self.tokens.iter()] }
+    }
+
+    /// Returns every message, in the order `walk` visits them.
+    pub fn messages(&self) -> Vec<&UMLToken> {
+        self.find_all(|token| matches!(*token, UMLToken::Message { .. }))
+    }
+
+    /// Returns every token matching `predicate`, in the order `walk` visits
+    /// them.
+    pub fn find_all<P: Fn(&UMLToken) -> bool>(&self, predicate: P) -> Vec<&UMLToken> {
+        let mut found = Vec::new();
+        self.walk(&mut |token| if predicate(token) {
+            found.push(token);
+        });
+
+        found
+    }
+
+    /// The number of tokens `walk` visits.
+    pub fn token_count(&self) -> usize {
+        let mut count = 0;
+        self.walk(&mut |_| count += 1);
+
+        count
+    }
+
+    /// The deepest nesting of blocks, so 0 for a diagram with no blocks.
+    /// `!include`s count as blocks.
+    pub fn max_depth(&self) -> usize {
+        self.tokens
+            .iter()
+            .map(|token| {
+                nested_sequences(token)
+                    .into_iter()
+                    .map(|sequence| sequence.max_depth() + 1)
+                    .max()
+                    .unwrap_or(0)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Replace the text of every message, including those in nested
+    /// sequences, with the result of calling `f` on it.
+    pub fn map_messages<F: FnMut(&str) -> String>(&mut self, mut f: F) {
+        self.walk_mut(&mut |token| {
+            if let UMLToken::Message { text: Some(ref mut text), .. } = *token {
+                *text = f(text);
+            }
+        });
+    }
+
+    /// Returns each participant with the index of the token that first
+    /// references it, in ascending index order.
+    ///
+    /// Indexes count tokens in the order `walk` visits them, so a participant
+    /// first used inside a fragment is positioned by where that fragment's
+    /// contents appear in the document.  Declarations, messages, notes and
+    /// activation lines all count as references.  This is the natural
+    /// left-to-right lifeline order when no explicit `order` is given.
+    pub fn participant_first_use(&self) -> Vec<(String, usize)> {
+        let mut first_use: Vec<(String, usize)> = Vec::new();
+        let mut index = 0;
+
+        self.walk(&mut |token| {
+            let names = match *token {
+                UMLToken::Participant { ref short_name, .. } => vec![short_name.as_str()],
+                _ => referenced_participants(token),
+            };
+
+            for name in names {
+                if !first_use.iter().any(|(used, _)| used == name) {
+                    first_use.push((name.to_string(), index));
+                }
+            }
+
+            index += 1;
+        });
+
+        first_use
+    }
+
+    /// Returns every participant, declared or only referenced, in order of
+    /// first appearance as given by `participant_first_use`.
+    pub fn participants(&self) -> Vec<String> {
+        self.participant_first_use().into_iter().map(|(name, _)| name).collect()
+    }
+
+    /// Whether `name` is declared with a `participant`, `actor` or similar
+    /// statement anywhere in the diagram.
+    pub fn contains_participant(&self, name: &str) -> bool {
+        self.participant_aliases().contains_key(name)
+    }
+
+    /// Whether `name` is used by any message, note, activation or other
+    /// statement in the diagram, not counting its declaration.
+    pub fn references_participant(&self, name: &str) -> bool {
+        let mut referenced = false;
+        self.walk(&mut |token| referenced |= referenced_participants(token).contains(&name));
+
+        referenced
+    }
+
+    /// Returns every message, in the order `walk` visits them, with the notes
+    /// attached to it: the `note left` and `note right` notes straight after
+    /// it in the same sequence, which PlantUML draws next to the arrow.
+    pub fn message_notes(&self) -> Vec<(&UMLToken, Vec<&UMLToken>)> {
+        let mut message_notes = Vec::new();
+        add_message_notes(self, &mut message_notes);
+
+        message_notes
+    }
+
+    /// Maps the name each declared participant is referred to by (its alias,
+    /// if it has one) to its display name, without quotes.
+    pub fn participant_aliases(&self) -> HashMap<String, String> {
+        let mut aliases = HashMap::new();
+
+        self.walk(&mut |token| {
+            if let UMLToken::Participant {
+                ref long_name,
+                ref short_name,
+                ..
+            } = *token {
+                let display_name = long_name.as_ref().unwrap_or(short_name).trim_matches('"');
+                aliases.insert(short_name.clone(), display_name.to_string());
+            }
+        });
+
+        aliases
+    }
+
+    /// Returns the participants that are used but never declared with a
+    /// `participant`, `actor` or similar statement, in order of first use.
+    pub fn undeclared_participants(&self) -> Vec<String> {
+        let declared = self.participant_aliases();
+
+        self.participant_first_use()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| !declared.contains_key(name))
+            .collect()
+    }
+
+    /// Declares every participant that is used without being declared, in
+    /// order of first use, straight after `@startuml` (or at the start if
+    /// there is no `@startuml`).  Running this again has no effect.
+    pub fn add_participant_header(&mut self) {
+        let header: Vec<UMLToken> = self.undeclared_participants()
+            .into_iter()
+            .map(|name| {
+                UMLToken::Participant {
+                    kind: ParticipantKind::Participant,
+                    long_name: None,
+                    short_name: name,
+                    stereotype: None,
+                    colour: None,
+                    order: None,
+                }
+            })
+            .collect();
+
+        let start = self.tokens
+            .iter()
+            .position(|token| matches!(*token, UMLToken::StartUML { .. }))
+            .map_or(0, |index| index + 1);
+        self.tokens.splice(start..start, header);
+    }
+
+    /// Removes blocks left empty, e.g. by filtering out their contents.
+    ///
+    /// Working from the innermost blocks outwards, a `loop`, `opt`,
+    /// `critical`, `break`, `group` or `box` whose sequence is empty is
+    /// removed, as is each empty branch of an `alt` or `par`.
+    /// An `alt` or `par` with no branches left is removed entirely.
+    /// `!include`s are kept even if they end up empty, but blocks within them
+    /// are pruned.
+    pub fn prune_empty_blocks(self) -> UMLTokens {
+        UMLTokens::new(self.tokens.into_iter().filter_map(prune_token).collect())
+    }
+
+    /// Empties the sequence of every `!include`, however deeply nested, keeping
+    /// just the file it names.  This gives a lightweight skeleton of the
+    /// diagram whose includes can be resolved again when needed.
+    pub fn without_include_bodies(mut self) -> UMLTokens {
+        self.walk_mut(&mut |token| {
+            if let UMLToken::Include { ref mut sequence, .. } = *token {
+                sequence.tokens.clear();
+            }
+        });
+
+        self
+    }
+
+    /// Focuses the diagram on the participants in `keep`.
+    ///
+    /// Participant declarations, messages, notes, `ref`s and activation,
+    /// creation and destruction statements are kept only if every participant
+    /// they name is in `keep`, so a message with just one kept end is dropped.
+    /// Notes that don't name any participants are dropped too.  Other tokens
+    /// are kept, with their contents filtered in the same way, and any blocks
+    /// left empty are then removed as by `prune_empty_blocks`.
+    pub fn retain_participants(self, keep: &[&str]) -> UMLTokens {
+        retain_sequence(self, keep).prune_empty_blocks()
+    }
+
+    /// Flattens `!include`s, returning each resulting token with the file it
+    /// came from.  `root` is the file these tokens were parsed from, and each
+    /// included file is named by the path written in the directive, joined to
+    /// the directory of the file that includes it.
+    ///
+    /// As in `iter_flat`, each token is followed by the tokens nested inside
+    /// it, each with the file it came from, so a token included within a
+    /// block is attributed to the included file.  The block itself is returned
+    /// whole, with any includes within it replaced by the included tokens.
+    pub fn resolve_with_provenance(&self, root: &Path) -> Vec<(PathBuf, UMLToken)> {
+        let mut resolved = Vec::new();
+        add_with_provenance(self, root, &mut resolved);
+
+        resolved
+    }
+}
+
+/// Call `f` on `token` and then on every token nested inside it, as `walk`.
+pub(crate) fn walk_token<'a, F: FnMut(&'a UMLToken)>(token: &'a UMLToken, f: &mut F) {
+    f(token);
+
+    if let UMLToken::SplitNote { ref notes } = *token {
+        for note in notes {
+            f(note);
+        }
+    }
+
+    for sequence in nested_sequences(token) {
+        sequence.walk(f);
+    }
+}
+
+/// The sequences nested directly inside a block token, in document order.
+/// Generic traversals such as `walk`, `walk_mut` and `iter_flat` descend
+/// through this (or the `mut` version below).  Passes that treat kinds of
+/// block differently, such as pruning, span search and activation checks,
+/// match on the block tokens themselves, so a new block token must be added
+/// to those too.
+pub(crate) fn nested_sequences(token: &UMLToken) -> Vec<&UMLTokens> {
+    match *token {
+        UMLToken::Loop { ref sequence, .. } |
+        UMLToken::Opt { ref sequence, .. } |
+        UMLToken::Critical { ref sequence, .. } |
+        UMLToken::Break { ref sequence, .. } |
+        UMLToken::Group { ref sequence, .. } |
+        UMLToken::Include { ref sequence, .. } |
+        UMLToken::Box { ref sequence, .. } => vec![sequence],
+
+        UMLToken::Parallel { ref sequences, .. } |
+        UMLToken::Alt { ref sequences, .. } => sequences.iter().collect(),
+
+        _ => Vec::new(),
+    }
+}
+
+pub(crate) fn nested_sequences_mut(token: &mut UMLToken) -> Vec<&mut UMLTokens> {
+    match *token {
+        UMLToken::Loop { ref mut sequence, .. } |
+        UMLToken::Opt { ref mut sequence, .. } |
+        UMLToken::Critical { ref mut sequence, .. } |
+        UMLToken::Break { ref mut sequence, .. } |
+        UMLToken::Group { ref mut sequence, .. } |
+        UMLToken::Include { ref mut sequence, .. } |
+        UMLToken::Box { ref mut sequence, .. } => vec![sequence],
+
+        UMLToken::Parallel { ref mut sequences, .. } |
+        UMLToken::Alt { ref mut sequences, .. } => sequences.iter_mut().collect(),
+
+        _ => Vec::new(),
+    }
+}
+
+fn add_message_notes<'a>(sequence: &'a UMLTokens,
+                         message_notes: &mut Vec<(&'a UMLToken, Vec<&'a UMLToken>)>) {
+    for (index, token) in sequence.tokens.iter().enumerate() {
+        match *token {
+            UMLToken::Message { .. } => {
+                let notes = sequence.tokens[index + 1..]
+                    .iter()
+                    .take_while(|token| match **token {
+                        UMLToken::Note { ref position, .. } => {
+                            matches!(*position, NotePosition::Left(None) | NotePosition::Right(None))
+                        }
+                        _ => false,
+                    })
+                    .collect();
+                message_notes.push((token, notes));
+            }
+
+            _ => {
+                for sequence in nested_sequences(token) {
+                    add_message_notes(sequence, message_notes);
+                }
+            }
+        }
+    }
+}
+
+/// The participants a token refers to, other than by declaring them.
+pub(crate) fn referenced_participants(token: &UMLToken) -> Vec<&str> {
+    match *token {
+        UMLToken::Message { ref from, ref to, .. } => vec![from.as_str(), to.as_str()],
+        UMLToken::Note { ref position, .. } => position.participants(),
+        UMLToken::Ref { ref participants, .. } => {
+            participants.iter().map(|name| name.as_str()).collect()
+        }
+        UMLToken::Activate { ref name, .. } |
+        UMLToken::Deactivate { ref name, .. } |
+        UMLToken::Create { ref name } |
+        UMLToken::Destroy { ref name } => vec![name.as_str()],
+        _ => Vec::new(),
+    }
+}
+
+fn retain_sequence(sequence: UMLTokens, keep: &[&str]) -> UMLTokens {
+    let tokens = sequence.tokens.into_iter().filter_map(|token| retain_token(token, keep));
+
+    UMLTokens::new(tokens.collect())
+}
+
+/// Filters the contents of `token` to the participants in `keep`, returning
+/// `None` if the token itself should be dropped.
+fn retain_token(token: UMLToken, keep: &[&str]) -> Option<UMLToken> {
+    match token {
+        UMLToken::SplitNote { notes } => {
+            let notes: Vec<UMLToken> = notes.into_iter()
+                .filter_map(|note| retain_token(note, keep))
+                .collect();
+
+            if notes.is_empty() {
+                None
+            } else {
+                Some(UMLToken::SplitNote { notes })
+            }
+        }
+
+        UMLToken::Loop { sequence, count } => {
+            Some(UMLToken::Loop {
+                sequence: retain_sequence(sequence, keep),
+                count,
+            })
+        }
+
+        UMLToken::Opt { label, sequence } => {
+            Some(UMLToken::Opt {
+                label,
+                sequence: retain_sequence(sequence, keep),
+            })
+        }
+
+        UMLToken::Critical { label, sequence } => {
+            Some(UMLToken::Critical {
+                label,
+                sequence: retain_sequence(sequence, keep),
+            })
+        }
+
+        UMLToken::Break { label, sequence } => {
+            Some(UMLToken::Break {
+                label,
+                sequence: retain_sequence(sequence, keep),
+            })
+        }
+
+        UMLToken::Group { label, sequence } => {
+            Some(UMLToken::Group {
+                label,
+                sequence: retain_sequence(sequence, keep),
+            })
+        }
+
+        UMLToken::Include {
+            file,
+            sequence,
+            once,
+        } => {
+            Some(UMLToken::Include {
+                file,
+                sequence: retain_sequence(sequence, keep),
+                once,
+            })
+        }
+
+        UMLToken::Box {
+            name,
+            colour,
+            sequence,
+        } => {
+            Some(UMLToken::Box {
+                name,
+                colour,
+                sequence: retain_sequence(sequence, keep),
+            })
+        }
+
+        UMLToken::Parallel { sequences, labels } => {
+            Some(UMLToken::Parallel {
+                sequences: retain_branches(sequences, keep),
+                labels,
+            })
+        }
+
+        UMLToken::Alt { sequences, labels } => {
+            Some(UMLToken::Alt {
+                sequences: retain_branches(sequences, keep),
+                labels,
+            })
+        }
+
+        token => {
+            let names = match token {
+                UMLToken::Participant { ref short_name, .. } => vec![short_name.as_str()],
+                UMLToken::Note { ref position, .. } => {
+                    let names = position.participants();
+                    if names.is_empty() {
+                        return None;
+                    }
+                    names
+                }
+                ref token => referenced_participants(token),
+            };
+
+            if names.iter().all(|name| keep.contains(name)) {
+                Some(token)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn retain_branches(sequences: Vec<UMLTokens>, keep: &[&str]) -> Vec<UMLTokens> {
+    sequences.into_iter().map(|sequence| retain_sequence(sequence, keep)).collect()
+}
+
+/// Prunes the blocks within `token`, returning `None` if it is a block that
+/// ends up empty.
+fn prune_token(token: UMLToken) -> Option<UMLToken> {
+    match token {
+        UMLToken::Loop { sequence, count } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Loop { sequence, count })
+            }
+        }
+
+        UMLToken::Opt { label, sequence } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Opt { label, sequence })
+            }
+        }
+
+        UMLToken::Critical { label, sequence } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Critical { label, sequence })
+            }
+        }
+
+        UMLToken::Break { label, sequence } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Break { label, sequence })
+            }
+        }
+
+        UMLToken::Group { label, sequence } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Group { label, sequence })
+            }
+        }
+
+        UMLToken::Box {
+            name,
+            colour,
+            sequence,
+        } => {
+            let sequence = sequence.prune_empty_blocks();
+            if sequence.tokens.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Box {
+                    name,
+                    colour,
+                    sequence,
+                })
+            }
+        }
+
+        UMLToken::Parallel { sequences, labels } => {
+            let (sequences, labels) = prune_branches(sequences, labels);
+            if sequences.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Parallel { sequences, labels })
+            }
+        }
+
+        UMLToken::Alt { sequences, labels } => {
+            let (sequences, labels) = prune_branches(sequences, labels);
+            if sequences.is_empty() {
+                None
+            } else {
+                Some(UMLToken::Alt { sequences, labels })
+            }
+        }
+
+        UMLToken::Include {
+            file,
+            sequence,
+            once,
+        } => {
+            Some(UMLToken::Include {
+                file,
+                sequence: sequence.prune_empty_blocks(),
+                once,
+            })
+        }
+
+        token => Some(token),
+    }
+}
+
+/// Prunes each branch, removing those left empty along with their labels.
+fn prune_branches(sequences: Vec<UMLTokens>,
+                  labels: Vec<Option<String>>)
+                  -> (Vec<UMLTokens>, Vec<Option<String>>) {
+    let mut labels = labels.into_iter();
+
+    sequences.into_iter()
+        .map(|sequence| (sequence.prune_empty_blocks(), labels.next().unwrap_or(None)))
+        .filter(|(sequence, _)| !sequence.tokens.is_empty())
+        .unzip()
+}
+
+fn add_with_provenance(sequence: &UMLTokens, file: &Path, resolved: &mut Vec<(PathBuf, UMLToken)>) {
+    for token in &sequence.tokens {
+        if let UMLToken::Include {
+            file: ref included,
+            ref sequence,
+            ..
+        } = *token {
+            let included = file.parent().unwrap_or(Path::new("")).join(included);
+            add_with_provenance(sequence, &included, resolved);
+        } else {
+            let mut inlined = token.clone();
+            inline_includes(&mut inlined);
+            resolved.push((file.to_path_buf(), inlined));
+
+            if let UMLToken::SplitNote { ref notes } = *token {
+                for note in notes {
+                    resolved.push((file.to_path_buf(), note.clone()));
+                }
+            }
+            for nested in nested_sequences(token) {
+                add_with_provenance(nested, file, resolved);
+            }
+        }
+    }
+}
+
+/// Replaces every include nested within `token` with the included tokens.
+fn inline_includes(token: &mut UMLToken) {
+    for sequence in nested_sequences_mut(token) {
+        inline_sequence(sequence);
+    }
+}
+
+fn inline_sequence(sequence: &mut UMLTokens) {
+    for mut token in std::mem::take(&mut sequence.tokens) {
+        if let UMLToken::Include { sequence: ref included, .. } = token {
+            let mut included = included.clone();
+            inline_sequence(&mut included);
+            sequence.tokens.extend(included.tokens);
+        } else {
+            inline_includes(&mut token);
+            sequence.tokens.push(token);
+        }
+    }
+}